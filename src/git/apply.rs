@@ -0,0 +1,1150 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::DateTime;
+use git2;
+
+use super::diff::{BinaryDiff, DiffHunk, DiffLine, FileDiff, FileHeader, MODE_EXECUTABLE, MODE_FILE};
+use super::worker::WorkerError;
+
+/// One patch read from a diff or an mbox. Those from an mbox carry the commit they
+/// were made from.
+#[derive(Debug, Clone, Default)]
+pub struct PatchMessage {
+    /// The name and email address of the author.
+    pub author: Option<(String, String)>,
+    /// Seconds since the epoch and the offset from UTC in minutes.
+    pub date: Option<(i64, i32)>,
+    pub subject: String,
+    pub body: String,
+    pub files: Vec<FileDiff>
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ApplyTarget {
+    WorkingTree,
+    Index,
+    /// Commits every patch in turn on top of HEAD, like `git am`.
+    Commits
+}
+
+/// A file or hunk that could not be applied.
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub path: String,
+    pub hunk: Option<String>,
+    pub reason: String
+}
+
+#[derive(Debug, Clone)]
+pub struct ApplyReport {
+    pub target: ApplyTarget,
+    /// The number of files applied, which is none when anything was rejected outside
+    /// of a series of commits.
+    pub files: usize,
+    pub commits: usize,
+    pub rejections: Vec<Rejection>,
+    /// The subject of the patch a series stopped at, which was not committed.
+    pub stopped_at: Option<String>
+}
+
+/// Reads a unified diff, or each message of an mbox as written by `git format-patch`.
+/// Anything that is neither a header nor a hunk is skipped.
+pub fn parse(text: &str) -> Vec<PatchMessage> {
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    if text.ends_with('\n') {
+        lines.pop();
+    }
+
+    let starts: Vec<usize> = (0..lines.len())
+        .filter(|&i| is_message_start(&lines, i))
+        .collect();
+
+    if starts.is_empty() {
+        let files = parse_files(&lines);
+        return if files.is_empty() { vec![] } else { vec![PatchMessage { files, ..PatchMessage::default() }] };
+    }
+
+    starts.iter().enumerate()
+        .map(|(n, &start)| {
+            let end = starts.get(n + 1).cloned().unwrap_or(lines.len());
+            parse_message(&lines[start + 1..end])
+        })
+        .filter(|x| !x.files.is_empty())
+        .collect()
+}
+
+/// An mbox message starts with a `From ` line after a blank one, followed by headers.
+fn is_message_start(lines: &[&str], i: usize) -> bool {
+    lines[i].starts_with("From ")
+        && (i == 0 || lines[i - 1].trim_end().is_empty())
+        && lines.get(i + 1).map(|x| x.contains(':') && !x.starts_with(' ')).unwrap_or(false)
+}
+
+fn parse_message(lines: &[&str]) -> PatchMessage {
+    let mut message = PatchMessage::default();
+    let mut headers: Vec<(String, String)> = vec![];
+    let mut i = 0;
+
+    while i < lines.len() && !lines[i].trim_end().is_empty() {
+        let line = lines[i].trim_end();
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = headers.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+            }
+        } else if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].to_lowercase(), line[colon + 1..].trim().to_string()));
+        }
+        i += 1;
+    }
+
+    for (name, value) in headers {
+        match name.as_str() {
+            "from" => message.author = Some(parse_address(&decode_header(&value))),
+            "date" => {
+                message.date = DateTime::parse_from_rfc2822(&value).ok()
+                    .map(|x| (x.timestamp(), x.offset().local_minus_utc() / 60));
+            }
+            "subject" => message.subject = strip_subject_prefix(&decode_header(&value)),
+            _ => {}
+        }
+    }
+
+    let rest = &lines[i..];
+    let diff_start = rest.iter()
+        .position(|x| x.trim_end() == "---" || x.starts_with("diff --git "))
+        .unwrap_or(rest.len());
+
+    let mut body: Vec<&str> = rest[..diff_start].iter().map(|x| x.trim_end()).collect();
+
+    // A `From:` line at the start of the body names the author when the sender is
+    // someone else.
+    let first = body.iter().position(|x| !x.is_empty());
+    if let Some(author) = first.and_then(|x| body[x].strip_prefix("From:")) {
+        message.author = Some(parse_address(author.trim()));
+        body.drain(..=first.unwrap_or(0));
+    }
+
+    message.body = body.join("\n").trim().to_string();
+    message.files = parse_files(&rest[diff_start..]);
+    message
+}
+
+/// Splits `Name <email>` apart.
+fn parse_address(text: &str) -> (String, String) {
+    match (text.find('<'), text.rfind('>')) {
+        (Some(start), Some(end)) if start < end => {
+            (text[..start].trim().trim_matches('"').to_string(), text[start + 1..end].trim().to_string())
+        }
+        _ => (text.trim().to_string(), text.trim().to_string())
+    }
+}
+
+/// Drops tags like `[PATCH v2 1/3]` from a subject.
+fn strip_subject_prefix(subject: &str) -> String {
+    let mut subject = subject.trim();
+
+    while subject.starts_with('[') {
+        match subject.find(']') {
+            Some(end) => subject = subject[end + 1..].trim_start(),
+            None => break
+        }
+    }
+
+    subject.to_string()
+}
+
+/// Decodes RFC 2047 encoded words in a header. Whitespace between two encoded words
+/// is dropped, as it only separates them.
+fn decode_header(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    let mut is_after_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let parts: Vec<&str> = rest[start + 2..].splitn(3, '?').collect();
+        if parts.len() < 3 {
+            break;
+        }
+
+        let end = match parts[2].find("?=") {
+            Some(end) => end,
+            None => break
+        };
+
+        let before = &rest[..start];
+        if !(is_after_word && before.trim().is_empty()) {
+            decoded.push_str(before);
+        }
+
+        let word = &parts[2][..end];
+        let bytes = match parts[1] {
+            "q" | "Q" => decode_q(word),
+            "b" | "B" => decode_base64(word),
+            _ => word.as_bytes().to_vec()
+        };
+        decoded.push_str(&String::from_utf8_lossy(&bytes));
+
+        rest = &parts[2][end + 2..];
+        is_after_word = true;
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_q(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'_' => decoded.push(b' '),
+            b'=' => match text.get(i + 1..i + 3).and_then(|x| u8::from_str_radix(x, 16).ok()) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'=')
+            },
+            byte => decoded.push(byte)
+        }
+        i += 1;
+    }
+
+    decoded
+}
+
+fn decode_base64(text: &str) -> Vec<u8> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None
+    };
+
+    let mut decoded = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for v in text.bytes().filter_map(value) {
+        buffer = (buffer << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    decoded
+}
+
+fn parse_files(lines: &[&str]) -> Vec<FileDiff> {
+    let mut files = vec![];
+    let mut i = 0;
+
+    while i < lines.len() {
+        let is_plain = lines[i].starts_with("--- ") && lines.get(i + 1).map(|x| x.starts_with("+++ ")).unwrap_or(false);
+
+        if lines[i].starts_with("diff --git ") || is_plain {
+            let (file, next) = parse_file(lines, i);
+            files.push(file);
+            i = next;
+        } else if lines[i].trim_end_matches('\r') == "-- " {
+            // The signature ending a message.
+            break;
+        } else {
+            i += 1;
+        }
+    }
+
+    files
+}
+
+/// The path of a `---` or `+++` line, without its `a/` or `b/` prefix and any
+/// timestamp. `None` stands for `/dev/null`.
+fn header_path(text: &str) -> Option<String> {
+    let path = text.split('\t').next().unwrap_or(text).trim_end();
+    if path == "/dev/null" {
+        return None;
+    }
+
+    // Like `patch -p1`, leaving paths without a directory alone.
+    Some(match path.find('/') {
+        Some(slash) => path[slash + 1..].to_string(),
+        None => path.to_string()
+    })
+}
+
+fn parse_file(lines: &[&str], start: usize) -> (FileDiff, usize) {
+    let mut file = FileDiff {
+        old_path: None,
+        new_path: None,
+        old_id: git2::Oid::zero(),
+        new_id: git2::Oid::zero(),
+        status: git2::Delta::Modified,
+        hunks: vec![],
+        binary: None,
+        header: FileHeader::default(),
+        size: 0,
+        is_generated: false,
+        changed_lines: None,
+        is_loaded: true
+    };
+
+    let mut i = start;
+
+    if let Some(names) = lines[i].strip_prefix("diff --git ") {
+        if let Some(middle) = names.trim_end().find(" b/") {
+            file.old_path = header_path(&names[..middle]);
+            file.new_path = header_path(names[middle + 1..].trim_end());
+        }
+
+        let mut extended = String::new();
+        i += 1;
+
+        while i < lines.len() {
+            let line = lines[i].trim_end();
+
+            if line.starts_with("new file mode ") {
+                file.status = git2::Delta::Added;
+            } else if line.starts_with("deleted file mode ") {
+                file.status = git2::Delta::Deleted;
+            } else if let Some(path) = line.strip_prefix("rename from ").or(line.strip_prefix("copy from ")) {
+                file.old_path = Some(path.to_string());
+            } else if let Some(path) = line.strip_prefix("rename to ") {
+                file.new_path = Some(path.to_string());
+                file.status = git2::Delta::Renamed;
+            } else if let Some(path) = line.strip_prefix("copy to ") {
+                file.new_path = Some(path.to_string());
+                file.status = git2::Delta::Copied;
+            } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+                file.binary = Some(BinaryDiff::default());
+            } else if !["old mode ", "new mode ", "similarity index ", "dissimilarity index ", "index "].iter().any(|x| line.starts_with(x)) {
+                break;
+            }
+
+            extended.push_str(line);
+            extended.push('\n');
+            i += 1;
+        }
+
+        file.header = FileHeader::parse(&extended);
+    }
+
+    if i + 1 < lines.len() && lines[i].starts_with("--- ") && lines[i + 1].starts_with("+++ ") {
+        let old = header_path(&lines[i][4..]);
+        let new = header_path(&lines[i + 1][4..]);
+
+        match (&old, &new) {
+            (None, _) => file.status = git2::Delta::Added,
+            (_, None) => file.status = git2::Delta::Deleted,
+            _ => {}
+        }
+
+        file.old_path = file.old_path.take().or(old);
+        file.new_path = file.new_path.take().or(new);
+        i += 2;
+    }
+
+    // Added and deleted files carry their path on both sides, as in diffs made here.
+    file.old_path = file.old_path.take().or_else(|| file.new_path.clone());
+    file.new_path = file.new_path.take().or_else(|| file.old_path.clone());
+
+    while i < lines.len() && lines[i].starts_with("@@ ") {
+        let (hunk, next) = parse_hunk(lines, i);
+        file.hunks.push(hunk);
+        i = next;
+    }
+
+    let changed = file.hunks.iter()
+        .flat_map(|x| x.lines.iter())
+        .filter(|x| x.origin == '+' || x.origin == '-')
+        .count();
+    file.changed_lines = Some(changed);
+
+    (file, i)
+}
+
+/// Reads `start,lines` from a hunk header, where a missing count means one line.
+fn parse_range(text: &str) -> (u32, u32) {
+    let mut parts = text.splitn(2, ',');
+    let start = parts.next().and_then(|x| x.parse().ok()).unwrap_or(0);
+    let lines = parts.next().and_then(|x| x.parse().ok()).unwrap_or(1);
+    (start, lines)
+}
+
+fn parse_hunk(lines: &[&str], start: usize) -> (DiffHunk, usize) {
+    let header = lines[start].trim_end();
+    let mut ranges = header.split(' ').skip(1);
+    let (old_start, old_lines) = parse_range(ranges.next().unwrap_or("").trim_start_matches('-'));
+    let (new_start, new_lines) = parse_range(ranges.next().unwrap_or("").trim_start_matches('+'));
+
+    let mut hunk = DiffHunk {
+        header: format!("{}\n", header),
+        old_start,
+        old_lines,
+        new_start,
+        new_lines,
+        lines: vec![]
+    };
+
+    let (mut old_left, mut new_left) = (old_lines, new_lines);
+    let (mut old_lineno, mut new_lineno) = (old_start.max(1), new_start.max(1));
+    let mut i = start + 1;
+
+    while i < lines.len() && (old_left > 0 || new_left > 0) {
+        let line = lines[i];
+        // Some mailers strip the space off empty context lines, which leaves just the
+        // carriage return of a CRLF file.
+        let (origin, content) = match line.trim_end_matches('\r').chars().next() {
+            Some(c @ ' ') | Some(c @ '+') | Some(c @ '-') => (c, &line[1..]),
+            None => (' ', line),
+            Some('\\') => {
+                mark_no_newline(&mut hunk.lines);
+                i += 1;
+                continue;
+            }
+            Some(_) => break
+        };
+
+        let (old, new) = match origin {
+            ' ' => (Some(old_lineno), Some(new_lineno)),
+            '-' => (Some(old_lineno), None),
+            _ => (None, Some(new_lineno))
+        };
+
+        if old.is_some() {
+            old_lineno += 1;
+            old_left = old_left.saturating_sub(1);
+        }
+        if new.is_some() {
+            new_lineno += 1;
+            new_left = new_left.saturating_sub(1);
+        }
+
+        let mut content = content.as_bytes().to_vec();
+        content.push(b'\n');
+
+        hunk.lines.push(DiffLine { origin, old_lineno: old, new_lineno: new, content });
+        i += 1;
+    }
+
+    if i < lines.len() && lines[i].starts_with('\\') {
+        mark_no_newline(&mut hunk.lines);
+        i += 1;
+    }
+
+    (hunk, i)
+}
+
+/// Takes the newline off the last line and adds the marker libgit2 would give it.
+fn mark_no_newline(lines: &mut Vec<DiffLine>) {
+    let origin = match lines.last_mut() {
+        Some(last) => {
+            last.content.pop();
+            match last.origin {
+                '-' => '>',
+                '+' => '<',
+                _ => '='
+            }
+        }
+        None => return
+    };
+
+    lines.push(DiffLine {
+        origin,
+        old_lineno: None,
+        new_lineno: None,
+        content: b"\n\\ No newline at end of file\n".to_vec()
+    });
+}
+
+/// Finds `wanted` among `lines` nearest to `expected`, but not before `min`.
+fn find_lines(lines: &[&[u8]], wanted: &[&[u8]], expected: usize, min: usize) -> Option<usize> {
+    let fits = |at: usize| at >= min && at + wanted.len() <= lines.len() && lines[at..at + wanted.len()] == *wanted;
+
+    if wanted.is_empty() {
+        // Nothing to match against, so only the given position will do.
+        return Some(expected).filter(|x| *x >= min && *x <= lines.len());
+    }
+
+    for distance in 0..=lines.len().max(expected) {
+        if fits(expected + distance) {
+            return Some(expected + distance);
+        }
+        if distance <= expected && fits(expected - distance) {
+            return Some(expected - distance);
+        }
+    }
+
+    None
+}
+
+/// Applies the hunks that match, returning the new content and the indices of the
+/// hunks that did not. Hunks may have moved by any number of lines, but their
+/// context has to match exactly.
+fn apply_hunks(content: &[u8], hunks: &[DiffHunk]) -> (Vec<u8>, Vec<usize>) {
+    let old: Vec<&[u8]> = content.split_inclusive(|x| *x == b'\n').collect();
+    let mut result = vec![];
+    let mut rejected = vec![];
+    // How many old lines were used up, and how far the hunks were found from where
+    // they said they would be.
+    let mut copied = 0;
+    let mut offset: i64 = 0;
+
+    for (i, hunk) in hunks.iter().enumerate() {
+        let side = |origins: &[char]| -> Vec<&[u8]> {
+            hunk.lines.iter()
+                .filter(|x| origins.contains(&x.origin))
+                .map(|x| x.content.as_slice())
+                .collect()
+        };
+        let (before, after) = (side(&[' ', '-']), side(&[' ', '+']));
+
+        let stated = hunk.old_first() as i64 - 1;
+        let expected = (stated + offset).max(0) as usize;
+
+        match find_lines(&old, &before, expected, copied) {
+            Some(at) => {
+                for line in old[copied..at].iter().chain(after.iter()) {
+                    result.extend_from_slice(line);
+                }
+                copied = at + before.len();
+                offset = at as i64 - stated;
+            }
+            None => rejected.push(i)
+        }
+    }
+
+    for line in old[copied..].iter() {
+        result.extend_from_slice(line);
+    }
+
+    (result, rejected)
+}
+
+/// Where patched files are read from and written to: the working tree, or an index
+/// when one is given. Changes are kept aside until `flush`, so that nothing is
+/// written when a later file does not apply.
+struct Destination<'a> {
+    repo: &'a git2::Repository,
+    index: Option<&'a mut git2::Index>,
+    /// The new content and mode of each changed path, or `None` when it is removed.
+    pending: BTreeMap<String, Option<(Vec<u8>, u32)>>
+}
+
+impl<'a> Destination<'a> {
+    fn new(repo: &'a git2::Repository, index: Option<&'a mut git2::Index>) -> Destination<'a> {
+        Destination { repo, index, pending: BTreeMap::new() }
+    }
+
+    fn workdir(&self) -> Result<&Path, WorkerError> {
+        self.repo.workdir().ok_or_else(|| git2::Error::from_str("The repository has no working tree.").into())
+    }
+
+    fn read(&self, path: &str) -> Option<(Vec<u8>, u32)> {
+        if let Some(change) = self.pending.get(path) {
+            return change.clone();
+        }
+
+        match self.index {
+            Some(ref index) => {
+                let entry = index.get_path(Path::new(path), 0)?;
+                let blob = self.repo.find_blob(entry.id).ok()?;
+                Some((blob.content().to_vec(), entry.mode))
+            }
+            None => {
+                let path = self.workdir().ok()?.join(path);
+                let content = fs::read(&path).ok()?;
+                let mode = if is_executable(&path) { MODE_EXECUTABLE } else { MODE_FILE };
+                Some((content, mode))
+            }
+        }
+    }
+
+    fn write(&mut self, path: &str, content: Vec<u8>, mode: u32) {
+        self.pending.insert(path.to_string(), Some((content, mode)));
+    }
+
+    fn remove(&mut self, path: &str) {
+        self.pending.insert(path.to_string(), None);
+    }
+
+    /// Writes out every change, removals first so that a file can make way for a
+    /// directory of the same name.
+    fn flush(&mut self) -> Result<(), WorkerError> {
+        let pending = std::mem::take(&mut self.pending);
+
+        for path in pending.iter().filter(|x| x.1.is_none()).map(|x| x.0) {
+            match self.index {
+                Some(ref mut index) => index.remove_path(Path::new(path))?,
+                None => fs::remove_file(self.workdir()?.join(path))?
+            }
+        }
+
+        for (path, (content, mode)) in pending.iter().filter_map(|(path, x)| x.as_ref().map(|x| (path, x))) {
+            match self.index {
+                Some(ref mut index) => {
+                    let time = git2::IndexTime::new(0, 0);
+                    let entry = git2::IndexEntry {
+                        ctime: time,
+                        mtime: time,
+                        dev: 0,
+                        ino: 0,
+                        mode: *mode,
+                        uid: 0,
+                        gid: 0,
+                        file_size: content.len() as u32,
+                        id: self.repo.blob(content)?,
+                        flags: 0,
+                        flags_extended: 0,
+                        path: path.as_bytes().to_vec()
+                    };
+                    index.add(&entry)?;
+                }
+                None => {
+                    let path = self.workdir()?.join(path);
+                    if let Some(dir) = path.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    fs::write(&path, content)?;
+                    set_executable(&path, *mode == MODE_EXECUTABLE)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).map(|x| x.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, is_executable: bool) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(if is_executable { mode | 0o111 } else { mode & !0o111 });
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_: &Path, _: bool) -> io::Result<()> {
+    Ok(())
+}
+
+/// Stages one file's patch, or adds a rejection for each part of it that does not
+/// apply. Returns whether all of it applied.
+fn apply_file(destination: &mut Destination, file: &FileDiff, rejections: &mut Vec<Rejection>) -> bool {
+    let old_path = file.old_path.clone().unwrap_or_default();
+    let new_path = file.new_path.clone().unwrap_or_default();
+    let mut reject = |reason: &str| {
+        rejections.push(Rejection { path: new_path.clone(), hunk: None, reason: reason.to_string() });
+        false
+    };
+
+    if file.binary.is_some() {
+        return reject("Binary patches cannot be applied");
+    }
+
+    let is_moved = (file.status == git2::Delta::Renamed || file.status == git2::Delta::Copied) && old_path != new_path;
+    if is_moved && destination.read(&new_path).is_some() {
+        return reject("The file already exists");
+    }
+
+    let is_added = file.status == git2::Delta::Added;
+    let (content, mode) = match (destination.read(&old_path), is_added) {
+        (Some(_), true) => return reject("The file already exists"),
+        (None, false) => return reject("The file does not exist"),
+        (Some(old), false) => old,
+        (None, true) => (vec![], MODE_FILE)
+    };
+
+    let (result, failed) = apply_hunks(&content, &file.hunks);
+    if !failed.is_empty() {
+        for i in failed {
+            rejections.push(Rejection {
+                path: new_path.clone(),
+                hunk: Some(file.hunks[i].header.trim_end().to_string()),
+                reason: "The lines around it do not match".to_string()
+            });
+        }
+        return false;
+    }
+
+    if file.status == git2::Delta::Deleted {
+        if !result.is_empty() {
+            return reject("The file has lines the patch does not remove");
+        }
+
+        destination.remove(&old_path);
+        return true;
+    }
+
+    destination.write(&new_path, result, file.header.new_mode.unwrap_or(mode));
+
+    if file.status == git2::Delta::Renamed && old_path != new_path {
+        destination.remove(&old_path);
+    }
+
+    true
+}
+
+/// Paths the patches touch that have changes of their own in the working tree or
+/// the index, which committing the patches would overwrite.
+fn changed_paths(repo: &git2::Repository, messages: &[PatchMessage]) -> Vec<String> {
+    let mut paths: Vec<String> = messages.iter()
+        .flat_map(|x| x.files.iter())
+        .flat_map(|x| x.old_path.iter().chain(x.new_path.iter()))
+        .filter(|x| {
+            repo.status_file(Path::new(x))
+                .map(|status| !status.is_empty() && !status.contains(git2::Status::IGNORED))
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect();
+
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+fn commit_message(message: &PatchMessage) -> String {
+    let subject = if message.subject.is_empty() { "Apply patch" } else { message.subject.as_str() };
+
+    if message.body.is_empty() {
+        format!("{}\n", subject)
+    } else {
+        format!("{}\n\n{}\n", subject, message.body)
+    }
+}
+
+fn commit_all(repo: &git2::Repository, messages: &[PatchMessage], report: &mut ApplyReport) -> Result<(), WorkerError> {
+    let changed = changed_paths(repo, messages);
+    if !changed.is_empty() {
+        let message = format!("Commit or stash the changes to {} before applying the patches as commits.", changed.join(", "));
+        return Err(git2::Error::from_str(&message).into());
+    }
+
+    let committer = repo.signature()?;
+    // A branch without commits yet gets the first patch as its root commit.
+    let mut parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(ref e) if e.code() == git2::ErrorCode::UnbornBranch => None,
+        Err(e) => return Err(e.into())
+    };
+
+    for message in messages {
+        let mut index = git2::Index::new()?;
+        if let Some(ref parent) = parent {
+            index.read_tree(&parent.tree()?)?;
+        }
+
+        let before = report.rejections.len();
+        let mut files = 0;
+
+        {
+            let mut destination = Destination::new(repo, Some(&mut index));
+            for file in message.files.iter() {
+                if apply_file(&mut destination, file, &mut report.rejections) {
+                    files += 1;
+                }
+            }
+
+            if report.rejections.len() > before {
+                report.stopped_at = Some(message.subject.clone());
+                break;
+            }
+            destination.flush()?;
+        }
+
+        let author = match (&message.author, message.date) {
+            (Some((name, email)), Some((seconds, offset))) => git2::Signature::new(name, email, &git2::Time::new(seconds, offset))?,
+            (Some((name, email)), None) => git2::Signature::now(name, email)?,
+            (None, _) => committer.clone()
+        };
+
+        let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        let id = repo.commit(None, &author, &committer, &commit_message(message), &tree, &parents)?;
+        parent = Some(repo.find_commit(id)?);
+        report.commits += 1;
+        report.files += files;
+    }
+
+    let last = match parent {
+        Some(ref last) if report.commits > 0 => last,
+        _ => return Ok(())
+    };
+
+    // The working tree is brought up to date before HEAD moves, so that the old HEAD
+    // tells what can be safely replaced.
+    repo.checkout_tree(last.as_object(), Some(git2::build::CheckoutBuilder::new().safe()))?;
+
+    let log = format!("am: {}", last.summary().unwrap_or(""));
+    match repo.find_reference("HEAD")?.symbolic_target() {
+        Some(branch) => {
+            repo.reference(branch, last.id(), true, &log)?;
+        }
+        None => repo.set_head_detached(last.id())?
+    }
+
+    Ok(())
+}
+
+/// Applies a series of patches. Into the working tree or the index nothing is
+/// written unless every hunk applies, like `git apply`. As commits the series stops
+/// at the first patch that does not apply cleanly.
+pub fn apply(repo: &git2::Repository, messages: &[PatchMessage], target: ApplyTarget) -> Result<ApplyReport, WorkerError> {
+    let mut report = ApplyReport { target, files: 0, commits: 0, rejections: vec![], stopped_at: None };
+
+    let mut index = match target {
+        ApplyTarget::WorkingTree => None,
+        ApplyTarget::Index => Some(repo.index()?),
+        ApplyTarget::Commits => {
+            commit_all(repo, messages, &mut report)?;
+            return Ok(report);
+        }
+    };
+
+    let files: Vec<&FileDiff> = messages.iter().flat_map(|x| x.files.iter()).collect();
+    {
+        let mut destination = Destination::new(repo, index.as_mut());
+        for file in files.iter() {
+            apply_file(&mut destination, file, &mut report.rejections);
+        }
+
+        if !report.rejections.is_empty() {
+            return Ok(report);
+        }
+        destination.flush()?;
+    }
+
+    if let Some(ref mut index) = index {
+        index.write()?;
+    }
+
+    report.files = files.len();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::git::patch;
+    use crate::git::tests::TestRepo;
+    use super::*;
+
+    const DIFF: &str = "\
+diff --git a/src/lib.rs b/src/lib.rs
+index 3b18e51..a5c1966 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,3 +1,3 @@
+ one
+-two
++TWO
+ three
+";
+
+    const SERIES: &str = "\
+From 1111111111111111111111111111111111111111 Mon Sep 17 00:00:00 2001
+From: =?UTF-8?q?J=C3=B6rg?= Smith <jorg@example.com>
+Date: Tue, 4 Dec 2018 10:00:00 +0100
+Subject: [PATCH 1/2] Add a
+ file
+
+The body.
+---
+ a.txt | 1 +
+
+diff --git a/a.txt b/a.txt
+new file mode 100644
+index 0000000..7898192
+--- /dev/null
++++ b/a.txt
+@@ -0,0 +1 @@
++a
+-- 
+2.19.2
+
+From 2222222222222222222222222222222222222222 Mon Sep 17 00:00:00 2001
+From: Sender <sender@example.com>
+Date: Tue, 4 Dec 2018 11:00:00 +0000
+Subject: [PATCH 2/2] =?UTF-8?B?w4RuZGVy?= a
+
+From: Author <author@example.com>
+
+Changes it.
+---
+diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1 +1 @@
+-a
++b
+-- 
+2.19.2
+";
+
+    fn lines(file: &FileDiff) -> Vec<(char, String)> {
+        file.hunks.iter()
+            .flat_map(|x| x.lines.iter())
+            .map(|x| (x.origin, String::from_utf8_lossy(&x.content).into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_reads_a_single_diff() {
+        let messages = parse(DIFF);
+
+        assert_eq!(messages.len(), 1);
+        let file = &messages[0].files[0];
+        assert_eq!(file.old_path.as_deref(), Some("src/lib.rs"));
+        assert_eq!(file.new_path.as_deref(), Some("src/lib.rs"));
+        assert_eq!(file.status, git2::Delta::Modified);
+        assert_eq!(file.changed_lines, Some(2));
+        assert_eq!(lines(file), vec![
+            (' ', "one\n".to_string()),
+            ('-', "two\n".to_string()),
+            ('+', "TWO\n".to_string()),
+            (' ', "three\n".to_string())
+        ]);
+    }
+
+    #[test]
+    fn parse_reads_each_message_of_a_series() {
+        let messages = parse(SERIES);
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].subject, "Add a file");
+        assert_eq!(messages[0].author, Some(("Jörg Smith".to_string(), "jorg@example.com".to_string())));
+        assert_eq!(messages[0].date, Some((1543914000, 60)));
+        assert_eq!(messages[0].body, "The body.");
+        assert_eq!(messages[0].files[0].status, git2::Delta::Added);
+        assert_eq!(lines(&messages[0].files[0]), vec![('+', "a\n".to_string())]);
+
+        assert_eq!(messages[1].subject, "Änder a");
+        assert_eq!(messages[1].author, Some(("Author".to_string(), "author@example.com".to_string())));
+        assert_eq!(messages[1].body, "Changes it.");
+        assert_eq!(lines(&messages[1].files[0]), vec![('-', "a\n".to_string()), ('+', "b\n".to_string())]);
+    }
+
+    #[test]
+    fn decode_header_joins_encoded_words() {
+        assert_eq!(decode_header("=?utf-8?q?a_b?= =?utf-8?q?=C3=A9?= c"), "a bé c");
+        assert_eq!(decode_header("=?UTF-8?B?w6k=?="), "é");
+        assert_eq!(decode_header("plain"), "plain");
+    }
+
+    #[test]
+    fn parse_reads_renames_additions_and_deletions() {
+        let messages = parse("\
+diff --git a/old.txt b/new.txt
+similarity index 100%
+rename from old.txt
+rename to new.txt
+diff --git a/gone.txt b/gone.txt
+deleted file mode 100644
+--- a/gone.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-x
+diff --git a/run.sh b/run.sh
+new file mode 100755
+--- /dev/null
++++ b/run.sh
+@@ -0,0 +1 @@
++y
+");
+
+        let files: Vec<(Option<&str>, Option<&str>, git2::Delta)> = messages[0].files.iter()
+            .map(|x| (x.old_path.as_deref(), x.new_path.as_deref(), x.status))
+            .collect();
+        assert_eq!(files, vec![
+            (Some("old.txt"), Some("new.txt"), git2::Delta::Renamed),
+            (Some("gone.txt"), Some("gone.txt"), git2::Delta::Deleted),
+            (Some("run.sh"), Some("run.sh"), git2::Delta::Added)
+        ]);
+        assert_eq!(messages[0].files[2].header.new_mode, Some(MODE_EXECUTABLE));
+    }
+
+    #[test]
+    fn missing_newlines_are_marked_and_kept() {
+        let messages = parse("\
+--- a/a.txt
++++ b/a.txt
+@@ -1,2 +1,2 @@
+ a
+-b
+\\ No newline at end of file
++c
+\\ No newline at end of file
+");
+
+        let file = &messages[0].files[0];
+        let origins: Vec<char> = lines(file).iter().map(|x| x.0).collect();
+        assert_eq!(origins, vec![' ', '-', '>', '+', '<']);
+
+        let (result, rejected) = apply_hunks(b"a\nb", &file.hunks);
+        assert_eq!(result, b"a\nc");
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn blank_crlf_context_lines_without_a_space_are_kept() {
+        let messages = parse("--- a/a.txt\r\n+++ b/a.txt\r\n@@ -1,3 +1,3 @@\r\n a\r\n\r\n-b\r\n+c\r\n");
+
+        let file = &messages[0].files[0];
+        assert_eq!(lines(file), vec![
+            (' ', "a\r\n".to_string()),
+            (' ', "\r\n".to_string()),
+            ('-', "b\r\n".to_string()),
+            ('+', "c\r\n".to_string())
+        ]);
+
+        let (result, rejected) = apply_hunks(b"a\r\n\r\nb\r\n", &file.hunks);
+        assert_eq!(result, b"a\r\n\r\nc\r\n");
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn hunks_are_found_away_from_their_stated_lines() {
+        let file = &parse(DIFF)[0].files[0];
+
+        let (result, rejected) = apply_hunks(b"zero\nmore\none\ntwo\nthree\n", &file.hunks);
+        assert_eq!(result, b"zero\nmore\none\nTWO\nthree\n");
+        assert!(rejected.is_empty());
+
+        let (result, rejected) = apply_hunks(b"one\nsomething else\nthree\n", &file.hunks);
+        assert_eq!(result, b"one\nsomething else\nthree\n");
+        assert_eq!(rejected, vec![0]);
+    }
+
+    #[test]
+    fn nothing_is_written_unless_every_file_applies() {
+        let repo = TestRepo::new("apply-all-or-nothing");
+        fs::write(repo.dir.join("a.txt"), "a\n").unwrap();
+        fs::write(repo.dir.join("b.txt"), "other\n").unwrap();
+
+        let messages = parse("\
+--- a/a.txt
++++ b/a.txt
+@@ -1 +1 @@
+-a
++b
+--- a/b.txt
++++ b/b.txt
+@@ -1 +1 @@
+-b
++c
+");
+
+        let report = apply(&repo, &messages, ApplyTarget::WorkingTree).unwrap();
+        assert_eq!(report.files, 0);
+        assert_eq!(report.rejections.len(), 1);
+        assert_eq!(report.rejections[0].path, "b.txt");
+        assert_eq!(fs::read_to_string(repo.dir.join("a.txt")).unwrap(), "a\n");
+
+        fs::write(repo.dir.join("b.txt"), "b\n").unwrap();
+        let report = apply(&repo, &messages, ApplyTarget::WorkingTree).unwrap();
+        assert_eq!(report.files, 2);
+        assert!(report.rejections.is_empty());
+        assert_eq!(fs::read_to_string(repo.dir.join("a.txt")).unwrap(), "b\n");
+        assert_eq!(fs::read_to_string(repo.dir.join("b.txt")).unwrap(), "c\n");
+    }
+
+    #[test]
+    fn renames_onto_existing_files_are_rejected() {
+        let repo = TestRepo::new("apply-rename");
+        fs::write(repo.dir.join("old.txt"), "old\n").unwrap();
+        fs::write(repo.dir.join("new.txt"), "new\n").unwrap();
+
+        let messages = parse("\
+diff --git a/old.txt b/new.txt
+similarity index 100%
+rename from old.txt
+rename to new.txt
+");
+
+        let report = apply(&repo, &messages, ApplyTarget::WorkingTree).unwrap();
+        assert_eq!(report.rejections.len(), 1);
+        assert_eq!(fs::read_to_string(repo.dir.join("new.txt")).unwrap(), "new\n");
+
+        fs::remove_file(repo.dir.join("new.txt")).unwrap();
+        let report = apply(&repo, &messages, ApplyTarget::WorkingTree).unwrap();
+        assert!(report.rejections.is_empty());
+        assert!(!repo.dir.join("old.txt").exists());
+        assert_eq!(fs::read_to_string(repo.dir.join("new.txt")).unwrap(), "old\n");
+    }
+
+    #[test]
+    fn series_are_committed_in_turn() {
+        let repo = TestRepo::new("apply-commits");
+        {
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "Committer").unwrap();
+            config.set_str("user.email", "committer@example.com").unwrap();
+        }
+
+        let report = apply(&repo, &parse(SERIES), ApplyTarget::Commits).unwrap();
+        assert_eq!(report.commits, 2);
+        assert!(report.stopped_at.is_none());
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("Änder a"));
+        assert_eq!(head.author().name(), Some("Author"));
+        assert_eq!(head.parent(0).unwrap().author().name(), Some("Jörg Smith"));
+        assert_eq!(fs::read_to_string(repo.dir.join("a.txt")).unwrap(), "b\n");
+    }
+
+    #[test]
+    fn formatted_commits_read_back_the_same() {
+        let repo = TestRepo::bare("apply-format");
+        let tree = {
+            let mut tree = repo.treebuilder(None).unwrap();
+            tree.insert("a.txt", repo.blob(b"a\n").unwrap(), 0o100644).unwrap();
+            repo.find_tree(tree.write().unwrap()).unwrap()
+        };
+        let author = git2::Signature::new("Jörg", "jorg@example.com", &git2::Time::new(1543914000, 60)).unwrap();
+        let id = repo.commit(None, &author, &author, "Fix: the pärser.\n\nThe body.\n", &tree, &[]).unwrap();
+
+        let messages = parse(&patch::format_patch(&repo, &repo.find_commit(id).unwrap(), 1, 2).unwrap());
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].subject, "Fix: the pärser.");
+        assert_eq!(messages[0].body, "The body.");
+        assert_eq!(messages[0].author, Some(("Jörg".to_string(), "jorg@example.com".to_string())));
+        assert_eq!(messages[0].date, Some((1543914000, 60)));
+        assert_eq!(lines(&messages[0].files[0]), vec![('+', "a\n".to_string())]);
+    }
+}
@@ -0,0 +1,250 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use git2;
+
+const LOCKFILES: &[&str] = &[
+    "Cargo.lock", "package-lock.json", "npm-shrinkwrap.json", "yarn.lock", "pnpm-lock.yaml",
+    "Gemfile.lock", "composer.lock", "poetry.lock", "Pipfile.lock", "go.sum", "mix.lock",
+    "flake.lock", "Podfile.lock", "packages.lock.json", "pubspec.lock"
+];
+
+/// A line of a `.gitattributes` file that sets or unsets `linguist-generated`.
+struct Rule {
+    pattern: String,
+    /// Patterns containing a slash are matched against the whole path below the
+    /// directory of their file, the others against the file name alone.
+    is_anchored: bool,
+    is_generated: bool
+}
+
+fn parse(text: &str) -> Vec<Rule> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+
+            let mut parts = line.split_whitespace();
+            let pattern = parts.next()?;
+
+            // Git refuses negated patterns, and a pattern for a directory never applies
+            // to the files inside it.
+            if pattern.starts_with('!') || pattern.ends_with('/') {
+                return None;
+            }
+
+            let is_generated = parts.filter_map(|x| match x {
+                "linguist-generated" | "linguist-generated=true" => Some(true),
+                "-linguist-generated" | "!linguist-generated" | "linguist-generated=false" => Some(false),
+                _ => None
+            }).next_back()?;
+
+            Some(Rule {
+                pattern: pattern.trim_start_matches('/').to_string(),
+                is_anchored: pattern.contains('/'),
+                is_generated
+            })
+        })
+        .collect()
+}
+
+/// Matches a path against a glob where `*` and `?` stay within one directory and
+/// `**` crosses any number of whole directories.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+
+            match rest.first() {
+                Some(b'/') => (0..=text.len())
+                    .filter(|i| *i == 0 || text[i - 1] == b'/')
+                    .any(|i| glob(&rest[1..], &text[i..])),
+                _ => (0..=text.len()).any(|i| glob(rest, &text[i..]))
+            }
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            let end = text.iter().position(|x| *x == b'/').unwrap_or(text.len());
+            (0..=end).any(|i| glob(rest, &text[i..]))
+        }
+        Some(b'?') => !text.is_empty() && text[0] != b'/' && glob(&pattern[1..], &text[1..]),
+        Some(c) => text.first() == Some(c) && glob(&pattern[1..], &text[1..])
+    }
+}
+
+/// Decides which files are generated, either because they are well known lockfiles
+/// or because `.gitattributes` marks them with `linguist-generated`. Attribute files
+/// are read as they are needed from the tree being diffed, or from the working tree
+/// if there is none, and kept for the rest of the diff.
+pub struct GeneratedFiles<'a> {
+    repo: &'a git2::Repository,
+    tree: Option<git2::Tree<'a>>,
+    rules: HashMap<String, Vec<Rule>>,
+    info: Vec<Rule>
+}
+
+fn read(path: PathBuf) -> Vec<Rule> {
+    fs::read_to_string(path).map(|x| parse(&x)).unwrap_or_default()
+}
+
+impl<'a> GeneratedFiles<'a> {
+    pub fn new(repo: &'a git2::Repository, tree: Option<git2::Tree<'a>>) -> GeneratedFiles<'a> {
+        GeneratedFiles {
+            repo,
+            tree,
+            rules: HashMap::new(),
+            info: read(repo.path().join("info").join("attributes"))
+        }
+    }
+
+    /// The rules of the `.gitattributes` file in `dir`, relative to the top of the tree.
+    fn load(&mut self, dir: &str) {
+        if self.rules.contains_key(dir) {
+            return;
+        }
+
+        let path = Path::new(dir).join(".gitattributes");
+
+        let rules = match (self.tree.as_ref(), self.repo.workdir()) {
+            (Some(tree), _) => tree.get_path(&path).ok()
+                .and_then(|x| self.repo.find_blob(x.id()).ok())
+                .map(|x| parse(&String::from_utf8_lossy(x.content())))
+                .unwrap_or_default(),
+            (None, Some(workdir)) => read(workdir.join(path)),
+            (None, None) => vec![]
+        };
+
+        self.rules.insert(dir.to_string(), rules);
+    }
+
+    pub fn is_generated(&mut self, path: &str) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let mut is_generated = LOCKFILES.contains(&name);
+
+        // Deeper attribute files take precedence, and the repository's own info file
+        // over all of them.
+        let mut dirs = vec![String::new()];
+        let parts: Vec<&str> = path.split('/').collect();
+        for i in 1..parts.len() {
+            dirs.push(parts[..i].join("/"));
+        }
+
+        for dir in dirs.iter() {
+            self.load(dir);
+        }
+
+        let rules = dirs.iter()
+            .flat_map(|dir| self.rules[dir].iter().map(move |rule| (dir.as_str(), rule)))
+            .chain(self.info.iter().map(|rule| ("", rule)));
+
+        for (dir, rule) in rules {
+            let relative = if dir.is_empty() { path } else { &path[dir.len() + 1..] };
+            let text = if rule.is_anchored { relative } else { name };
+
+            if glob(rule.pattern.as_bytes(), text.as_bytes()) {
+                is_generated = rule.is_generated;
+            }
+        }
+
+        is_generated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::git::tests::TestRepo;
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn star_stays_within_a_directory() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/ui/main.rs"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "a/c"));
+    }
+
+    #[test]
+    fn double_star_crosses_whole_directories() {
+        assert!(matches("**/gen.rs", "gen.rs"));
+        assert!(matches("**/gen.rs", "a/b/gen.rs"));
+        assert!(!matches("**/gen.rs", "a/regen.rs"));
+        assert!(matches("vendor/**", "vendor/a/b.js"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+        assert!(!matches("a/**/b", "ab"));
+    }
+
+    #[test]
+    fn parse_reads_set_and_unset_attributes() {
+        let rules = parse("# comment\n\n*.pb.go linguist-generated\nCargo.lock -linguist-generated\n*.md text\nx.js linguist-generated=true !linguist-generated\n");
+
+        let rules: Vec<(&str, bool, bool)> = rules.iter().map(|x| (x.pattern.as_str(), x.is_anchored, x.is_generated)).collect();
+        assert_eq!(rules, vec![("*.pb.go", false, true), ("Cargo.lock", false, false), ("x.js", false, false)]);
+    }
+
+    #[test]
+    fn parse_anchors_patterns_with_a_slash() {
+        let rules = parse("/gen.rs linguist-generated\nsrc/gen/*.rs linguist-generated\n");
+
+        assert_eq!(rules[0].pattern, "gen.rs");
+        assert!(rules[0].is_anchored);
+        assert!(rules[1].is_anchored);
+    }
+
+    #[test]
+    fn parse_skips_directory_and_negated_patterns() {
+        assert!(parse("vendor/ linguist-generated\n!*.rs linguist-generated\n").is_empty());
+    }
+
+    #[test]
+    fn attributes_are_read_from_the_tree() {
+        let repo = TestRepo::bare("attributes");
+
+        let tree = {
+            let root = repo.blob(b"*.gen linguist-generated\n/top.txt linguist-generated\n").unwrap();
+            let nested = repo.blob(b"*.lock -linguist-generated\n").unwrap();
+
+            let mut sub = repo.treebuilder(None).unwrap();
+            sub.insert(".gitattributes", nested, 0o100644).unwrap();
+            let sub = sub.write().unwrap();
+
+            let mut top = repo.treebuilder(None).unwrap();
+            top.insert(".gitattributes", root, 0o100644).unwrap();
+            top.insert("sub", sub, 0o040000).unwrap();
+            repo.find_tree(top.write().unwrap()).unwrap()
+        };
+
+        let mut generated = GeneratedFiles::new(&repo, Some(tree));
+
+        assert!(generated.is_generated("a/b.gen"));
+        assert!(generated.is_generated("top.txt"));
+        assert!(!generated.is_generated("sub/top.txt"));
+        assert!(generated.is_generated("Cargo.lock"));
+        assert!(!generated.is_generated("sub/Cargo.lock"));
+        assert!(!generated.is_generated("main.rs"));
+    }
+}
@@ -0,0 +1,840 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use git2;
+
+use super::attributes::GeneratedFiles;
+use super::worker::{CancelToken, WorkerError};
+
+#[derive(Debug, Clone)]
+pub struct DiffLine {
+    pub origin: char,
+    pub old_lineno: Option<u32>,
+    pub new_lineno: Option<u32>,
+    pub content: Vec<u8>
+}
+
+impl<'a> From<&'a git2::DiffLine<'a>> for DiffLine {
+    fn from(line: &git2::DiffLine) -> DiffLine {
+        DiffLine {
+            origin: line.origin(),
+            old_lineno: line.old_lineno(),
+            new_lineno: line.new_lineno(),
+            content: line.content().to_vec()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffHunk {
+    pub header: String,
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    pub lines: Vec<DiffLine>
+}
+
+impl DiffHunk {
+    /// The first old line after the hunk. A hunk that removes nothing starts after
+    /// its start line rather than on it, as in the patch format.
+    pub fn old_end(&self) -> u32 {
+        if self.old_lines == 0 { self.old_start + 1 } else { self.old_start + self.old_lines }
+    }
+
+    /// The first old line the hunk covers or is inserted before.
+    pub fn old_first(&self) -> u32 {
+        if self.old_lines == 0 { self.old_start + 1 } else { self.old_start }
+    }
+
+    /// How far the new line numbers after the hunk are ahead of the old ones.
+    pub fn offset(&self) -> i64 {
+        let new_end = if self.new_lines == 0 { self.new_start + 1 } else { self.new_start + self.new_lines };
+        new_end as i64 - self.old_end() as i64
+    }
+}
+
+/// Image blobs larger than this are not loaded for display.
+const MAX_IMAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "bmp", "ico", "webp", "tif", "tiff"];
+
+/// The sides of a binary file. Either side is `None` when the file was added or deleted.
+#[derive(Debug, Clone, Default)]
+pub struct BinaryDiff {
+    pub old_size: Option<usize>,
+    pub new_size: Option<usize>,
+    /// The contents of both sides, only loaded for images that are small enough to show.
+    pub old_image: Option<Vec<u8>>,
+    pub new_image: Option<Vec<u8>>
+}
+
+pub const MODE_FILE: u32 = 0o100644;
+pub const MODE_EXECUTABLE: u32 = 0o100755;
+const MODE_LINK: u32 = 0o120000;
+const MODE_SUBMODULE: u32 = 0o160000;
+
+/// What the header of a file's patch tells besides its content, and where symbolic
+/// links point on either side.
+#[derive(Debug, Clone, Default)]
+pub struct FileHeader {
+    pub similarity: Option<u16>,
+    pub old_mode: Option<u32>,
+    pub new_mode: Option<u32>,
+    pub old_target: Option<String>,
+    pub new_target: Option<String>
+}
+
+impl FileHeader {
+    pub fn parse(text: &str) -> FileHeader {
+        let mut header = FileHeader::default();
+        let mode = |x: &str| u32::from_str_radix(x.trim(), 8).ok();
+
+        for line in text.lines() {
+            if let Some(v) = line.strip_prefix("similarity index ") {
+                header.similarity = v.trim_end_matches('%').parse().ok();
+            } else if let Some(v) = line.strip_prefix("old mode ").or(line.strip_prefix("deleted file mode ")) {
+                header.old_mode = mode(v);
+            } else if let Some(v) = line.strip_prefix("new mode ").or(line.strip_prefix("new file mode ")) {
+                header.new_mode = mode(v);
+            } else if line.starts_with("index ") {
+                // Unchanged modes are only given here, after the ids.
+                let unchanged = line.split_whitespace().nth(2).and_then(mode);
+                header.old_mode = header.old_mode.or(unchanged);
+                header.new_mode = header.new_mode.or(unchanged);
+            }
+        }
+
+        header
+    }
+}
+
+/// A delta's old and new paths.
+type DeltaPaths = (Option<String>, Option<String>);
+
+/// Reads the patch headers of every delta.
+fn headers(diff: &git2::Diff) -> Result<HashMap<DeltaPaths, FileHeader>, git2::Error> {
+    let mut headers = HashMap::new();
+
+    diff.print(git2::DiffFormat::PatchHeader, |delta, _, line| {
+        if line.origin() == 'F' {
+            let path = |x: Option<&Path>| x.map(|x| x.to_string_lossy().to_string());
+            let key = (path(delta.old_file().path()), path(delta.new_file().path()));
+            headers.insert(key, FileHeader::parse(&String::from_utf8_lossy(line.content())));
+        }
+        true
+    })?;
+
+    Ok(headers)
+}
+
+fn short_id(id: git2::Oid) -> String {
+    id.to_string()[..7].to_string()
+}
+
+/// An owned copy of a delta and its patch, so it can be sent from the worker to the UI.
+/// The patch is only filled in once it is loaded with `file_patches`.
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub old_id: git2::Oid,
+    pub new_id: git2::Oid,
+    pub status: git2::Delta,
+    pub hunks: Vec<DiffHunk>,
+    pub binary: Option<BinaryDiff>,
+    pub header: FileHeader,
+    /// The size in bytes of the larger side.
+    pub size: usize,
+    /// Whether the file is a lockfile or marked as `linguist-generated`.
+    pub is_generated: bool,
+    /// The number of added and removed lines, known once the patch was generated.
+    pub changed_lines: Option<usize>,
+    pub is_loaded: bool
+}
+
+/// Finds the size of one side of a delta without reading its contents.
+fn side_size(repo: &git2::Repository, file: &git2::DiffFile) -> usize {
+    if !file.id().is_zero() {
+        return repo.odb()
+            .and_then(|x| x.read_header(file.id()))
+            .map(|(size, _)| size)
+            .unwrap_or(0);
+    }
+
+    repo.workdir()
+        .and_then(|x| Some(x.join(file.path()?)))
+        .and_then(|x| ::std::fs::metadata(x).ok())
+        .map(|x| x.len() as usize)
+        .unwrap_or(0)
+}
+
+impl FileDiff {
+    fn new(repo: &git2::Repository, delta: &git2::DiffDelta) -> FileDiff {
+        let old_file = delta.old_file();
+        let new_file = delta.new_file();
+
+        FileDiff {
+            old_path: old_file.path().map(|x| x.to_string_lossy().to_string()),
+            new_path: new_file.path().map(|x| x.to_string_lossy().to_string()),
+            old_id: old_file.id(),
+            new_id: new_file.id(),
+            status: delta.status(),
+            hunks: vec![],
+            binary: None,
+            header: FileHeader::default(),
+            size: side_size(repo, &old_file).max(side_size(repo, &new_file)),
+            is_generated: false,
+            changed_lines: None,
+            is_loaded: false
+        }
+    }
+
+    /// Takes the patch loaded by `file_patches`, keeping what was read when the diff
+    /// was listed.
+    pub fn set_patch(&mut self, loaded: FileDiff) {
+        self.hunks = loaded.hunks;
+        self.binary = loaded.binary;
+        self.changed_lines = loaded.changed_lines;
+        self.is_loaded = loaded.is_loaded;
+    }
+
+    /// The path shown for the file, which is the old path only when it was deleted.
+    pub fn path(&self) -> &str {
+        self.new_path.as_ref()
+            .or(self.old_path.as_ref())
+            .map(|x| x.as_str())
+            .unwrap_or("")
+    }
+
+    /// The path, or both paths for renames and copies.
+    pub fn label(&self) -> String {
+        match (self.status, &self.old_path) {
+            (git2::Delta::Renamed, Some(old)) | (git2::Delta::Copied, Some(old)) => format!("{} → {}", old, self.path()),
+            _ => self.path().to_string()
+        }
+    }
+
+    /// Describes what changed about the file besides its content: how similar a rename
+    /// or copy is, its mode, where a symbolic link points and which commit a submodule
+    /// is at.
+    pub fn annotations(&self) -> Vec<String> {
+        let header = &self.header;
+        let mut notes = vec![];
+
+        let similar = |verb: &str| match header.similarity {
+            Some(n) => format!("{}, {}% similar", verb, n),
+            None => verb.to_string()
+        };
+
+        match self.status {
+            git2::Delta::Renamed => notes.push(similar("renamed")),
+            git2::Delta::Copied => notes.push(similar("copied")),
+            _ => {}
+        }
+
+        match (header.old_mode, header.new_mode) {
+            (Some(MODE_FILE), Some(MODE_EXECUTABLE)) => notes.push("made executable".to_string()),
+            (Some(MODE_EXECUTABLE), Some(MODE_FILE)) => notes.push("no longer executable".to_string()),
+            (Some(old), Some(new)) if old != new => notes.push(format!("mode {:o} → {:o}", old, new)),
+            (None, Some(MODE_EXECUTABLE)) => notes.push("executable".to_string()),
+            _ => {}
+        }
+
+        match (&header.old_target, &header.new_target) {
+            (Some(old), Some(new)) if old != new => notes.push(format!("symlink {} → {}", old, new)),
+            (_, Some(target)) | (Some(target), None) => notes.push(format!("symlink to {}", target)),
+            _ => {}
+        }
+
+        let is_submodule = |mode| mode == Some(MODE_SUBMODULE);
+        match (is_submodule(header.old_mode), is_submodule(header.new_mode)) {
+            (true, true) if self.old_id.is_zero() || self.new_id.is_zero() => notes.push("submodule, modified".to_string()),
+            (true, true) => notes.push(format!("submodule {} → {}", short_id(self.old_id), short_id(self.new_id))),
+            (false, true) if !self.new_id.is_zero() => notes.push(format!("submodule at {}", short_id(self.new_id))),
+            (true, false) if !self.old_id.is_zero() => notes.push(format!("submodule was at {}", short_id(self.old_id))),
+            (false, true) | (true, false) => notes.push("submodule".to_string()),
+            (false, false) => {}
+        }
+
+        notes
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WhitespaceMode {
+    Show,
+    IgnoreAtEol,
+    IgnoreChanges,
+    IgnoreAll
+}
+
+impl WhitespaceMode {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            WhitespaceMode::Show => "show",
+            WhitespaceMode::IgnoreAtEol => "ignore-eol",
+            WhitespaceMode::IgnoreChanges => "ignore-changes",
+            WhitespaceMode::IgnoreAll => "ignore-all"
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<WhitespaceMode> {
+        match value {
+            "show" => Some(WhitespaceMode::Show),
+            "ignore-eol" => Some(WhitespaceMode::IgnoreAtEol),
+            "ignore-changes" => Some(WhitespaceMode::IgnoreChanges),
+            "ignore-all" => Some(WhitespaceMode::IgnoreAll),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DiffAlgorithm {
+    Myers,
+    Patience,
+    Minimal
+}
+
+impl DiffAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DiffAlgorithm::Myers => "myers",
+            DiffAlgorithm::Patience => "patience",
+            DiffAlgorithm::Minimal => "minimal"
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<DiffAlgorithm> {
+        match value {
+            "myers" => Some(DiffAlgorithm::Myers),
+            "patience" => Some(DiffAlgorithm::Patience),
+            "minimal" => Some(DiffAlgorithm::Minimal),
+            _ => None
+        }
+    }
+}
+
+/// How diffs are generated. The same settings apply to every diff that is shown.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DiffSettings {
+    pub whitespace: WhitespaceMode,
+    pub context_lines: u32,
+    pub algorithm: DiffAlgorithm,
+    pub detect_renames: bool,
+    pub rename_threshold: u16,
+    pub detect_copies: bool,
+    pub copy_threshold: u16
+}
+
+impl Default for DiffSettings {
+    fn default() -> DiffSettings {
+        DiffSettings {
+            whitespace: WhitespaceMode::Show,
+            context_lines: 3,
+            algorithm: DiffAlgorithm::Myers,
+            detect_renames: true,
+            rename_threshold: 50,
+            detect_copies: false,
+            copy_threshold: 50
+        }
+    }
+}
+
+impl DiffSettings {
+    pub fn diff_options(&self) -> git2::DiffOptions {
+        let mut opts = git2::DiffOptions::new();
+
+        opts.ignore_whitespace_eol(self.whitespace == WhitespaceMode::IgnoreAtEol)
+            .ignore_whitespace_change(self.whitespace == WhitespaceMode::IgnoreChanges)
+            .ignore_whitespace(self.whitespace == WhitespaceMode::IgnoreAll)
+            .context_lines(self.context_lines)
+            .patience(self.algorithm == DiffAlgorithm::Patience)
+            .minimal(self.algorithm == DiffAlgorithm::Minimal);
+
+        opts
+    }
+
+    pub fn find_options(&self) -> git2::DiffFindOptions {
+        let mut opts = git2::DiffFindOptions::new();
+
+        opts.renames(self.detect_renames)
+            .rename_threshold(self.rename_threshold)
+            .copies(self.detect_copies)
+            .copy_threshold(self.copy_threshold);
+
+        opts
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiffStats {
+    pub files: usize,
+    pub insertions: usize,
+    pub deletions: usize
+}
+
+impl DiffStats {
+    fn new(diff: &git2::Diff) -> Result<DiffStats, git2::Error> {
+        let stats = diff.stats()?;
+
+        Ok(DiffStats {
+            files: stats.files_changed(),
+            insertions: stats.insertions(),
+            deletions: stats.deletions()
+        })
+    }
+}
+
+fn hunks(patch: &git2::Patch) -> Result<Vec<DiffHunk>, git2::Error> {
+    let mut hunks = vec![];
+
+    for hunk_idx in 0..patch.num_hunks() {
+        let (hunk, line_count) = patch.hunk(hunk_idx)?;
+        let mut lines = vec![];
+
+        for line_idx in 0..line_count {
+            lines.push(DiffLine::from(&patch.line_in_hunk(hunk_idx, line_idx)?));
+        }
+
+        hunks.push(DiffHunk {
+            header: String::from_utf8_lossy(hunk.header()).to_string(),
+            old_start: hunk.old_start(),
+            old_lines: hunk.old_lines(),
+            new_start: hunk.new_start(),
+            new_lines: hunk.new_lines(),
+            lines
+        });
+    }
+
+    Ok(hunks)
+}
+
+/// Reads a file from the object database, or from the working tree if git has not
+/// hashed it.
+fn read_file(repo: &git2::Repository, id: git2::Oid, path: Option<&Path>) -> Option<Vec<u8>> {
+    if !id.is_zero() {
+        return repo.find_blob(id).ok().map(|x| x.content().to_vec());
+    }
+
+    let path = repo.workdir()?.join(path?);
+    ::std::fs::read(path).ok()
+}
+
+/// Reads one side of a delta.
+fn side_content(repo: &git2::Repository, file: &git2::DiffFile) -> Option<Vec<u8>> {
+    read_file(repo, file.id(), file.path())
+}
+
+/// The lines of one side of a file, for showing more of the unchanged lines around
+/// its hunks. Each line keeps its line ending, as diff lines do.
+pub fn file_lines(repo: &git2::Repository, id: git2::Oid, path: Option<&str>) -> Vec<String> {
+    read_file(repo, id, path.map(Path::new))
+        .map(|x| String::from_utf8_lossy(&x).split_inclusive('\n').map(|x| x.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Whether libgit2 found either side of a patch to be binary, which it reports as a
+/// single line in place of any hunks. This also respects the `binary` attribute.
+fn is_binary(patch: &mut git2::Patch) -> Result<bool, git2::Error> {
+    let mut is_binary = false;
+    patch.print(&mut |_, _, line| {
+        is_binary |= line.origin() == 'B';
+        true
+    })?;
+    Ok(is_binary)
+}
+
+fn is_image(path: &str) -> bool {
+    Path::new(path).extension()
+        .and_then(|x| x.to_str())
+        .map(|x| IMAGE_EXTENSIONS.contains(&x.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Describes a binary delta. Its sides are only read for images small enough to show;
+/// the sizes come from the object headers.
+fn binary_diff(repo: &git2::Repository, delta: &git2::DiffDelta, path: &str) -> BinaryDiff {
+    let has_old = !matches!(delta.status(), git2::Delta::Added | git2::Delta::Untracked);
+    let has_new = delta.status() != git2::Delta::Deleted;
+
+    let (old_file, new_file) = (delta.old_file(), delta.new_file());
+    let old_size = if has_old { Some(side_size(repo, &old_file)) } else { None };
+    let new_size = if has_new { Some(side_size(repo, &new_file)) } else { None };
+
+    let image = |file: &git2::DiffFile, size: Option<usize>| match size {
+        Some(size) if size <= MAX_IMAGE_SIZE && is_image(path) => side_content(repo, file),
+        _ => None
+    };
+
+    BinaryDiff {
+        old_size,
+        new_size,
+        old_image: image(&old_file, old_size),
+        new_image: image(&new_file, new_size)
+    }
+}
+
+/// Reads where a symbolic link points.
+fn link_target(repo: &git2::Repository, file: &git2::DiffFile) -> Option<String> {
+    side_content(repo, file).map(|x| String::from_utf8_lossy(&x).to_string())
+}
+
+/// Copies the deltas out of a diff without their patches.
+/// `tree` is what the diff goes to, for reading the attributes from; without one they
+/// come from the working tree.
+fn collect<'a>(repo: &'a git2::Repository, diff: &git2::Diff, tree: Option<git2::Tree<'a>>, cancel: &CancelToken) -> Result<Vec<FileDiff>, WorkerError> {
+    let mut generated = GeneratedFiles::new(repo, tree);
+    let mut headers = headers(diff)?;
+    let mut files = vec![];
+
+    for delta in diff.deltas() {
+        cancel.check()?;
+
+        let mut file = FileDiff::new(repo, &delta);
+        file.is_generated = generated.is_generated(file.path());
+
+        if let Some(mut header) = headers.remove(&(file.old_path.clone(), file.new_path.clone())) {
+            if header.old_mode == Some(MODE_LINK) {
+                header.old_target = link_target(repo, &delta.old_file());
+            }
+            if header.new_mode == Some(MODE_LINK) {
+                header.new_target = link_target(repo, &delta.new_file());
+            }
+            file.header = header;
+        }
+
+        files.push(file);
+    }
+
+    Ok(files)
+}
+
+/// Fills in the patch of the `n`th delta, unless it changes more than `max_lines` lines.
+fn load_patch(repo: &git2::Repository, diff: &git2::Diff, n: usize, file: &mut FileDiff, max_lines: Option<usize>) -> Result<(), git2::Error> {
+    if let Some(mut patch) = git2::Patch::from_diff(diff, n)? {
+        let (_, insertions, deletions) = patch.line_stats()?;
+        file.changed_lines = Some(insertions + deletions);
+
+        if max_lines.map(|x| insertions + deletions > x).unwrap_or(false) {
+            return Ok(());
+        }
+
+        file.hunks = hunks(&patch)?;
+
+        // Renames and mode changes have no hunks either, but need nothing read.
+        if file.hunks.is_empty() && is_binary(&mut patch)? {
+            file.binary = Some(binary_diff(repo, &patch.delta(), file.path()));
+        }
+    }
+
+    file.is_loaded = true;
+    Ok(())
+}
+
+/// What a commit is compared against. Only merges have more than one choice.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DiffBase {
+    Parent(usize),
+    /// Compares a merge with what merging its parents automatically would have given,
+    /// which leaves only the conflict resolutions and any other changes made by hand.
+    ConflictResolution
+}
+
+/// Merges the two parents of a merge commit again. Conflicting hunks are settled in
+/// favour of the first parent, as are files that could not be merged at all.
+fn remerge_tree<'a>(repo: &'a git2::Repository, commit: &git2::Commit) -> Result<git2::Tree<'a>, git2::Error> {
+    let (ours, theirs) = (commit.parent(0)?, commit.parent(1)?);
+
+    let mut opts = git2::MergeOptions::new();
+    opts.file_favor(git2::FileFavor::Ours);
+
+    let mut index = repo.merge_commits(&ours, &theirs, Some(&opts))?;
+
+    if index.has_conflicts() {
+        let mut conflicts: Vec<String> = index.iter()
+            .filter(|x| (x.flags >> 12) & 0x3 != 0)
+            .map(|x| String::from_utf8_lossy(&x.path).to_string())
+            .collect();
+        conflicts.dedup();
+
+        for path in conflicts {
+            let path = Path::new(&path);
+            let ours = index.get_path(path, 2);
+            index.remove_path(path)?;
+
+            if let Some(mut entry) = ours {
+                entry.flags &= !0x3000;
+                index.add(&entry)?;
+            }
+        }
+    }
+
+    let id = index.write_tree_to(repo)?;
+    repo.find_tree(id)
+}
+
+fn base_tree<'a>(repo: &'a git2::Repository, commit: &git2::Commit<'a>, base: DiffBase) -> Result<Option<git2::Tree<'a>>, git2::Error> {
+    match base {
+        DiffBase::ConflictResolution if commit.parent_count() == 2 => Ok(Some(remerge_tree(repo, commit)?)),
+        DiffBase::Parent(n) if n < commit.parent_count() => Ok(Some(commit.parent(n)?.tree()?)),
+        _ => match commit.parents().next() {
+            Some(v) => Ok(Some(v.tree()?)),
+            None => Ok(None)
+        }
+    }
+}
+
+pub fn commit_diff(repo: &git2::Repository, id: git2::Oid, base: DiffBase, settings: &DiffSettings, cancel: &CancelToken) -> Result<(DiffStats, Vec<FileDiff>), WorkerError> {
+    let diff = source_diff(repo, &DiffSource::Commit(id, base), settings, &[])?;
+    let tree = repo.find_commit(id)?.tree()?;
+    Ok((DiffStats::new(&diff)?, collect(repo, &diff, Some(tree), cancel)?))
+}
+
+/// One side of a comparison: a commit id or ref name, or the working tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffSide {
+    Rev(String),
+    WorkingTree
+}
+
+/// The two sides of a comparison between arbitrary commits, refs or the working tree.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub from_label: String,
+    pub to_label: String,
+    pub stats: DiffStats
+}
+
+/// Describes a side of a comparison: refs by name, commits by short id and summary.
+fn describe_side(repo: &git2::Repository, side: &DiffSide) -> Result<String, git2::Error> {
+    let rev = match *side {
+        DiffSide::Rev(ref rev) => rev,
+        DiffSide::WorkingTree => return Ok("Working tree".to_string())
+    };
+
+    let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+
+    if git2::Oid::from_str(rev).map(|x| x == commit.id()).unwrap_or(false) {
+        let id = commit.id().to_string();
+        Ok(format!("{} {}", &id[..7], commit.summary().unwrap_or("")))
+    } else {
+        Ok(rev.to_string())
+    }
+}
+
+fn side_tree<'a>(repo: &'a git2::Repository, side: &DiffSide) -> Result<Option<git2::Tree<'a>>, git2::Error> {
+    match *side {
+        DiffSide::Rev(ref rev) => Ok(Some(repo.revparse_single(rev)?.peel_to_commit()?.tree()?)),
+        DiffSide::WorkingTree => Ok(None)
+    }
+}
+
+/// Diffs any two sides, as if going from `from` to `to`. The working tree includes
+/// what is staged and untracked files, like `git diff <commit>` would show.
+pub fn compare(repo: &git2::Repository, from: &DiffSide, to: &DiffSide, settings: &DiffSettings, cancel: &CancelToken) -> Result<(Comparison, Vec<FileDiff>), WorkerError> {
+    let diff = source_diff(repo, &DiffSource::Comparison(from.clone(), to.clone()), settings, &[])?;
+
+    let comparison = Comparison {
+        from_label: describe_side(repo, from)?,
+        to_label: describe_side(repo, to)?,
+        stats: DiffStats::new(&diff)?
+    };
+
+    Ok((comparison, collect(repo, &diff, side_tree(repo, to)?, cancel)?))
+}
+
+/// Returns the staged changes and the full set of changes in the working tree, the
+/// latter only used for the file list.
+pub fn workdir_diff(repo: &git2::Repository, settings: &DiffSettings, cancel: &CancelToken) -> Result<(Vec<FileDiff>, Vec<FileDiff>), WorkerError> {
+    let repo_head_tree = repo.head()?.peel_to_tree()?;
+    let mut diff_opts = settings.diff_options();
+    diff_opts
+        .include_untracked(true)
+        .recurse_untracked_dirs(true);
+
+    let mut workdir_diff = repo.diff_tree_to_workdir_with_index(Some(&repo_head_tree), Some(&mut diff_opts))?;
+    workdir_diff.find_similar(Some(&mut settings.find_options()))?;
+
+    let index_diff = source_diff(repo, &DiffSource::Staged, settings, &[])?;
+
+    Ok((collect(repo, &index_diff, None, cancel)?, collect(repo, &workdir_diff, None, cancel)?))
+}
+
+/// Where a diff that is shown in full comes from, so that the patches of its files
+/// can be generated when they are needed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffSource {
+    Staged,
+    Commit(git2::Oid, DiffBase),
+    Comparison(DiffSide, DiffSide)
+}
+
+/// Generates the diff of `source`, limited to `paths` unless they are empty.
+fn source_diff<'a>(repo: &'a git2::Repository, source: &DiffSource, settings: &DiffSettings, paths: &[&str]) -> Result<git2::Diff<'a>, git2::Error> {
+    let mut opts = settings.diff_options();
+    opts.disable_pathspec_match(true);
+
+    for path in paths {
+        opts.pathspec(*path);
+    }
+
+    let mut diff = match *source {
+        DiffSource::Staged => {
+            let head = repo.head()?.peel_to_tree()?;
+            repo.diff_tree_to_index(Some(&head), None, Some(&mut opts))?
+        }
+        DiffSource::Commit(id, base) => {
+            let commit = repo.find_commit(id)?;
+            let base = base_tree(repo, &commit, base)?;
+            repo.diff_tree_to_tree(base.as_ref(), Some(&commit.tree()?), Some(&mut opts))?
+        }
+        DiffSource::Comparison(ref from, ref to) => {
+            opts.include_untracked(true)
+                .recurse_untracked_dirs(true)
+                .show_untracked_content(true);
+
+            match (side_tree(repo, from)?, side_tree(repo, to)?) {
+                (Some(from), Some(to)) => repo.diff_tree_to_tree(Some(&from), Some(&to), Some(&mut opts))?,
+                (Some(tree), None) => repo.diff_tree_to_workdir_with_index(Some(&tree), Some(&mut opts))?,
+                (None, Some(tree)) => repo.diff_tree_to_workdir_with_index(Some(&tree), Some(opts.reverse(true)))?,
+                (None, None) => repo.diff_tree_to_tree(None, None, None)?
+            }
+        }
+    };
+
+    diff.find_similar(Some(&mut settings.find_options()))?;
+    Ok(diff)
+}
+
+/// A file of a diff whose patch is wanted, by its position in the list of files.
+#[derive(Debug, Clone)]
+pub struct PatchRequest {
+    pub index: usize,
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    /// Files changing more lines than this are left unloaded.
+    pub max_lines: Option<usize>
+}
+
+/// Generates the patches of a few files of a diff at once, by diffing only their paths.
+/// Only the patches are filled in; see `FileDiff::set_patch`.
+pub fn file_patches(repo: &git2::Repository, source: &DiffSource, settings: &DiffSettings, requests: &[PatchRequest], cancel: &CancelToken) -> Result<Vec<(usize, FileDiff)>, WorkerError> {
+    let paths: Vec<&str> = requests.iter()
+        .flat_map(|x| x.old_path.iter().chain(x.new_path.iter()))
+        .map(|x| x.as_str())
+        .collect();
+
+    let diff = source_diff(repo, source, settings, &paths)?;
+    let mut files = vec![];
+
+    // The headers and attributes were already read when the diff was first listed.
+    for (n, delta) in diff.deltas().enumerate() {
+        let mut file = FileDiff::new(repo, &delta);
+        let request = requests.iter()
+            .find(|x| x.old_path == file.old_path && x.new_path == file.new_path);
+
+        if let Some(request) = request {
+            cancel.check()?;
+            load_patch(repo, &diff, n, &mut file, request.max_lines)?;
+            files.push((request.index, file));
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(status: git2::Delta, old_path: &str, new_path: &str, header: FileHeader) -> FileDiff {
+        FileDiff {
+            old_path: Some(old_path.to_string()),
+            new_path: Some(new_path.to_string()),
+            old_id: git2::Oid::zero(),
+            new_id: git2::Oid::zero(),
+            status,
+            hunks: vec![],
+            binary: None,
+            header,
+            size: 0,
+            is_generated: false,
+            changed_lines: None,
+            is_loaded: true
+        }
+    }
+
+    #[test]
+    fn header_reads_modes_and_similarity() {
+        let header = FileHeader::parse("similarity index 90%\nrename from a\nrename to b\nindex 1234567..89abcde 100755\n");
+        assert_eq!(header.similarity, Some(90));
+        assert_eq!((header.old_mode, header.new_mode), (Some(MODE_EXECUTABLE), Some(MODE_EXECUTABLE)));
+
+        let header = FileHeader::parse("old mode 100644\nnew mode 100755\nindex 1234567..89abcde\n");
+        assert_eq!((header.old_mode, header.new_mode), (Some(MODE_FILE), Some(MODE_EXECUTABLE)));
+
+        let header = FileHeader::parse("new file mode 120000\nindex 0000000..89abcde\n");
+        assert_eq!((header.old_mode, header.new_mode), (None, Some(MODE_LINK)));
+    }
+
+    #[test]
+    fn renames_show_both_paths_and_their_similarity() {
+        let header = FileHeader { similarity: Some(75), ..FileHeader::default() };
+        let renamed = file(git2::Delta::Renamed, "old.rs", "new.rs", header);
+
+        assert_eq!(renamed.path(), "new.rs");
+        assert_eq!(renamed.label(), "old.rs → new.rs");
+        assert_eq!(renamed.annotations(), vec!["renamed, 75% similar"]);
+
+        let modified = file(git2::Delta::Modified, "a.rs", "a.rs", FileHeader::default());
+        assert_eq!(modified.label(), "a.rs");
+        assert!(modified.annotations().is_empty());
+    }
+
+    #[test]
+    fn mode_and_link_changes_are_described() {
+        let header = FileHeader { old_mode: Some(MODE_FILE), new_mode: Some(MODE_EXECUTABLE), ..FileHeader::default() };
+        assert_eq!(file(git2::Delta::Modified, "a", "a", header).annotations(), vec!["made executable"]);
+
+        let header = FileHeader {
+            old_mode: Some(MODE_LINK),
+            new_mode: Some(MODE_LINK),
+            old_target: Some("x".to_string()),
+            new_target: Some("y".to_string()),
+            ..FileHeader::default()
+        };
+        assert_eq!(file(git2::Delta::Modified, "a", "a", header).annotations(), vec!["symlink x → y"]);
+    }
+
+    #[test]
+    fn submodules_show_the_commits_they_point_at() {
+        let mut moved = file(git2::Delta::Modified, "lib", "lib", FileHeader {
+            old_mode: Some(MODE_SUBMODULE),
+            new_mode: Some(MODE_SUBMODULE),
+            ..FileHeader::default()
+        });
+        moved.old_id = git2::Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+        moved.new_id = git2::Oid::from_str("2222222222222222222222222222222222222222").unwrap();
+        assert_eq!(moved.annotations(), vec!["submodule 1111111 → 2222222"]);
+
+        moved.new_id = git2::Oid::zero();
+        assert_eq!(moved.annotations(), vec!["submodule, modified"]);
+    }
+}
@@ -0,0 +1,777 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cmp::min;
+use std::collections::HashMap;
+
+use chrono::{self, TimeZone};
+use git2;
+
+use super::diff::{DiffBase, DiffStats};
+use super::worker::{CancelToken, WorkerError};
+
+/// How many commits are materialised at a time as the history is scrolled.
+pub const PAGE_SIZE: usize = 200;
+
+#[derive(Debug, Clone)]
+pub struct CommitInfo {
+    pub id: git2::Oid,
+    summary: String,
+    pub short_id: String,
+    pub author: String,
+    pub commit_date: String,
+    pub branch_heads: Vec<String>,
+    pub parents: Vec<git2::Oid>
+}
+
+impl CommitInfo {
+    pub fn summary(&self) -> String {
+        if self.branch_heads.is_empty() {
+            return self.summary.to_string();
+        }
+
+        let mut out = String::new();
+        
+        for name in &self.branch_heads {
+            out.push_str(&format!("[{}] ", &name));
+        }
+        out.push_str(&self.summary);
+        out
+    } 
+}
+
+const UNCOMMITTED_STR: &str = "<b>Uncommitted changes</b>";
+
+impl CommitInfo {
+    pub fn uncommitted_sentinel(head: Option<git2::Oid>) -> CommitInfo {
+        CommitInfo {
+            id: git2::Oid::zero(),
+            summary: UNCOMMITTED_STR.into(),
+            short_id: "*".into(),
+            author: "*".into(),
+            commit_date: "*".into(),
+            branch_heads: vec![],
+            parents: head.into_iter().collect()
+        }
+    }
+
+    pub fn is_sentinel(&self) -> bool {
+        self.id == git2::Oid::zero() && self.summary == UNCOMMITTED_STR
+    }
+}
+
+/// A commit linked to from the overview, such as a parent.
+#[derive(Debug, Clone)]
+pub struct CommitLink {
+    pub id: git2::Oid,
+    pub short_id: String,
+    pub summary: String
+}
+
+impl CommitLink {
+    fn new(commit: &git2::Commit) -> CommitLink {
+        CommitLink {
+            id: commit.id(),
+            short_id: commit.short_id_str(),
+            summary: commit.full_summary_str().to_string()
+        }
+    }
+}
+
+impl From<&CommitInfo> for CommitLink {
+    fn from(info: &CommitInfo) -> CommitLink {
+        CommitLink {
+            id: info.id,
+            short_id: info.short_id.clone(),
+            summary: info.summary.clone()
+        }
+    }
+}
+
+/// What the overview pane shows about the selected commit.
+#[derive(Debug, Clone)]
+pub struct CommitDetails {
+    pub id: git2::Oid,
+    pub author: String,
+    pub date: String,
+    /// Only set when someone else committed it, or at another time.
+    pub committer: Option<(String, String)>,
+    pub message: String,
+    pub parents: Vec<CommitLink>,
+    /// Only the children that are loaded in the history are known.
+    pub children: Vec<CommitLink>,
+    pub refs: Vec<String>,
+    pub base: DiffBase,
+    pub stats: DiffStats
+}
+
+impl CommitDetails {
+    pub fn new(repo: &git2::Repository, commit: &git2::Commit) -> CommitDetails {
+        let committer = commit.committer();
+        let is_same_committer = commit.author().name_bytes() == committer.name_bytes() &&
+            commit.author().email_bytes() == committer.email_bytes() &&
+            commit.author().when() == committer.when();
+
+        let committer = if is_same_committer {
+            None
+        } else {
+            let name = format!("{} <{}>", committer.name().unwrap_or("Unknown"), committer.email().unwrap_or("unknown"));
+            Some((name, commit.date().to_string()))
+        };
+
+        CommitDetails {
+            id: commit.id(),
+            author: commit.author_str(),
+            date: commit.author_date().to_string(),
+            committer,
+            message: String::from_utf8_lossy(commit.message_bytes()).trim_end().to_string(),
+            parents: commit.parents().map(|x| CommitLink::new(&x)).collect(),
+            children: vec![],
+            refs: refs_at(repo, commit.id()),
+            base: DiffBase::Parent(0),
+            stats: DiffStats::default()
+        }
+    }
+}
+
+/// The branches, remote branches and tags that point at a commit.
+fn refs_at(repo: &git2::Repository, id: git2::Oid) -> Vec<String> {
+    let refs = match repo.references() {
+        Ok(v) => v,
+        Err(_) => return vec![]
+    };
+
+    refs.filter_map(Result::ok)
+        .filter(|x| !x.is_remote() || !x.name().map(|x| x.ends_with("/HEAD")).unwrap_or(false))
+        .filter(|x| x.peel_to_commit().map(|x| x.id() == id).unwrap_or(false))
+        .filter_map(|x| x.shorthand().map(|x| x.to_string()))
+        .collect()
+}
+
+pub trait HumanCommitExt<'a> {
+    fn author_str(&self) -> String;
+    fn id_str(&self) -> String;
+    fn short_id_str(&self) -> String;
+    fn full_summary_str(&'a self) -> &'a str;
+    fn summary_str(&'a self) -> &'a str;
+    fn date(&self) -> chrono::DateTime<chrono::FixedOffset>;
+    fn author_date(&self) -> chrono::DateTime<chrono::FixedOffset>;
+}
+
+fn to_datetime(time: git2::Time) -> chrono::DateTime<chrono::FixedOffset> {
+    let naive_dt = chrono::Utc.timestamp(time.seconds(), 0).naive_utc();
+    let offset = chrono::offset::FixedOffset::east(time.offset_minutes() * 60);
+    chrono::DateTime::from_utc(naive_dt, offset)
+}
+
+impl<'a> HumanCommitExt<'a> for git2::Commit<'a> {
+    fn author_str(&self) -> String {
+        let author = self.author();
+        let author_name = author.name().unwrap_or("Unknown");
+        let author_email = author.email().unwrap_or("unknown");
+        format!("{} <{}>", &author_name, &author_email)
+    }
+
+    fn id_str(&self) -> String {
+        format!("{}", self.id())
+    }
+
+    fn short_id_str(&self) -> String {
+        self.id_str()[0..7].to_string()
+    }
+    
+    fn full_summary_str(&'a self) -> &'a str {
+        self.summary().unwrap_or("<No summary found>")
+    }
+
+    fn summary_str(&'a self) -> &'a str {
+        let full_summary = self.full_summary_str();
+        &full_summary[0..min(80, full_summary.len())]
+    }
+
+    fn date(&self) -> chrono::DateTime<chrono::FixedOffset> {
+        to_datetime(self.time())
+    }
+
+    fn author_date(&self) -> chrono::DateTime<chrono::FixedOffset> {
+        to_datetime(self.author().when())
+    }
+}
+
+/// The result of walking the history. A reload replaces everything shown; otherwise
+/// `commits` only holds what is new and belongs above the commits already loaded.
+#[derive(Debug)]
+pub struct HistoryUpdate {
+    pub is_reload: bool,
+    pub head: Option<git2::Oid>,
+    pub roots: Vec<git2::Oid>,
+    pub sentinel: Option<CommitInfo>,
+    pub commits: Vec<CommitInfo>,
+    pub pending: Vec<git2::Oid>,
+    pub branch_heads: HashMap<git2::Oid, Vec<String>>
+}
+
+/// Which refs the history is walked from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HistoryScope {
+    Branch,
+    LocalBranches,
+    AllRefs
+}
+
+impl HistoryScope {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HistoryScope::Branch => "branch",
+            HistoryScope::LocalBranches => "local",
+            HistoryScope::AllRefs => "all"
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<HistoryScope> {
+        match value {
+            "branch" => Some(HistoryScope::Branch),
+            "local" => Some(HistoryScope::LocalBranches),
+            "all" => Some(HistoryScope::AllRefs),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HistoryOptions {
+    pub scope: HistoryScope,
+    pub first_parent: bool,
+    pub hide_merges: bool
+}
+
+impl Default for HistoryOptions {
+    fn default() -> HistoryOptions {
+        HistoryOptions {
+            scope: HistoryScope::AllRefs,
+            first_parent: false,
+            hide_merges: false
+        }
+    }
+}
+
+fn find_branch<'a>(repo: &'a git2::Repository, name: &str) -> Option<git2::Branch<'a>> {
+    repo.find_branch(name, git2::BranchType::Local)
+        .or_else(|_| repo.find_branch(name, git2::BranchType::Remote))
+        .ok()
+}
+
+/// The tips the history is walked from, depending on the scope. These are remembered
+/// so a refresh can hide everything that was already walked.
+fn history_roots(repo: &git2::Repository, branch: &str, scope: HistoryScope) -> Vec<git2::Oid> {
+    let mut roots = vec![];
+
+    let selected = find_branch(repo, branch).and_then(|x| x.get().peel_to_commit().ok());
+    if let Some(commit) = selected {
+        roots.push(commit.id());
+    }
+
+    if scope == HistoryScope::Branch {
+        // Fall back to HEAD rather than show nothing at all.
+        if roots.is_empty() {
+            roots.extend(repo.head().ok().and_then(|x| x.target()));
+        }
+        return roots;
+    }
+
+    if let Some(id) = repo.head().ok().and_then(|x| x.target()) {
+        roots.push(id);
+    }
+
+    let globs: &[&str] = match scope {
+        HistoryScope::LocalBranches => &["refs/heads/*"],
+        _ => &["refs/heads/*", "refs/remotes/*", "refs/tags/*"]
+    };
+
+    // These may fail if the directories for them do not exist, so we ignore errors.
+    for glob in globs {
+        if let Ok(refs) = repo.references_glob(glob) {
+            for reference in refs.filter_map(Result::ok) {
+                if let Ok(commit) = reference.peel_to_commit() {
+                    roots.push(commit.id());
+                }
+            }
+        }
+    }
+
+    roots.sort();
+    roots.dedup();
+    roots
+}
+
+/// The commits to walk from and to hide for a revision range typed by the user, read
+/// the same way as `git rev-list` would.
+fn range_tips(repo: &git2::Repository, spec: &str) -> Result<(Vec<git2::Oid>, Vec<git2::Oid>), WorkerError> {
+    let revspec = repo.revparse(spec.trim())?;
+
+    let peel = |object: Option<&git2::Object>| -> Result<git2::Oid, WorkerError> {
+        match object {
+            Some(object) => Ok(object.peel_to_commit()?.id()),
+            None => Err(git2::Error::from_str("The range is missing a revision.").into())
+        }
+    };
+
+    if !revspec.mode().is_range() {
+        return Ok((vec![peel(revspec.from())?], vec![]));
+    }
+
+    let (from, to) = (peel(revspec.from())?, peel(revspec.to())?);
+
+    if !revspec.mode().is_merge_base() {
+        return Ok((vec![to], vec![from]));
+    }
+
+    // Either side, but nothing reachable from any of their merge bases.
+    let hide = repo.merge_bases(from, to).map(|x| x.to_vec()).unwrap_or_default();
+    Ok((vec![from, to], hide))
+}
+
+/// The commits to walk from and to hide: the given revision range if there is one,
+/// otherwise the tips for the scope.
+fn history_tips(repo: &git2::Repository, branch: &str, scope: HistoryScope, range: Option<&str>) -> Result<(Vec<git2::Oid>, Vec<git2::Oid>), WorkerError> {
+    match range {
+        Some(spec) => range_tips(repo, spec),
+        None => Ok((history_roots(repo, branch, scope), vec![]))
+    }
+}
+
+fn branch_heads(repo: &git2::Repository) -> Result<HashMap<git2::Oid, Vec<String>>, WorkerError> {
+    let mut heads: HashMap<git2::Oid, Vec<String>> = HashMap::new();
+
+    for branch in repo.branches(None)?.filter_map(Result::ok) {
+        let branch = branch.0;
+        let name = match branch.name() {
+            Ok(Some(v)) => v.to_string(),
+            _ => continue
+        };
+
+        if let Ok(commit) = branch.get().peel_to_commit() {
+            heads.entry(commit.id()).or_default().push(name);
+        }
+    }
+
+    Ok(heads)
+}
+
+/// Only collects the ids, which is cheap compared to looking up and formatting
+/// every commit. The commits themselves are materialised a page at a time.
+fn walk_ids(repo: &git2::Repository, push: &[git2::Oid], hide: &[git2::Oid], options: HistoryOptions, cancel: &CancelToken) -> Result<Vec<git2::Oid>, WorkerError> {
+    let mut revwalk = repo.revwalk()?;
+    let mut sort = git2::Sort::TIME;
+    sort.insert(git2::Sort::TOPOLOGICAL);
+    revwalk.set_sorting(sort);
+
+    if options.first_parent {
+        revwalk.simplify_first_parent();
+    }
+
+    for id in push {
+        revwalk.push(*id).unwrap_or(());
+    }
+
+    for id in hide {
+        revwalk.hide(*id).unwrap_or(());
+    }
+
+    let mut ids = vec![];
+
+    for (n, rev) in revwalk.enumerate() {
+        if n % 1000 == 0 {
+            cancel.check()?;
+        }
+
+        let id = match rev {
+            Ok(v) => v,
+            Err(_) => continue
+        };
+
+        if options.hide_merges && repo.find_commit(id).map(|x| x.parent_count() > 1).unwrap_or(false) {
+            continue;
+        }
+
+        ids.push(id);
+    }
+
+    Ok(ids)
+}
+
+/// The parents the graph should connect a commit to, following hidden merges through
+/// to what they were merged onto. Parents that a range hides are left out by the
+/// history view, which is what knows the whole walk.
+fn graph_parents(repo: &git2::Repository, commit: &git2::Commit, options: HistoryOptions) -> Vec<git2::Oid> {
+    if options.first_parent {
+        return commit.parent_ids().take(1).collect();
+    }
+
+    if !options.hide_merges {
+        return commit.parent_ids().collect();
+    }
+
+    // A hidden merge is skipped over by following its first parent.
+    let mut parents: Vec<git2::Oid> = commit.parent_ids().filter_map(|mut id| {
+        loop {
+            let parent = repo.find_commit(id).ok()?;
+            if parent.parent_count() <= 1 {
+                return Some(id);
+            }
+            id = parent.parent_id(0).ok()?;
+        }
+    }).collect();
+
+    parents.dedup();
+    parents
+}
+
+fn commit_info(repo: &git2::Repository, id: git2::Oid, heads: &HashMap<git2::Oid, Vec<String>>, options: HistoryOptions) -> Option<CommitInfo> {
+    let commit = repo.find_commit(id).ok()?;
+
+    Some(CommitInfo {
+        id: commit.id(),
+        summary: commit.summary_str().to_string(),
+        short_id: commit.short_id_str().to_string(),
+        author: commit.author_str().to_string(),
+        commit_date: commit.date().to_string(),
+        branch_heads: heads.get(&id).cloned().unwrap_or_default(),
+        parents: graph_parents(repo, &commit, options)
+    })
+}
+
+fn has_uncommitted_changes(repo: &git2::Repository) -> Result<bool, WorkerError> {
+    let statuses = repo.statuses(None)?;
+    Ok(statuses.iter().filter(|x| !x.status().is_ignored()).count() > 0)
+}
+
+/// The uncommitted changes are only shown when HEAD is part of the history.
+fn uncommitted_sentinel(repo: &git2::Repository, roots: &[git2::Oid]) -> Result<Option<CommitInfo>, WorkerError> {
+    let head = repo.head().ok().and_then(|x| x.target());
+
+    if head.map(|x| !roots.contains(&x)).unwrap_or(false) || !has_uncommitted_changes(repo)? {
+        return Ok(None);
+    }
+
+    Ok(Some(CommitInfo::uncommitted_sentinel(head)))
+}
+
+/// As long as every previously walked tip is still reachable, only the new commits are
+/// walked; anything else (a rebase, a reset, a deleted branch) needs a full reload.
+///
+/// A revision range is always reloaded, as what it hides may have moved as well.
+pub fn update(repo: &git2::Repository, branch: &str, options: HistoryOptions, range: Option<&str>, old_roots: &[git2::Oid], cancel: &CancelToken) -> Result<HistoryUpdate, WorkerError> {
+    let (roots, hide) = history_tips(repo, branch, options.scope, range)?;
+    let heads = branch_heads(repo)?;
+
+    let is_fast_forward = hide.is_empty() && !old_roots.is_empty() && old_roots.iter().all(|old| {
+        roots.iter().any(|new| new == old || repo.graph_descendant_of(*new, *old).unwrap_or(false))
+    });
+
+    let (ids, pending) = if is_fast_forward {
+        (walk_ids(repo, &roots, old_roots, options, cancel)?, vec![])
+    } else {
+        let mut ids = walk_ids(repo, &roots, &hide, options, cancel)?;
+        let rest = ids.split_off(min(PAGE_SIZE, ids.len()));
+        (ids, rest)
+    };
+
+    let mut commits = vec![];
+    for id in ids {
+        cancel.check()?;
+        commits.extend(commit_info(repo, id, &heads, options));
+    }
+
+    Ok(HistoryUpdate {
+        is_reload: !is_fast_forward,
+        head: repo.head().ok().and_then(|x| x.target()),
+        sentinel: uncommitted_sentinel(repo, &roots)?,
+        roots,
+        commits,
+        pending,
+        branch_heads: heads
+    })
+}
+
+pub fn load_commits(repo: &git2::Repository, ids: &[git2::Oid], options: HistoryOptions, cancel: &CancelToken) -> Result<Vec<CommitInfo>, WorkerError> {
+    let heads = branch_heads(repo)?;
+    let mut commits = vec![];
+
+    for id in ids {
+        cancel.check()?;
+        commits.extend(commit_info(repo, *id, &heads, options));
+    }
+
+    Ok(commits)
+}
+
+/// What the text of a history search is matched against.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SearchField {
+    Message,
+    Person,
+    Sha,
+    Path,
+    Content
+}
+
+impl SearchField {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SearchField::Message => "message",
+            SearchField::Person => "person",
+            SearchField::Sha => "sha",
+            SearchField::Path => "path",
+            SearchField::Content => "content"
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<SearchField> {
+        match value {
+            "message" => Some(SearchField::Message),
+            "person" => Some(SearchField::Person),
+            "sha" => Some(SearchField::Sha),
+            "path" => Some(SearchField::Path),
+            "content" => Some(SearchField::Content),
+            _ => None
+        }
+    }
+}
+
+/// Narrows the history down to matching commits. `since` and `until` are in seconds
+/// since the epoch and are inclusive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryFilter {
+    pub field: SearchField,
+    pub text: String,
+    pub since: Option<i64>,
+    pub until: Option<i64>
+}
+
+impl HistoryFilter {
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.since.is_none() && self.until.is_none()
+    }
+}
+
+/// How many matches are collected before they are sent on to the view.
+const SEARCH_BATCH_SIZE: usize = 50;
+
+fn first_parent_tree<'a>(commit: &git2::Commit<'a>) -> Result<Option<git2::Tree<'a>>, git2::Error> {
+    match commit.parents().next() {
+        Some(parent) => parent.tree().map(Some),
+        None => Ok(None)
+    }
+}
+
+fn count_matches(haystack: &[u8], needle: &[u8]) -> usize {
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return 0;
+    }
+
+    haystack.windows(needle.len()).filter(|x| *x == needle).count()
+}
+
+/// Like `git log -S`: a commit matches when it changes how often the text occurs in
+/// any one file.
+fn changes_occurrences(repo: &git2::Repository, commit: &git2::Commit, text: &str) -> Result<bool, git2::Error> {
+    let parent = first_parent_tree(commit)?;
+    let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), None)?;
+
+    let count = |id: git2::Oid| -> usize {
+        if id.is_zero() {
+            return 0;
+        }
+
+        match repo.find_blob(id) {
+            Ok(ref blob) if !blob.is_binary() => count_matches(blob.content(), text.as_bytes()),
+            _ => 0
+        }
+    };
+
+    Ok(diff.deltas().any(|delta| count(delta.old_file().id()) != count(delta.new_file().id())))
+}
+
+fn touches_path(repo: &git2::Repository, commit: &git2::Commit, path: &str) -> Result<bool, git2::Error> {
+    let parent = first_parent_tree(commit)?;
+    let mut opts = git2::DiffOptions::new();
+    opts.pathspec(path);
+
+    let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), Some(&mut opts))?;
+    Ok(diff.deltas().len() > 0)
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(needle)
+}
+
+fn is_match(repo: &git2::Repository, commit: &git2::Commit, filter: &HistoryFilter) -> Result<bool, git2::Error> {
+    let time = commit.time().seconds();
+
+    if filter.since.map(|x| time < x).unwrap_or(false) || filter.until.map(|x| time > x).unwrap_or(false) {
+        return Ok(false);
+    }
+
+    if filter.text.is_empty() {
+        return Ok(true);
+    }
+
+    let text = filter.text.to_lowercase();
+
+    let person_matches = |sig: git2::Signature| {
+        contains_ignore_case(sig.name().unwrap_or(""), &text) ||
+            contains_ignore_case(sig.email().unwrap_or(""), &text)
+    };
+
+    match filter.field {
+        SearchField::Message => Ok(contains_ignore_case(commit.message().unwrap_or(""), &text)),
+        SearchField::Person => Ok(person_matches(commit.author()) || person_matches(commit.committer())),
+        SearchField::Sha => Ok(commit.id_str().starts_with(&text)),
+        SearchField::Path => touches_path(repo, commit, &filter.text),
+        SearchField::Content => changes_occurrences(repo, commit, &filter.text)
+    }
+}
+
+/// Walks the same history as `update`, but only keeps the commits matching `filter`.
+/// Matches are handed to `emit` in batches as they are found; whatever is left over
+/// when the walk finishes is returned.
+pub fn search(repo: &git2::Repository, branch: &str, options: HistoryOptions, range: Option<&str>, filter: &HistoryFilter, cancel: &CancelToken, emit: &dyn Fn(Vec<CommitInfo>)) -> Result<Vec<CommitInfo>, WorkerError> {
+    let (roots, hide) = history_tips(repo, branch, options.scope, range)?;
+    let heads = branch_heads(repo)?;
+    let ids = walk_ids(repo, &roots, &hide, options, cancel)?;
+
+    let mut matches = vec![];
+
+    for id in ids {
+        cancel.check()?;
+
+        let commit = match repo.find_commit(id) {
+            Ok(v) => v,
+            Err(_) => continue
+        };
+
+        // A commit that cannot be searched, such as one whose tree is missing from a
+        // shallow clone, is left out rather than ending the whole search.
+        match is_match(repo, &commit, filter) {
+            Ok(true) => {}
+            _ => continue
+        }
+
+        matches.extend(commit_info(repo, id, &heads, options));
+
+        if matches.len() >= SEARCH_BATCH_SIZE {
+            emit(matches.split_off(0));
+        }
+    }
+
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::git::tests::TestRepo;
+    use super::*;
+
+    fn commit(repo: &git2::Repository, parents: &[git2::Oid], message: &str, time: i64, content: &[u8]) -> git2::Oid {
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert("file.txt", repo.blob(content).unwrap(), 0o100644).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+
+        let author = git2::Signature::new("Ann Author", "ann@example.com", &git2::Time::new(time, 0)).unwrap();
+        let committer = git2::Signature::new("Cy Committer", "cy@example.com", &git2::Time::new(time, 0)).unwrap();
+        let parents: Vec<git2::Commit> = parents.iter().map(|x| repo.find_commit(*x).unwrap()).collect();
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+
+        repo.commit(None, &author, &committer, message, &tree, &parents).unwrap()
+    }
+
+    fn filter(field: SearchField, text: &str) -> HistoryFilter {
+        HistoryFilter { field, text: text.to_string(), since: None, until: None }
+    }
+
+    #[test]
+    fn count_matches_counts_overlapping_occurrences() {
+        assert_eq!(count_matches(b"aaa", b"aa"), 2);
+        assert_eq!(count_matches(b"abc", b"x"), 0);
+        assert_eq!(count_matches(b"a", b"abc"), 0);
+        assert_eq!(count_matches(b"abc", b""), 0);
+    }
+
+    #[test]
+    fn commits_match_by_field() {
+        let repo = TestRepo::bare("history-match");
+        let first = commit(&repo, &[], "Add the Parser", 1000, b"one\n");
+        let second = commit(&repo, &[first], "Tidy up", 2000, b"one\nneedle\n");
+        let third = commit(&repo, &[second], "Reword", 3000, b"two\nneedle\n");
+
+        let matches = |id: git2::Oid, filter: &HistoryFilter| is_match(&repo, &repo.find_commit(id).unwrap(), filter).unwrap();
+
+        assert!(matches(first, &filter(SearchField::Message, "parser")));
+        assert!(!matches(second, &filter(SearchField::Message, "parser")));
+        assert!(matches(first, &filter(SearchField::Person, "ANN")));
+        assert!(matches(first, &filter(SearchField::Person, "cy@example")));
+        assert!(!matches(first, &filter(SearchField::Person, "bob")));
+        assert!(matches(first, &filter(SearchField::Sha, &first.to_string()[..7])));
+        assert!(!matches(second, &filter(SearchField::Sha, &first.to_string()[..7])));
+
+        assert!(!matches(first, &filter(SearchField::Content, "needle")));
+        assert!(matches(second, &filter(SearchField::Content, "needle")));
+        assert!(!matches(third, &filter(SearchField::Content, "needle")));
+
+        let dates = HistoryFilter { since: Some(2000), until: Some(2000), ..filter(SearchField::Message, "") };
+        assert!(!matches(first, &dates));
+        assert!(matches(second, &dates));
+        assert!(!matches(third, &dates));
+    }
+
+    #[test]
+    fn ranges_are_read_like_rev_list() {
+        let repo = TestRepo::bare("history-range");
+        let a = commit(&repo, &[], "a", 1000, b"a\n");
+        let b = commit(&repo, &[a], "b", 2000, b"b\n");
+        let c = commit(&repo, &[b], "c", 3000, b"c\n");
+        let d = commit(&repo, &[b], "d", 4000, b"d\n");
+        repo.reference("refs/heads/main", c, true, "").unwrap();
+        repo.reference("refs/heads/topic", d, true, "").unwrap();
+
+        let tips = |spec: &str| range_tips(&repo, spec).ok();
+
+        assert_eq!(tips("main"), Some((vec![c], vec![])));
+        assert_eq!(tips(" topic..main "), Some((vec![c], vec![d])));
+        assert_eq!(tips(&format!("{}..topic", a)), Some((vec![d], vec![a])));
+        assert_eq!(tips("main...topic"), Some((vec![c, d], vec![b])));
+        assert_eq!(tips("missing..main"), None);
+    }
+
+    #[test]
+    fn symmetric_ranges_hide_every_merge_base() {
+        let repo = TestRepo::bare("history-merge-bases");
+        let a = commit(&repo, &[], "a", 1000, b"a\n");
+        let b = commit(&repo, &[a], "b", 2000, b"b\n");
+        let c = commit(&repo, &[a], "c", 3000, b"c\n");
+        // Criss-cross merges, which have both b and c as their merge bases.
+        let left = commit(&repo, &[b, c], "left", 4000, b"left\n");
+        let right = commit(&repo, &[c, b], "right", 5000, b"right\n");
+
+        let (push, mut hide) = range_tips(&repo, &format!("{}...{}", left, right)).unwrap();
+        hide.sort();
+        let mut bases = vec![b, c];
+        bases.sort();
+
+        assert_eq!(push, vec![left, right]);
+        assert_eq!(hide, bases);
+    }
+}
@@ -0,0 +1,73 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+pub mod apply;
+pub mod attributes;
+pub mod diff;
+pub mod history;
+pub mod patch;
+pub mod worker;
+
+#[cfg(test)]
+pub mod tests {
+    use std::fs;
+    use std::ops::Deref;
+    use std::path::PathBuf;
+    use std::process;
+
+    use git2;
+
+    /// A repository in a directory of its own, which is removed again when dropped.
+    pub struct TestRepo {
+        pub dir: PathBuf,
+        repo: git2::Repository
+    }
+
+    impl TestRepo {
+        /// A repository with a working tree. `name` keeps the directories of tests
+        /// running at the same time apart.
+        pub fn new(name: &str) -> TestRepo {
+            let dir = TestRepo::clean_dir(name);
+            let repo = git2::Repository::init(&dir).unwrap();
+            TestRepo { dir, repo }
+        }
+
+        pub fn bare(name: &str) -> TestRepo {
+            let dir = TestRepo::clean_dir(name);
+            let repo = git2::Repository::init_bare(&dir).unwrap();
+            TestRepo { dir, repo }
+        }
+
+        fn clean_dir(name: &str) -> PathBuf {
+            let dir = ::std::env::temp_dir().join(format!("sourcepan-{}-{}", name, process::id()));
+            fs::remove_dir_all(&dir).unwrap_or(());
+            dir
+        }
+    }
+
+    impl Deref for TestRepo {
+        type Target = git2::Repository;
+
+        fn deref(&self) -> &git2::Repository {
+            &self.repo
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).unwrap_or(());
+        }
+    }
+}
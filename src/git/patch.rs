@@ -0,0 +1,389 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{FixedOffset, TimeZone};
+use git2;
+
+use super::diff::{DiffHunk, DiffLine, FileDiff, MODE_FILE};
+use super::worker::WorkerError;
+
+const NO_NEWLINE: &str = "\\ No newline at end of file\n";
+
+/// Patch file names are cut short after this many characters of the subject, as
+/// `git format-patch` does.
+const MAX_NAME_LENGTH: usize = 52;
+
+/// Narrows a hunk down to the lines chosen by their index in it. Removals that were
+/// not chosen stay as context and additions are dropped, so that the hunk still
+/// applies. Returns `None` if no change is left.
+pub fn select_lines(hunk: &DiffHunk, chosen: &HashSet<usize>) -> Option<DiffHunk> {
+    let mut lines: Vec<DiffLine> = vec![];
+    // Whether the line before was kept, which decides whether its newline marker is.
+    let mut is_kept = false;
+
+    for (i, line) in hunk.lines.iter().enumerate() {
+        let line = match line.origin {
+            '+' if !chosen.contains(&i) => {
+                is_kept = false;
+                continue;
+            }
+            '-' if !chosen.contains(&i) => DiffLine { origin: ' ', ..line.clone() },
+            '=' | '<' | '>' if !is_kept => continue,
+            _ => line.clone()
+        };
+
+        is_kept = true;
+        lines.push(line);
+    }
+
+    if !lines.iter().any(|x| x.origin == '+' || x.origin == '-') {
+        return None;
+    }
+
+    let count = |origins: &[char]| lines.iter().filter(|x| origins.contains(&x.origin)).count() as u32;
+    let (old_lines, new_lines) = (count(&[' ', '-']), count(&[' ', '+']));
+
+    Some(DiffHunk {
+        header: hunk.header.clone(),
+        old_start: hunk.old_start,
+        old_lines,
+        new_start: hunk.new_start,
+        new_lines,
+        lines
+    })
+}
+
+fn range(start: u32, lines: u32) -> String {
+    if lines == 1 { start.to_string() } else { format!("{},{}", start, lines) }
+}
+
+fn push_line(text: &mut String, line: &DiffLine) {
+    if let '=' | '<' | '>' = line.origin {
+        text.push_str(NO_NEWLINE);
+        return;
+    }
+
+    text.push(line.origin);
+    text.push_str(&String::from_utf8_lossy(&line.content));
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+/// Writes a file's diff as a patch `git apply` takes, with only the given hunks. The
+/// new line numbers are worked out again, as hunks may have been left out or narrowed
+/// down with `select_lines`.
+pub fn file_patch(file: &FileDiff, hunks: &[DiffHunk]) -> String {
+    let old_path = file.old_path.as_ref().or(file.new_path.as_ref()).cloned().unwrap_or_default();
+    let new_path = file.new_path.as_ref().or(file.old_path.as_ref()).cloned().unwrap_or_default();
+    let header = &file.header;
+    let is_added = matches!(file.status, git2::Delta::Added | git2::Delta::Untracked);
+    // A deleted file that keeps some of its lines is only changed.
+    let is_deleted = file.status == git2::Delta::Deleted && hunks.iter().all(|x| x.new_lines == 0);
+
+    let mut text = format!("diff --git a/{} b/{}\n", old_path, new_path);
+
+    if is_added {
+        text.push_str(&format!("new file mode {:o}\n", header.new_mode.unwrap_or(MODE_FILE)));
+    } else if is_deleted {
+        text.push_str(&format!("deleted file mode {:o}\n", header.old_mode.unwrap_or(MODE_FILE)));
+    } else if let (Some(old), Some(new)) = (header.old_mode, header.new_mode) {
+        if old != new {
+            text.push_str(&format!("old mode {:o}\nnew mode {:o}\n", old, new));
+        }
+    }
+
+    let verb = match file.status {
+        git2::Delta::Renamed => Some("rename"),
+        git2::Delta::Copied => Some("copy"),
+        _ => None
+    };
+
+    if let Some(verb) = verb {
+        if let Some(similarity) = header.similarity {
+            text.push_str(&format!("similarity index {}%\n", similarity));
+        }
+        text.push_str(&format!("{} from {}\n{} to {}\n", verb, old_path, verb, new_path));
+    }
+
+    let old_name = if is_added { "/dev/null".to_string() } else { format!("a/{}", old_path) };
+    let new_name = if is_deleted { "/dev/null".to_string() } else { format!("b/{}", new_path) };
+
+    if file.binary.is_some() {
+        text.push_str(&format!("Binary files {} and {} differ\n", old_name, new_name));
+        return text;
+    }
+
+    if hunks.is_empty() {
+        return text;
+    }
+
+    text.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+
+    // How far the new side has moved from the old one by the hunks written so far.
+    let mut offset: i64 = 0;
+
+    for hunk in hunks {
+        let new_first = hunk.old_first() as i64 + offset;
+        let new_start = if hunk.new_lines == 0 { new_first - 1 } else { new_first };
+        let context = hunk.header.splitn(3, "@@").nth(2).unwrap_or("\n");
+
+        text.push_str(&format!("@@ -{} +{} @@{}", range(hunk.old_start, hunk.old_lines), range(new_start.max(0) as u32, hunk.new_lines), context));
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+
+        for line in hunk.lines.iter() {
+            push_line(&mut text, line);
+        }
+
+        offset += hunk.new_lines as i64 - hunk.old_lines as i64;
+    }
+
+    text
+}
+
+/// Puts non-ASCII header text in an RFC 2047 encoded word, as mail headers allow
+/// nothing else.
+fn encode_header(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_string();
+    }
+
+    let mut encoded = "=?UTF-8?q?".to_string();
+    for byte in text.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => encoded.push(byte as char),
+            b' ' => encoded.push('_'),
+            _ => encoded.push_str(&format!("={:02X}", byte))
+        }
+    }
+    encoded.push_str("?=");
+    encoded
+}
+
+/// Formats a commit as one message of an mbox, like `git format-patch`: the author
+/// and date as headers, the message as subject and body, then the diff against the
+/// first parent with its stats. `number` counts from 1 within a series of `total`.
+pub fn format_patch(repo: &git2::Repository, commit: &git2::Commit, number: usize, total: usize) -> Result<String, git2::Error> {
+    let parent_tree = match commit.parent_count() {
+        0 => None,
+        _ => Some(commit.parent(0)?.tree()?)
+    };
+
+    let mut diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+    diff.find_similar(None)?;
+
+    let stats = diff.stats()?.to_buf(git2::DiffStatsFormat::FULL | git2::DiffStatsFormat::INCLUDE_SUMMARY, 72)?;
+
+    let author = commit.author();
+    let when = author.when();
+    let date = FixedOffset::east(when.offset_minutes() * 60).timestamp(when.seconds(), 0);
+
+    // The subject is the first paragraph, the body whatever follows it.
+    let message = String::from_utf8_lossy(commit.message_bytes()).to_string();
+    let mut paragraphs = message.trim().splitn(2, "\n\n");
+    let subject = paragraphs.next().unwrap_or("").split_whitespace().collect::<Vec<_>>().join(" ");
+    let body = paragraphs.next().unwrap_or("").trim_end();
+
+    let prefix = if total > 1 { format!("[PATCH {}/{}]", number, total) } else { "[PATCH]".to_string() };
+
+    let mut text = format!("From {} Mon Sep 17 00:00:00 2001\n", commit.id());
+    text.push_str(&format!("From: {} <{}>\n",
+        encode_header(&String::from_utf8_lossy(author.name_bytes())),
+        String::from_utf8_lossy(author.email_bytes())));
+    text.push_str(&format!("Date: {}\n", date.format("%a, %-d %b %Y %H:%M:%S %z")));
+    text.push_str(&format!("Subject: {} {}\n\n", prefix, encode_header(&subject)));
+
+    if !body.is_empty() {
+        text.push_str(body);
+        text.push('\n');
+    }
+
+    text.push_str("---\n");
+    text.push_str(stats.as_str().unwrap_or(""));
+    text.push('\n');
+
+    let mut patch = vec![];
+    diff.print(git2::DiffFormat::Patch, |_, _, line| {
+        if let '+' | '-' | ' ' = line.origin() {
+            patch.push(line.origin() as u8);
+        }
+        patch.extend_from_slice(line.content());
+        true
+    })?;
+
+    text.push_str(&String::from_utf8_lossy(&patch));
+    text.push_str(&format!("-- \nSourcepan {}\n\n", env!("CARGO_PKG_VERSION")));
+
+    Ok(text)
+}
+
+/// The file name `git format-patch` would give a patch, from its number and subject.
+fn patch_name(number: usize, commit: &git2::Commit) -> String {
+    let summary = commit.summary().unwrap_or("");
+    let mut name = String::new();
+
+    for c in summary.chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            name.push(c);
+        } else if !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+
+    let name: String = name.trim_matches(['-', '.']).chars().take(MAX_NAME_LENGTH).collect();
+    format!("{:04}-{}.patch", number, name.trim_end_matches(['-', '.']))
+}
+
+/// Saves commits as a numbered series of patches in `dir`, oldest first as given,
+/// returning the files written.
+pub fn save_patches(repo: &git2::Repository, ids: &[git2::Oid], dir: &Path) -> Result<Vec<PathBuf>, WorkerError> {
+    let mut paths = vec![];
+
+    for (i, id) in ids.iter().enumerate() {
+        let commit = repo.find_commit(*id)?;
+        let path = dir.join(patch_name(i + 1, &commit));
+        fs::write(&path, format_patch(repo, &commit, i + 1, ids.len())?)?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::git::diff::FileHeader;
+    use crate::git::tests::TestRepo;
+    use super::*;
+
+    fn hunk(old: (u32, u32), new: (u32, u32), lines: &[(char, &str)]) -> DiffHunk {
+        DiffHunk {
+            header: format!("@@ -{},{} +{},{} @@ fn main()\n", old.0, old.1, new.0, new.1),
+            old_start: old.0,
+            old_lines: old.1,
+            new_start: new.0,
+            new_lines: new.1,
+            lines: lines.iter()
+                .map(|&(origin, content)| DiffLine { origin, old_lineno: None, new_lineno: None, content: content.as_bytes().to_vec() })
+                .collect()
+        }
+    }
+
+    fn file(hunks: Vec<DiffHunk>) -> FileDiff {
+        FileDiff {
+            old_path: Some("src/main.rs".to_string()),
+            new_path: Some("src/main.rs".to_string()),
+            old_id: git2::Oid::zero(),
+            new_id: git2::Oid::zero(),
+            status: git2::Delta::Modified,
+            hunks,
+            binary: None,
+            header: FileHeader::default(),
+            size: 0,
+            is_generated: false,
+            changed_lines: None,
+            is_loaded: true
+        }
+    }
+
+    fn origins(hunk: &DiffHunk) -> String {
+        hunk.lines.iter().map(|x| x.origin).collect()
+    }
+
+    #[test]
+    fn unchosen_lines_become_context_or_are_dropped() {
+        let full = hunk((1, 4), (1, 4), &[(' ', "a\n"), ('-', "b\n"), ('-', "c\n"), ('+', "d\n"), ('+', "e\n"), (' ', "f\n")]);
+
+        let chosen: HashSet<usize> = [1, 3].iter().cloned().collect();
+        let narrowed = select_lines(&full, &chosen).unwrap();
+        assert_eq!(origins(&narrowed), " - + ");
+        assert_eq!(String::from_utf8_lossy(&narrowed.lines[2].content), "c\n");
+        assert_eq!((narrowed.old_lines, narrowed.new_lines), (4, 4));
+
+        assert!(select_lines(&full, &HashSet::new()).is_none());
+    }
+
+    #[test]
+    fn newline_markers_follow_their_line() {
+        let full = hunk((1, 1), (1, 1), &[('-', "a"), ('>', "\n\\ No newline at end of file\n"), ('+', "b"), ('<', "\n\\ No newline at end of file\n")]);
+
+        let chosen: HashSet<usize> = [0].iter().cloned().collect();
+        assert_eq!(origins(&select_lines(&full, &chosen).unwrap()), "->");
+    }
+
+    #[test]
+    fn file_patches_renumber_the_new_side() {
+        let first = hunk((1, 2), (1, 3), &[(' ', "a\n"), ('+', "b\n"), (' ', "c\n")]);
+        let second = hunk((10, 2), (11, 1), &[(' ', "x\n"), ('-', "y\n")]);
+        let file = file(vec![first, second]);
+
+        assert_eq!(file_patch(&file, &file.hunks[1..]), "\
+diff --git a/src/main.rs b/src/main.rs
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -10,2 +10 @@ fn main()
+ x
+-y
+");
+
+        assert_eq!(file_patch(&file, &file.hunks), "\
+diff --git a/src/main.rs b/src/main.rs
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,2 +1,3 @@ fn main()
+ a
++b
+ c
+@@ -10,2 +11 @@ fn main()
+ x
+-y
+");
+    }
+
+    #[test]
+    fn non_ascii_headers_are_encoded() {
+        assert_eq!(encode_header("Plain text"), "Plain text");
+        assert_eq!(encode_header("Jörg M"), "=?UTF-8?q?J=C3=B6rg_M?=");
+    }
+
+    #[test]
+    fn commits_are_formatted_like_format_patch() {
+        let repo = TestRepo::bare("patch-format");
+        let tree = {
+            let mut tree = repo.treebuilder(None).unwrap();
+            tree.insert("a.txt", repo.blob(b"a\n").unwrap(), 0o100644).unwrap();
+            repo.find_tree(tree.write().unwrap()).unwrap()
+        };
+        let author = git2::Signature::new("Jörg", "jorg@example.com", &git2::Time::new(1543914000, 60)).unwrap();
+        let id = repo.commit(None, &author, &author, "Fix: the pärser.\n\nThe body.\n", &tree, &[]).unwrap();
+        let commit = repo.find_commit(id).unwrap();
+
+        assert_eq!(patch_name(3, &commit), "0003-Fix-the-p-rser.patch");
+
+        let text = format_patch(&repo, &commit, 1, 2).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], format!("From {} Mon Sep 17 00:00:00 2001", id));
+        assert_eq!(lines[1], "From: =?UTF-8?q?J=C3=B6rg?= <jorg@example.com>");
+        assert_eq!(lines[2], "Date: Tue, 4 Dec 2018 10:00:00 +0100");
+        assert_eq!(lines[3], "Subject: [PATCH 1/2] =?UTF-8?q?Fix=3A_the_p=C3=A4rser=2E?=");
+        assert_eq!(&lines[4..7], &["", "The body.", "---"]);
+        assert!(text.contains("\n--- /dev/null\n+++ b/a.txt\n@@ -0,0 +1 @@\n+a\n-- \n"));
+    }
+}
@@ -0,0 +1,399 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use git2;
+
+use super::apply::{self, ApplyReport, ApplyTarget, PatchMessage};
+use super::diff::{self, Comparison, DiffBase, DiffSettings, DiffSide, DiffSource, FileDiff, PatchRequest};
+use super::history::{self, CommitDetails, CommitInfo, HistoryFilter, HistoryOptions, HistoryUpdate};
+use super::patch;
+
+pub enum Request {
+    Open(PathBuf),
+    /// Changes how every following diff is generated.
+    DiffSettings(DiffSettings),
+    /// Walks the history from the tips `options` asks for, or over `range` if one is
+    /// given. If `roots` holds the tips of a previous walk, only what is new since then
+    /// is returned when possible.
+    History { branch: String, options: HistoryOptions, range: Option<String>, roots: Vec<git2::Oid> },
+    LoadCommits(Vec<git2::Oid>, HistoryOptions),
+    /// Streams the commits matching `filter` back as several `SearchResults`.
+    Search { branch: String, options: HistoryOptions, range: Option<String>, filter: HistoryFilter },
+    CommitDiff(git2::Oid, DiffBase),
+    /// Diffs two revisions or a revision and the working tree.
+    Compare { from: DiffSide, to: DiffSide },
+    WorkdirDiff,
+    /// Loads the patches of files listed by an earlier diff of `source`.
+    FilePatches(DiffSource, Vec<PatchRequest>),
+    /// Reads the lines of the `index`th file of the diff shown, by its old id and path.
+    FileLines { index: usize, id: git2::Oid, path: Option<String> },
+    Stage(PathBuf),
+    Unstage(PathBuf),
+    /// Writes commits to `dir` as a numbered series of patches, oldest first as given.
+    SavePatches { ids: Vec<git2::Oid>, dir: PathBuf },
+    ApplyPatch { messages: Vec<PatchMessage>, target: ApplyTarget }
+}
+
+impl Request {
+    /// Writes are never skipped, even when a newer request has superseded them.
+    fn is_write(&self) -> bool {
+        matches!(*self, Request::Open(_) | Request::DiffSettings(_) | Request::Stage(_) | Request::Unstage(_) | Request::SavePatches { .. } | Request::ApplyPatch { .. })
+    }
+}
+
+pub enum Response {
+    History(HistoryUpdate),
+    Commits(Vec<CommitInfo>),
+    SearchResults { commits: Vec<CommitInfo>, is_done: bool },
+    CommitDiff(CommitDetails, Vec<FileDiff>),
+    Comparison(Comparison, Vec<FileDiff>),
+    WorkdirDiff { staged: Vec<FileDiff>, unstaged: Vec<FileDiff> },
+    /// The loaded files along with their positions in the list they were asked for from.
+    FilePatches(DiffSource, Vec<(usize, FileDiff)>),
+    FileLines { index: usize, id: git2::Oid, lines: Vec<String> },
+    IndexUpdated,
+    PatchesSaved(Vec<PathBuf>),
+    PatchApplied(ApplyReport),
+    Error(String)
+}
+
+#[derive(Debug)]
+pub enum WorkerError {
+    Cancelled,
+    NoRepository,
+    Git(git2::Error),
+    Io(io::Error)
+}
+
+impl From<git2::Error> for WorkerError {
+    fn from(error: git2::Error) -> WorkerError {
+        WorkerError::Git(error)
+    }
+}
+
+impl From<io::Error> for WorkerError {
+    fn from(error: io::Error) -> WorkerError {
+        WorkerError::Io(error)
+    }
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WorkerError::Cancelled => write!(f, "The request was cancelled."),
+            WorkerError::NoRepository => write!(f, "No repository is open."),
+            WorkerError::Git(ref e) => write!(f, "{}", e.message()),
+            WorkerError::Io(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+/// Lets long running jobs notice that the client has since asked for something else.
+pub struct CancelToken {
+    generation: usize,
+    current: Arc<AtomicUsize>
+}
+
+impl CancelToken {
+    pub fn check(&self) -> Result<(), WorkerError> {
+        if self.current.load(Ordering::SeqCst) != self.generation {
+            Err(WorkerError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+struct Reply {
+    generation: usize,
+    is_write: bool,
+    response: Response
+}
+
+struct Job {
+    request: Request,
+    cancel: CancelToken,
+    reply: Sender<Reply>
+}
+
+/// Owns a thread with its own `git2::Repository` handle. Presenters talk to it
+/// through a `Client` and poll for responses from a `gtk::timeout_add` callback.
+pub struct Worker {
+    jobs: Sender<Job>,
+    busy: Arc<AtomicUsize>
+}
+
+impl Worker {
+    pub fn new(path: PathBuf) -> Worker {
+        let (tx, rx) = channel();
+        let busy = Arc::new(AtomicUsize::new(0));
+
+        let thread_busy = Arc::clone(&busy);
+        thread::spawn(move || Worker::run(path, rx, thread_busy));
+
+        Worker { jobs: tx, busy }
+    }
+
+    pub fn client(&self) -> Client {
+        let (tx, rx) = channel();
+
+        Client {
+            jobs: self.jobs.clone(),
+            busy: Arc::clone(&self.busy),
+            generation: Arc::new(AtomicUsize::new(0)),
+            reply_tx: tx,
+            replies: rx
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.busy.load(Ordering::SeqCst) > 0
+    }
+
+    fn run(path: PathBuf, jobs: Receiver<Job>, busy: Arc<AtomicUsize>) {
+        let mut repo = git2::Repository::open(&path).ok();
+        let mut settings = DiffSettings::default();
+
+        for job in jobs {
+            match job.request {
+                Request::Open(ref path) => repo = git2::Repository::open(path).ok(),
+                Request::DiffSettings(ref new_settings) => settings = *new_settings,
+                _ => {}
+            }
+
+            if let Request::Search { .. } = job.request {
+                // Searches can walk the whole history, so they get a thread and a
+                // repository handle of their own instead of holding up what is queued.
+                let path = repo.as_ref().map(|x| x.path().to_path_buf());
+                let busy = Arc::clone(&busy);
+
+                thread::spawn(move || {
+                    let repo = path.and_then(|x| git2::Repository::open(x).ok());
+                    Worker::execute(repo.as_ref(), &settings, &job);
+                    busy.fetch_sub(1, Ordering::SeqCst);
+                });
+
+                continue;
+            }
+
+            Worker::execute(repo.as_ref(), &settings, &job);
+            busy.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Handles a job and sends back what it gave, unless it was cancelled.
+    fn execute(repo: Option<&git2::Repository>, settings: &DiffSettings, job: &Job) {
+        let generation = job.cancel.generation;
+        let is_write = job.request.is_write();
+        let reply = &job.reply;
+        let emit = |response| reply.send(Reply { generation, is_write, response }).unwrap_or(());
+
+        let result = if !is_write && job.cancel.check().is_err() {
+            Err(WorkerError::Cancelled)
+        } else {
+            match repo {
+                Some(repo) => Worker::handle(repo, settings, &job.request, &job.cancel, &emit),
+                None => Err(WorkerError::NoRepository)
+            }
+        };
+
+        let response = match result {
+            Ok(v) => Some(v),
+            Err(WorkerError::Cancelled) => None,
+            Err(err) => Some(Response::Error(format!("{}", err)))
+        };
+
+        if let Some(response) = response {
+            emit(response);
+        }
+    }
+
+    /// Returns the final response; `emit` sends any partial ones ahead of it.
+    fn handle(repo: &git2::Repository, settings: &DiffSettings, request: &Request, cancel: &CancelToken, emit: &dyn Fn(Response)) -> Result<Response, WorkerError> {
+        match *request {
+            Request::Open(_) => Ok(Response::IndexUpdated),
+            Request::DiffSettings(_) => Ok(Response::IndexUpdated),
+            Request::History { ref branch, options, ref range, ref roots } => {
+                history::update(repo, branch, options, range.as_ref().map(|x| x.as_str()), roots, cancel).map(Response::History)
+            }
+            Request::LoadCommits(ref ids, options) => {
+                history::load_commits(repo, ids, options, cancel).map(Response::Commits)
+            }
+            Request::Search { ref branch, options, ref range, ref filter } => {
+                let range = range.as_ref().map(|x| x.as_str());
+                let emit_batch = |commits| emit(Response::SearchResults { commits, is_done: false });
+                let commits = history::search(repo, branch, options, range, filter, cancel, &emit_batch)?;
+                Ok(Response::SearchResults { commits, is_done: true })
+            }
+            Request::CommitDiff(id, base) => {
+                let commit = repo.find_commit(id)?;
+                let (stats, files) = diff::commit_diff(repo, id, base, settings, cancel)?;
+                let mut details = CommitDetails::new(repo, &commit);
+                details.stats = stats;
+                details.base = base;
+                Ok(Response::CommitDiff(details, files))
+            }
+            Request::Compare { ref from, ref to } => {
+                let (comparison, files) = diff::compare(repo, from, to, settings, cancel)?;
+                Ok(Response::Comparison(comparison, files))
+            }
+            Request::WorkdirDiff => {
+                let (staged, unstaged) = diff::workdir_diff(repo, settings, cancel)?;
+                Ok(Response::WorkdirDiff { staged, unstaged })
+            }
+            Request::FilePatches(ref source, ref requests) => {
+                let files = diff::file_patches(repo, source, settings, requests, cancel)?;
+                Ok(Response::FilePatches(source.clone(), files))
+            }
+            Request::FileLines { index, id, ref path } => {
+                let lines = diff::file_lines(repo, id, path.as_ref().map(|x| x.as_str()));
+                Ok(Response::FileLines { index, id, lines })
+            }
+            Request::Stage(ref path) => {
+                let mut index = repo.index()?;
+                index.add_path(path)?;
+                index.write()?;
+                Ok(Response::IndexUpdated)
+            }
+            Request::Unstage(ref path) => {
+                let head = repo.head()?.peel(git2::ObjectType::Commit)?;
+                repo.reset_default(Some(&head), [path])?;
+                repo.index()?.write()?;
+                Ok(Response::IndexUpdated)
+            }
+            Request::SavePatches { ref ids, ref dir } => {
+                patch::save_patches(repo, ids, dir).map(Response::PatchesSaved)
+            }
+            Request::ApplyPatch { ref messages, target } => {
+                apply::apply(repo, messages, target).map(Response::PatchApplied)
+            }
+        }
+    }
+}
+
+/// A presenter's connection to the worker. Every request is stamped with the client's
+/// current generation; bumping the generation cancels whatever is still outstanding.
+pub struct Client {
+    jobs: Sender<Job>,
+    busy: Arc<AtomicUsize>,
+    generation: Arc<AtomicUsize>,
+    reply_tx: Sender<Reply>,
+    replies: Receiver<Reply>
+}
+
+impl Client {
+    pub fn send(&self, request: Request) {
+        let job = Job {
+            request,
+            cancel: CancelToken {
+                generation: self.generation.load(Ordering::SeqCst),
+                current: Arc::clone(&self.generation)
+            },
+            reply: self.reply_tx.clone()
+        };
+
+        self.busy.fetch_add(1, Ordering::SeqCst);
+        if self.jobs.send(job).is_err() {
+            self.busy.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Sends a request that supersedes everything this client sent before it.
+    pub fn send_latest(&self, request: Request) {
+        self.cancel();
+        self.send(request);
+    }
+
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns the next response that is still current. What writes report, errors
+    /// included, is always returned, since nothing newer takes its place.
+    pub fn try_recv(&self) -> Option<Response> {
+        let current = self.generation.load(Ordering::SeqCst);
+
+        while let Ok(reply) = self.replies.try_recv() {
+            if reply.generation == current || reply.is_write {
+                return Some(reply.response);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn wait(worker: &Worker) {
+        while worker.is_busy() {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn errors_are_only_returned_while_current() {
+        let worker = Worker::new(::std::env::temp_dir().join("sourcepan-worker-missing"));
+        let client = worker.client();
+
+        client.send(Request::WorkdirDiff);
+        wait(&worker);
+        assert!(matches!(client.try_recv(), Some(Response::Error(_))));
+
+        client.send(Request::WorkdirDiff);
+        wait(&worker);
+        client.cancel();
+        assert!(client.try_recv().is_none());
+    }
+
+    #[test]
+    fn superseded_requests_are_skipped() {
+        let worker = Worker::new(::std::env::temp_dir().join("sourcepan-worker-missing"));
+        let client = worker.client();
+
+        client.send(Request::WorkdirDiff);
+        client.send_latest(Request::WorkdirDiff);
+        wait(&worker);
+
+        assert!(matches!(client.try_recv(), Some(Response::Error(_))));
+        assert!(client.try_recv().is_none());
+    }
+
+    #[test]
+    fn writes_report_back_even_when_superseded() {
+        let worker = Worker::new(::std::env::temp_dir().join("sourcepan-worker-missing"));
+        let client = worker.client();
+
+        client.send(Request::Stage(PathBuf::from("a.txt")));
+        client.send_latest(Request::WorkdirDiff);
+        wait(&worker);
+
+        assert!(matches!(client.try_recv(), Some(Response::Error(_))));
+        assert!(matches!(client.try_recv(), Some(Response::Error(_))));
+        assert!(client.try_recv().is_none());
+    }
+}
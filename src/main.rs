@@ -21,12 +21,16 @@ extern crate notify;
 extern crate glib;
 extern crate gdk;
 extern crate pango;
+extern crate cairo;
+extern crate gdk_pixbuf;
+extern crate regex;
 
 use std::rc::Rc;
 
-use preferences::{AppInfo, Preferences, PreferencesMap};
+use preferences::{AppInfo, Preferences, PreferencesError, PreferencesMap};
 use gtk::prelude::*;
 
+mod git;
 mod ui;
 
 use crate::ui::init::InitViewable;
@@ -51,6 +55,19 @@ impl Config {
             Err(_) => None
         }
     }
+
+    /// Settings that are remembered separately for each repository.
+    fn repo_setting(repo_dir: &str, name: &str) -> Option<String> {
+        let map = PreferencesMap::<String>::load(&APP_INFO, "repos").ok()?;
+        map.get(&format!("{}:{}", repo_dir, name)).cloned()
+    }
+
+    fn set_repo_setting(repo_dir: &str, name: &str, value: &str) -> Result<(), PreferencesError> {
+        let mut map = PreferencesMap::<String>::load(&APP_INFO, "repos")
+            .unwrap_or_else(|_| PreferencesMap::new());
+        map.insert(format!("{}:{}", repo_dir, name), value.into());
+        map.save(&APP_INFO, "repos")
+    }
 }
 
 fn create_main_window(repo: git2::Repository) -> Result<Rc<ui::main::MainWindow>, ui::main::MainWindowError> {
@@ -74,7 +91,7 @@ fn main() {
     let css_provider = gtk::CssProvider::new();
     match css_provider.load_from_data(include_str!("ui/app.css").as_bytes()) {
         Ok(_) => {},
-        Err(e) => panic!(e)
+        Err(e) => panic!("{}", e)
     };
     gtk::StyleContext::add_provider_for_screen(
         &gdk::Screen::get_default().unwrap(),
@@ -82,7 +99,7 @@ fn main() {
         gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
 
     // Holds a strong reference to the primary window to stop some *fun* UB
-    let _window: Rc<ui::Window> = if let Some(repo_dir) = Config::repo_dir() {
+    let _window: Rc<dyn ui::Window> = if let Some(repo_dir) = Config::repo_dir() {
         match git2::Repository::open(&repo_dir) {
             Ok(repo) => match create_main_window(repo) {
                 Ok(w) => w,
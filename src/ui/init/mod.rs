@@ -48,7 +48,7 @@ impl<V: InitViewable> InitPresenter<V> {
     fn select_repo(&self, repo_dir: &Path) {
         use crate::Config;
 
-        let repo = match git2::Repository::open(&repo_dir) {
+        let repo = match git2::Repository::open(repo_dir) {
             Ok(repo) => repo,
             Err(err) => {
                 self.view().handle_error(err);
@@ -109,7 +109,7 @@ impl InitViewable for InitWindow {
 
         let view = view!(InitWindow {
             presenter: InitPresenter::new(),
-            window: window,
+            window,
             main_window: RefCell::new(None)
         });
 
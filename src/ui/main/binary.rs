@@ -0,0 +1,222 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use gtk::prelude::*;
+use gtk;
+use gdk::ContextExt;
+use gdk_pixbuf::{InterpType, Pixbuf, PixbufLoader, PixbufLoaderExt};
+
+use crate::git::diff::BinaryDiff;
+
+/// Images wider than this are scaled down to fit.
+const MAX_IMAGE_WIDTH: i32 = 480;
+
+/// The height of the previews shown within the diff.
+const THUMBNAIL_HEIGHT: i32 = 120;
+
+pub fn format_size(size: usize) -> String {
+    match size {
+        0..=1023 => format!("{} bytes", size),
+        1024..=1_048_575 => format!("{:.1} KB", size as f64 / 1024.0),
+        _ => format!("{:.1} MB", size as f64 / 1_048_576.0)
+    }
+}
+
+pub fn load_image(data: &[u8]) -> Option<Pixbuf> {
+    let loader = PixbufLoader::new();
+    let is_loaded = loader.write(data).is_ok();
+    let is_closed = loader.close().is_ok();
+
+    if is_loaded && is_closed {
+        loader.get_pixbuf()
+    } else {
+        None
+    }
+}
+
+fn scale(pixbuf: &Pixbuf, factor: f64) -> Pixbuf {
+    if factor >= 1.0 {
+        return pixbuf.clone();
+    }
+
+    let width = ((pixbuf.get_width() as f64 * factor) as i32).max(1);
+    let height = ((pixbuf.get_height() as f64 * factor) as i32).max(1);
+    pixbuf.scale_simple(width, height, InterpType::Bilinear).unwrap_or_else(|| pixbuf.clone())
+}
+
+pub fn thumbnail(pixbuf: &Pixbuf) -> Pixbuf {
+    scale(pixbuf, THUMBNAIL_HEIGHT as f64 / pixbuf.get_height().max(1) as f64)
+}
+
+pub fn summary(binary: &BinaryDiff) -> String {
+    match (binary.old_size, binary.new_size) {
+        (Some(old), Some(new)) => format!("Binary file changed: {} → {}", format_size(old), format_size(new)),
+        (None, Some(new)) => format!("Binary file added: {}", format_size(new)),
+        (Some(old), None) => format!("Binary file deleted: {}", format_size(old)),
+        (None, None) => "Binary file".to_string()
+    }
+}
+
+/// Shows the sizes of a binary file and, for images, both versions of it.
+pub struct BinaryDiffView {
+    root: gtk::Box
+}
+
+impl BinaryDiffView {
+    pub fn new(binary: &BinaryDiff) -> BinaryDiffView {
+        let root = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        root.set_border_width(8);
+
+        let label = gtk::Label::new(summary(binary).as_str());
+        label.set_xalign(0.0);
+        root.add(&label);
+
+        let old = binary.old_image.as_ref().and_then(|x| load_image(x));
+        let new = binary.new_image.as_ref().and_then(|x| load_image(x));
+
+        let widest = old.iter().chain(new.iter()).map(|x| x.get_width()).max().unwrap_or(1);
+        let factor = MAX_IMAGE_WIDTH as f64 / widest as f64;
+        let old = old.map(|x| scale(&x, factor));
+        let new = new.map(|x| scale(&x, factor));
+
+        match (old, new) {
+            (Some(old), Some(new)) => {
+                let stack = gtk::Stack::new();
+                stack.add_titled(&BinaryDiffView::side_by_side(&old, &new), "side-by-side", "Side by side");
+                stack.add_titled(&BinaryDiffView::overlay(&old, &new, false), "onion-skin", "Onion skin");
+                stack.add_titled(&BinaryDiffView::overlay(&old, &new, true), "swipe", "Swipe");
+
+                let switcher = gtk::StackSwitcher::new();
+                switcher.set_stack(&stack);
+                switcher.set_halign(gtk::Align::Start);
+
+                root.add(&switcher);
+                root.add(&stack);
+            }
+            (Some(image), None) | (None, Some(image)) => {
+                let image = gtk::Image::new_from_pixbuf(&image);
+                image.set_halign(gtk::Align::Start);
+                root.add(&image);
+            }
+            (None, None) => {}
+        }
+
+        BinaryDiffView { root }
+    }
+
+    fn side_by_side(old: &Pixbuf, new: &Pixbuf) -> gtk::Box {
+        let container = gtk::Box::new(gtk::Orientation::Horizontal, 16);
+
+        for (title, pixbuf) in &[("Old", old), ("New", new)] {
+            let side = gtk::Box::new(gtk::Orientation::Vertical, 4);
+            let label = gtk::Label::new(*title);
+            label.set_xalign(0.0);
+            side.add(&label);
+            side.add(&gtk::Image::new_from_pixbuf(*pixbuf));
+            container.add(&side);
+        }
+
+        container
+    }
+
+    /// Draws the new image over the old one, either blended or revealed up to a
+    /// divider, as set with a slider.
+    fn overlay(old: &Pixbuf, new: &Pixbuf, is_swipe: bool) -> gtk::Box {
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 4);
+        let position = Rc::new(Cell::new(0.5));
+
+        let width = old.get_width().max(new.get_width());
+        let height = old.get_height().max(new.get_height());
+
+        let area = gtk::DrawingArea::new();
+        area.set_size_request(width, height);
+        area.set_halign(gtk::Align::Start);
+
+        area.connect_draw(clone!(old, new, position => move |_, cr| {
+            cr.set_source_pixbuf(&old, 0.0, 0.0);
+            cr.paint();
+
+            cr.set_source_pixbuf(&new, 0.0, 0.0);
+
+            if is_swipe {
+                let x = width as f64 * position.get();
+                cr.rectangle(x, 0.0, width as f64 - x, height as f64);
+                cr.fill();
+
+                cr.set_source_rgba(0.2, 0.2, 0.2, 1.0);
+                cr.set_line_width(1.0);
+                cr.move_to(x.floor() + 0.5, 0.0);
+                cr.line_to(x.floor() + 0.5, height as f64);
+                cr.stroke();
+            } else {
+                cr.paint_with_alpha(position.get());
+            }
+
+            gtk::Inhibit(false)
+        }));
+
+        let slider = gtk::Scale::new_with_range(gtk::Orientation::Horizontal, 0.0, 1.0, 0.01);
+        slider.set_value(position.get());
+        slider.set_draw_value(false);
+        slider.set_size_request(width, -1);
+        slider.set_halign(gtk::Align::Start);
+
+        slider.connect_value_changed(clone!(area, position => move |slider| {
+            position.set(slider.get_value());
+            area.queue_draw();
+        }));
+
+        let labels = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        labels.set_size_request(width, -1);
+        labels.set_halign(gtk::Align::Start);
+        labels.pack_start(&gtk::Label::new("Old"), false, false, 0);
+        labels.pack_end(&gtk::Label::new("New"), false, false, 0);
+
+        container.add(&area);
+        container.add(&slider);
+        container.add(&labels);
+        container
+    }
+
+    pub fn widget(&self) -> &gtk::Box {
+        &self.root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_use_the_largest_fitting_unit() {
+        assert_eq!(format_size(0), "0 bytes");
+        assert_eq!(format_size(1023), "1023 bytes");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(3 * 1_048_576), "3.0 MB");
+    }
+
+    #[test]
+    fn summaries_tell_what_happened_to_the_file() {
+        let binary = |old_size, new_size| BinaryDiff { old_size, new_size, ..BinaryDiff::default() };
+
+        assert_eq!(summary(&binary(Some(10), Some(2048))), "Binary file changed: 10 bytes → 2.0 KB");
+        assert_eq!(summary(&binary(None, Some(10))), "Binary file added: 10 bytes");
+        assert_eq!(summary(&binary(Some(10), None)), "Binary file deleted: 10 bytes");
+        assert_eq!(summary(&binary(None, None)), "Binary file");
+    }
+}
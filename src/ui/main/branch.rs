@@ -26,6 +26,10 @@ use super::history::{HistoryView, HistoryViewable};
 use super::diff::DiffView;
 use super::CommitInfo;
 
+use crate::git::apply::{self, ApplyReport, ApplyTarget, PatchMessage};
+use crate::git::diff::{Comparison, DiffBase, DiffSettings, DiffSide, DiffSource, DiffStats, FileDiff, PatchRequest};
+use crate::git::history::{CommitDetails, CommitLink};
+use crate::git::worker::{Client, Request, Response, Worker};
 use crate::ui::main::TreeItem;
 use crate::ui::main::diff::DiffContext;
 use crate::ui::AsMessageDialog;
@@ -33,24 +37,90 @@ use crate::ui::AsMessageDialog;
 pub struct BranchPresenter<V> {
     view: RefCell<Weak<V>>,
     repo: RefCell<Rc<git2::Repository>>,
+    worker: Rc<Worker>,
+    client: Client,
     deltas: RefCell<(Vec<TreeItem>, Vec<TreeItem>)>,
-    branch: RefCell<String>
+    branch: RefCell<String>,
+    shown: RefCell<Option<DiffTarget>>,
+    /// The patches previewed in the diff, until they are applied or something else is shown.
+    patch: RefCell<Vec<PatchMessage>>
 }
 
-impl<V: BranchViewable> BranchPresenter<V> {
-    fn new(repo: Rc<git2::Repository>, initial_branch: String) -> BranchPresenter<V> {
-         BranchPresenter {
+/// What the file list and diff currently show, so it can be asked for again.
+#[derive(Debug, Clone)]
+enum DiffTarget {
+    Uncommitted,
+    Commit(git2::Oid, DiffBase),
+    Comparison(DiffSide, DiffSide)
+}
+
+impl DiffTarget {
+    fn request(&self) -> Request {
+        match *self {
+            DiffTarget::Uncommitted => Request::WorkdirDiff,
+            DiffTarget::Commit(id, base) => Request::CommitDiff(id, base),
+            DiffTarget::Comparison(ref from, ref to) => Request::Compare { from: from.clone(), to: to.clone() }
+        }
+    }
+
+    /// Where the diff shown for the target comes from. For uncommitted changes only
+    /// what is staged is shown.
+    fn source(&self) -> DiffSource {
+        match *self {
+            DiffTarget::Uncommitted => DiffSource::Staged,
+            DiffTarget::Commit(id, base) => DiffSource::Commit(id, base),
+            DiffTarget::Comparison(ref from, ref to) => DiffSource::Comparison(from.clone(), to.clone())
+        }
+    }
+}
+
+impl TreeItem {
+    fn new(file: &FileDiff, is_selected: bool) -> TreeItem {
+        TreeItem {
+            id: file.new_id,
+            path: file.path().to_string(),
+            label: file.label(),
+            annotations: file.annotations().join(", "),
+            delta: file.status,
+            is_selected
+        }
+    }
+}
+
+impl<V: BranchViewable> BranchPresenter<V> where V: 'static {
+    fn new(repo: Rc<git2::Repository>, worker: Rc<Worker>, initial_branch: String) -> Rc<BranchPresenter<V>> {
+        let presenter = Rc::new(BranchPresenter {
             view: RefCell::new(Weak::new()),
             repo: RefCell::new(repo),
+            client: worker.client(),
+            worker,
             deltas: RefCell::new((vec![], vec![])),
-            branch: RefCell::new(initial_branch)
-        }
+            branch: RefCell::new(initial_branch),
+            shown: RefCell::new(None),
+            patch: RefCell::new(vec![])
+        });
+
+        gtk::timeout_add(50, weak!(presenter => move || {
+            let presenter = try_upgrade!(presenter, gtk::Continue(false));
+
+            while let Some(response) = presenter.client.try_recv() {
+                presenter.on_response(response);
+            }
+
+            gtk::Continue(true)
+        }));
+
+        presenter
     }
 
     pub fn repo(&self) -> Rc<git2::Repository> {
         self.repo.borrow().clone()
     }
 
+    pub fn worker(&self) -> Rc<Worker> {
+        Rc::clone(&self.worker)
+    }
+
     pub fn deltas(&self) -> &RefCell<(Vec<TreeItem>, Vec<TreeItem>)> {
         &self.deltas
     }
@@ -75,81 +145,228 @@ impl<V: BranchViewable> BranchPresenter<V> {
         };
 
         *self.branch.borrow_mut() = branch;
+        self.client.send(Request::Open(repo.path().to_path_buf()));
         *self.repo.borrow_mut() = repo;
+        *self.shown.borrow_mut() = None;
 
+        self.view().restore_diff_settings();
         self.view().refresh_commit_history();
     }
 
+    pub fn stage_path(&self, path: &str) {
+        self.client.send(Request::Stage(path.into()));
+        self.on_uncommitted_changes_selected();
+    }
+
+    pub fn unstage_path(&self, path: &str) {
+        self.client.send(Request::Unstage(path.into()));
+        self.on_uncommitted_changes_selected();
+    }
+
+    fn show(&self, target: DiffTarget) {
+        self.client.send_latest(target.request());
+        *self.shown.borrow_mut() = Some(target);
+        self.patch.borrow_mut().clear();
+    }
+
+    /// Shows a unified diff or mbox series in the diff, to be applied from there.
+    /// `source` names where it came from.
+    pub fn preview_patch(&self, text: &str, source: &str) {
+        let messages = apply::parse(text);
+        let files: Vec<FileDiff> = messages.iter().flat_map(|x| x.files.iter().cloned()).collect();
+
+        if files.is_empty() {
+            return self.view().handle_error("No patch was found.");
+        }
+
+        let count = |origin: char| files.iter()
+            .flat_map(|x| x.hunks.iter())
+            .flat_map(|x| x.lines.iter())
+            .filter(|x| x.origin == origin)
+            .count();
+
+        let to_label = match messages.len() {
+            1 => source.to_string(),
+            n => format!("{} ({} patches)", source, n)
+        };
+
+        let comparison = Comparison {
+            from_label: "Working tree".to_string(),
+            to_label,
+            stats: DiffStats { files: files.len(), insertions: count('+'), deletions: count('-') }
+        };
+
+        let deltas: Vec<TreeItem> = files.iter()
+            .map(|x| TreeItem::new(x, true))
+            .collect();
+
+        // Whatever was shown before must not replace the preview once it arrives.
+        self.client.cancel();
+        *self.shown.borrow_mut() = None;
+        *self.patch.borrow_mut() = messages;
+
+        self.view().set_comparison_statuses(&deltas, &comparison);
+        self.view().set_diff(files, DiffContext::Patch);
+    }
+
+    pub fn apply_patch(&self, target: ApplyTarget) {
+        let messages = self.patch.borrow().clone();
+
+        if !messages.is_empty() {
+            self.client.send(Request::ApplyPatch { messages, target });
+        }
+    }
+
+    /// Generates every following diff with `settings`, starting with the one shown.
+    pub fn set_diff_settings(&self, settings: DiffSettings) {
+        self.client.send(Request::DiffSettings(settings));
+
+        let shown = self.shown.borrow().clone();
+        if let Some(target) = shown {
+            self.show(target);
+        }
+    }
+
     pub fn on_uncommitted_changes_selected(&self) {
-        let repo = self.repo.borrow();
-        let repo_head_tree = repo.head().unwrap().peel_to_tree().unwrap();
-        let mut diff_opts = git2::DiffOptions::new();
-        diff_opts
-            .include_untracked(true)
-            .recurse_untracked_dirs(true);
-
-        let mut workdir_diff = repo.diff_tree_to_workdir_with_index(Some(&repo_head_tree), Some(&mut diff_opts)).unwrap();
-        workdir_diff.find_similar(None).unwrap();
-
-        let mut index_diff = repo.diff_tree_to_index(Some(&repo_head_tree), None, None).unwrap();
-        index_diff.find_similar(None).unwrap();
-
-        let index_deltas: Vec<TreeItem> = index_diff.deltas().map(|d| {
-            TreeItem {
-                id: d.new_file().id(),
-                path: d.new_file().path().unwrap().to_string_lossy().to_string(),
-                delta: d.status(),
-                is_selected: true
-            }
-        }).collect();
-
-        let workdir_deltas: Vec<TreeItem> = workdir_diff.deltas().map(|d| {
-            TreeItem {
-                id: d.new_file().id(),
-                path: d.new_file().path().unwrap().to_string_lossy().to_string(),
-                delta: d.status(),
-                is_selected: false
-            }
-        })
-        .filter(|x| !index_deltas.iter().any(|y| x.id == y.id))
-        .collect();
+        self.show(DiffTarget::Uncommitted);
+    }
+
+    pub fn on_commit_selected(&self, info: &CommitInfo) {
+        self.on_commit_id_selected(info.id);
+    }
+
+    pub fn on_commit_id_selected(&self, id: git2::Oid) {
+        self.show_commit_diff(id, DiffBase::Parent(0));
+    }
+
+    /// Shows a commit compared against `base`, such as another parent of a merge.
+    pub fn show_commit_diff(&self, id: git2::Oid, base: DiffBase) {
+        self.show(DiffTarget::Commit(id, base));
+    }
+
+    /// Shows what changed going from one commit, ref or the working tree to another.
+    pub fn compare(&self, from: DiffSide, to: DiffSide) {
+        self.show(DiffTarget::Comparison(from, to));
+    }
+
+    pub fn swap_comparison(&self) {
+        let shown = self.shown.borrow().clone();
+
+        if let Some(DiffTarget::Comparison(from, to)) = shown {
+            self.compare(to, from);
+        }
+    }
+
+    /// Loads the patches of files of the diff shown, which are only listed at first.
+    pub fn load_file_patches(&self, requests: Vec<PatchRequest>) {
+        let source = match *self.shown.borrow() {
+            Some(ref target) => target.source(),
+            None => return
+        };
+
+        self.client.send(Request::FilePatches(source, requests));
+    }
+
+    /// Reads the old side of a file of the diff shown, to show more lines around its hunks.
+    pub fn load_file_lines(&self, index: usize, id: git2::Oid, path: Option<String>) {
+        self.client.send(Request::FileLines { index, id, path });
+    }
+
+    /// Scrolls the diff to a file picked from the file list.
+    pub fn show_file_in_diff(&self, path: &str) {
+        self.view().show_file_in_diff(path);
+    }
+
+    /// Selects a commit in the history, such as one linked to from the overview.
+    pub fn select_commit(&self, id: git2::Oid) {
+        self.view().select_commit(id);
+    }
+
+    fn on_response(&self, response: Response) {
+        match response {
+            Response::WorkdirDiff { staged, unstaged } => self.on_workdir_diff(staged, unstaged),
+            Response::CommitDiff(details, files) => self.on_commit_diff(details, files),
+            Response::Comparison(comparison, files) => self.on_comparison(comparison, files),
+            Response::FilePatches(source, files) => self.on_file_patches(source, files),
+            Response::FileLines { index, id, lines } => self.view().set_file_lines(index, id, lines),
+            Response::PatchApplied(report) => self.on_patch_applied(report),
+            Response::Error(err) => self.view().handle_error(err),
+            _ => {}
+        }
+    }
+
+    fn on_workdir_diff(&self, staged: Vec<FileDiff>, unstaged: Vec<FileDiff>) {
+        let index_deltas: Vec<TreeItem> = staged.iter()
+            .map(|x| TreeItem::new(x, true))
+            .collect();
+
+        let workdir_deltas: Vec<TreeItem> = unstaged.iter()
+            .map(|x| TreeItem::new(x, false))
+            .filter(|x| !index_deltas.iter().any(|y| x.id == y.id))
+            .collect();
 
         self.view().set_statuses(&index_deltas, &workdir_deltas);
-        self.view().set_diff(index_diff, DiffContext::Staged);
+        self.view().set_diff(staged, DiffContext::Staged);
 
         *self.deltas.borrow_mut() = (index_deltas, workdir_deltas);
     }
 
-    pub fn on_commit_selected<'a>(&self, info: &CommitInfo) {
-        let repo = self.repo.borrow();
-        let commit = repo.find_commit(info.id).expect("Commit to exist in repo");
-        let parent_commits: Vec<git2::Commit> = commit.parents().collect();
+    fn on_commit_diff(&self, mut details: CommitDetails, files: Vec<FileDiff>) {
+        details.children = self.view().children_of(details.id);
 
-        let maybe_parent = parent_commits.first().map(|x| x.tree().unwrap());
-        let parent = match maybe_parent {
-            Some(ref v) => Some(v),
-            None => None
-        };
+        let deltas: Vec<TreeItem> = files.iter()
+            .map(|x| TreeItem::new(x, true))
+            .collect();
+
+        self.view().set_overview_statuses(&deltas, &details);
+        self.view().set_diff(files, DiffContext::Committed);
+    }
+
+    fn on_comparison(&self, comparison: Comparison, files: Vec<FileDiff>) {
+        let deltas: Vec<TreeItem> = files.iter()
+            .map(|x| TreeItem::new(x, true))
+            .collect();
 
-        let mut diff = repo.diff_tree_to_tree(
-            parent,
-            Some(&commit.tree().unwrap()),
-            None
-        ).expect("a diff");
+        self.view().set_comparison_statuses(&deltas, &comparison);
+        self.view().set_diff(files, DiffContext::Comparison);
+    }
 
-        diff.find_similar(None).unwrap();
+    fn on_patch_applied(&self, report: ApplyReport) {
+        let mut message = match report.target {
+            ApplyTarget::Commits => format!("Created {} commits.", report.commits),
+            _ if !report.rejections.is_empty() => "Nothing was applied.".to_string(),
+            ApplyTarget::Index => format!("Applied {} files to the index.", report.files),
+            ApplyTarget::WorkingTree => format!("Applied {} files to the working tree.", report.files)
+        };
 
-        let deltas: Vec<TreeItem> = diff.deltas().map(|d| {
-            TreeItem {
-                id: d.new_file().id(),
-                path: d.new_file().path().unwrap().to_string_lossy().to_string(),
-                delta: d.status(),
-                is_selected: true
+        if let Some(ref subject) = report.stopped_at {
+            message.push_str(&format!("\n\nStopped at “{}”, which was not committed.", subject));
+        }
+
+        if !report.rejections.is_empty() {
+            message.push_str("\n\nRejected:");
+            for rejection in report.rejections.iter() {
+                match rejection.hunk {
+                    Some(ref hunk) => message.push_str(&format!("\n{}: {}: {}", rejection.path, hunk, rejection.reason)),
+                    None => message.push_str(&format!("\n{}: {}", rejection.path, rejection.reason))
+                }
             }
-        }).collect();
+        }
 
-        self.view().set_overview_statuses(&deltas, &commit);
-        self.view().set_diff(diff, DiffContext::Committed);
+        self.view().show_message(&message, !report.rejections.is_empty());
+
+        if report.target == ApplyTarget::Commits {
+            self.view().refresh_commit_history();
+        }
+        self.on_uncommitted_changes_selected();
+    }
+
+    fn on_file_patches(&self, source: DiffSource, files: Vec<(usize, FileDiff)>) {
+        let is_shown = self.shown.borrow().as_ref().map(|x| x.source() == source).unwrap_or(false);
+
+        if is_shown {
+            self.view().set_file_patches(files);
+        }
     }
 
     pub fn view(&self) -> Rc<V> {
@@ -161,12 +378,20 @@ impl<V: BranchViewable> BranchPresenter<V> {
 
 pub trait BranchViewable {
     fn handle_error(&self, error: impl fmt::Display);
-    fn set_overview_statuses(&self, statuses: &[TreeItem], commit: &git2::Commit);
+    fn show_message(&self, message: &str, is_warning: bool);
+    fn set_overview_statuses(&self, statuses: &[TreeItem], commit: &CommitDetails);
     fn set_statuses(&self, staged: &[TreeItem], unstaged: &[TreeItem]);
-    fn set_diff(&self, diff: git2::Diff, context: DiffContext);
+    fn set_comparison_statuses(&self, statuses: &[TreeItem], comparison: &Comparison);
+    fn set_diff(&self, files: Vec<FileDiff>, context: DiffContext);
+    fn set_file_patches(&self, files: Vec<(usize, FileDiff)>);
+    fn set_file_lines(&self, index: usize, id: git2::Oid, lines: Vec<String>);
+    fn show_file_in_diff(&self, path: &str);
     fn set_repo(&self, repo: Rc<git2::Repository>);
     fn set_branch(&self, branch: &str);
     fn refresh_commit_history(&self);
+    fn restore_diff_settings(&self);
+    fn select_commit(&self, id: git2::Oid);
+    fn children_of(&self, id: git2::Oid) -> Vec<CommitLink>;
 }
 
 #[allow(dead_code)]
@@ -191,6 +416,18 @@ impl BranchViewable for BranchView {
     fn refresh_commit_history(&self) {
         self.history_view.refresh_commit_history();
     }
+
+    fn select_commit(&self, id: git2::Oid) {
+        self.history_view.select_commit(id);
+    }
+
+    fn restore_diff_settings(&self) {
+        self.diff_view.restore_settings();
+    }
+
+    fn children_of(&self, id: git2::Oid) -> Vec<CommitLink> {
+        self.history_view.children_of(id)
+    }
     
     fn handle_error(&self, error: impl fmt::Display) {
         let dialog = error.as_message_dialog(Some(&self.window));
@@ -198,22 +435,52 @@ impl BranchViewable for BranchView {
         dialog.destroy();
     }
     
+    fn show_message(&self, message: &str, is_warning: bool) {
+        let kind = if is_warning { gtk::MessageType::Warning } else { gtk::MessageType::Info };
+        let dialog = gtk::MessageDialog::new(
+            Some(&self.window),
+            gtk::DialogFlags::MODAL,
+            kind,
+            gtk::ButtonsType::Close,
+            message
+        );
+
+        dialog.run();
+        dialog.destroy();
+    }
+
     fn set_statuses(&self, staged: &[TreeItem], unstaged: &[TreeItem]) {
         self.files_view.presenter.set_history_statuses(staged, unstaged);
     }
 
-    fn set_overview_statuses(&self, statuses: &[TreeItem], commit: &git2::Commit) {
+    fn set_overview_statuses(&self, statuses: &[TreeItem], commit: &CommitDetails) {
         self.files_view.presenter.set_overview_statuses(statuses, commit);
     }
 
-    fn set_diff(&self, diff: git2::Diff, context: DiffContext) {
-        DiffView::set_diff(&self.diff_view, diff, context);
+    fn set_comparison_statuses(&self, statuses: &[TreeItem], comparison: &Comparison) {
+        self.files_view.presenter.set_comparison_statuses(statuses, comparison);
+    }
+
+    fn set_diff(&self, files: Vec<FileDiff>, context: DiffContext) {
+        DiffView::set_diff(&self.diff_view, files, context);
+    }
+
+    fn set_file_patches(&self, files: Vec<(usize, FileDiff)>) {
+        self.diff_view.set_file_patches(files);
+    }
+
+    fn set_file_lines(&self, index: usize, id: git2::Oid, lines: Vec<String>) {
+        self.diff_view.set_file_lines(index, id, lines);
+    }
+
+    fn show_file_in_diff(&self, path: &str) {
+        self.diff_view.scroll_to_file(path);
     }
 }
 
 impl BranchView {
-    pub fn new(window: &gtk::Window, repo: Rc<git2::Repository>, initial_branch: String) -> Rc<BranchView> {
-        let presenter = Rc::new(BranchPresenter::new(repo, initial_branch));
+    pub fn new(window: &gtk::Window, repo: Rc<git2::Repository>, worker: Rc<Worker>, initial_branch: String) -> Rc<BranchView> {
+        let presenter = BranchPresenter::new(repo, worker, initial_branch);
 
         let (history_view, files_view, diff_view, root) = BranchView::create(Rc::downgrade(&presenter));
 
@@ -222,7 +489,7 @@ impl BranchView {
             history_view, 
             files_view,
             diff_view,
-            root: root,
+            root,
             window: window.clone()
         });
 
@@ -232,7 +499,7 @@ impl BranchView {
     fn create(parent: Weak<BranchPresenter<BranchView>>) -> (Rc<HistoryView>, Rc<FileStatusView>, Rc<DiffView>, gtk::Paned) {
         let commit_history = HistoryView::new(parent.clone());
         let files_view = FileStatusView::new(parent.clone());
-        let diff_view = Rc::new(DiffView::new());
+        let diff_view = DiffView::new(parent.clone());
 
         let main_pane = gtk::Paned::new(gtk::Orientation::Vertical);
         let bottom_pane = gtk::Paned::new(gtk::Orientation::Horizontal);
@@ -246,6 +513,14 @@ impl BranchView {
         (commit_history, files_view, diff_view, main_pane)
     }
 
+    pub fn compare(&self, from: &str, to: &str) {
+        self.presenter.compare(DiffSide::Rev(from.to_string()), DiffSide::Rev(to.to_string()));
+    }
+
+    pub fn preview_patch(&self, text: &str, source: &str) {
+        self.presenter.preview_patch(text, source);
+    }
+
     pub fn widget(&self) -> &gtk::Paned {
         &self.root
     }
@@ -13,493 +13,718 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::rc::{Rc, Weak};
-use std::sync::{Arc, RwLock};
 
-use git2;
 use gtk::prelude::*;
 use gtk;
 use gdk;
+use gdk::enums::key;
+use gdk_pixbuf::Pixbuf;
+use glib::markup_escape_text;
 use pango;
+use preferences::PreferencesError;
 
-use crate::ui;
-use crate::ui::Parent;
-
-const NO_NL_STR: &'static str = "No newline at end of file";
+use git2;
 
-pub struct DiffView {
-    root: gtk::ScrolledWindow,
-    container: gtk::Box,
-    files: RefCell<Vec<Rc<DiffFileView>>>
+use crate::git::apply::ApplyTarget;
+use crate::git::diff::{DiffAlgorithm, DiffHunk, DiffLine, DiffSettings, FileDiff, PatchRequest, WhitespaceMode};
+use crate::git::patch;
+use super::binary::{self, BinaryDiffView};
+use super::branch::{BranchPresenter, BranchView, BranchViewable};
+use super::highlight::{self, Span, Style};
+use super::pattern::Pattern;
+use super::syntax::{self, Language};
+
+const NO_NL_STR: &str = "No newline at end of file";
+
+/// How many hidden lines each click reveals on either side of a gap between hunks.
+const EXPAND_STEP: usize = 20;
+
+trait DiffViewable {
+    fn set_settings(&self, settings: DiffSettings);
+    fn set_limits(&self, limits: DiffLimits);
+    fn set_show_whitespace(&self, show_whitespace: bool);
+    fn show_primary_action(&self, label: &str, is_enabled: bool);
+    fn hide_primary_button(&self);
+    fn show_apply_controls(&self, is_shown: bool);
 }
 
-impl DiffView {
-    pub fn new() -> DiffView {
-        let container = gtk::Box::new(gtk::Orientation::Vertical, 8);
-        container.get_style_context().add_class("diff-container");
-
-        let root = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
-        root.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
-        root.add(&container);
+struct DiffPresenter<V> {
+    parent: Weak<BranchPresenter<BranchView>>,
+    view: RefCell<Weak<V>>,
+    settings: Cell<DiffSettings>,
+    limits: Cell<DiffLimits>,
+    /// Whether whitespace and line endings are drawn as markers.
+    show_whitespace: Cell<bool>,
+    /// What the shown diff is of, or `None` if it has no files.
+    context: Cell<Option<DiffContext>>
+}
 
-        DiffView {
-            root,
-            container,
-            files: RefCell::new(vec![])
+impl<V: DiffViewable> DiffPresenter<V> {
+    fn new(parent: Weak<BranchPresenter<BranchView>>) -> DiffPresenter<V> {
+        DiffPresenter {
+            parent,
+            view: RefCell::new(Weak::new()),
+            settings: Cell::new(DiffSettings::default()),
+            limits: Cell::new(DiffLimits::default()),
+            show_whitespace: Cell::new(false),
+            context: Cell::new(None)
         }
     }
 
-    pub fn set_diff(view: &Rc<DiffView>, diff: git2::Diff, context: DiffContext) {
-        for child in view.container.get_children() {
-            view.container.remove(&child);
-        }
+    fn view(&self) -> Rc<V> {
+        self.view.borrow()
+            .upgrade()
+            .expect("Presenter only running while view still exists")
+    }
 
-        let mut views = vec![];
+    fn parent(&self) -> Rc<BranchPresenter<BranchView>> {
+        self.parent
+            .upgrade()
+            .expect("Presenter only running while parent still exists")
+    }
 
-        for (n, d) in diff.deltas().enumerate() {
-            let patch = git2::Patch::from_diff(&diff, n).unwrap().unwrap();
-            let path = d.new_file().path().unwrap().to_string_lossy();
+    fn repo_key(&self) -> String {
+        self.parent().repo().path().to_string_lossy().to_string()
+    }
 
-            let file_view = DiffFileView::new(Rc::downgrade(&view), context, patch, &path);
-            view.container.add(file_view.widget());
-            views.push(file_view);
+    fn check_saved(&self, saved: Result<(), PreferencesError>) {
+        if let Err(err) = saved {
+            self.parent().view().handle_error(format!("The diff options could not be saved: {}", err));
         }
-
-        *view.files.borrow_mut() = views;
-
-        view.container.show_all();
     }
 
-    pub fn widget(&self) -> &gtk::ScrolledWindow {
-        &self.root
+    /// Picks up the settings last used with the current repository.
+    fn restore_settings(&self) {
+        use crate::Config;
+
+        let key = self.repo_key();
+        let defaults = DiffSettings::default();
+        let setting = |name| Config::repo_setting(&key, name);
+        let number = |name, default| setting(name).and_then(|x| x.parse().ok()).unwrap_or(default);
+        let flag = |name, default| setting(name).map(|x| x == "true").unwrap_or(default);
+
+        let settings = DiffSettings {
+            whitespace: setting("diff_whitespace")
+                .and_then(|x| WhitespaceMode::from_str(&x))
+                .unwrap_or(defaults.whitespace),
+            context_lines: number("diff_context_lines", defaults.context_lines as u16) as u32,
+            algorithm: setting("diff_algorithm")
+                .and_then(|x| DiffAlgorithm::from_str(&x))
+                .unwrap_or(defaults.algorithm),
+            detect_renames: flag("diff_detect_renames", defaults.detect_renames),
+            rename_threshold: number("diff_rename_threshold", defaults.rename_threshold),
+            detect_copies: flag("diff_detect_copies", defaults.detect_copies),
+            copy_threshold: number("diff_copy_threshold", defaults.copy_threshold)
+        };
+
+        let size = |name, default| setting(name).and_then(|x| x.parse().ok()).unwrap_or(default);
+        let limits = DiffLimits {
+            max_lines: size("diff_max_lines", DiffLimits::default().max_lines),
+            max_bytes: size("diff_max_bytes", DiffLimits::default().max_bytes)
+        };
+
+        let show_whitespace = flag("diff_show_whitespace", false);
+
+        self.settings.set(settings);
+        self.limits.set(limits);
+        self.show_whitespace.set(show_whitespace);
+        self.view().set_settings(settings);
+        self.view().set_limits(limits);
+        self.view().set_show_whitespace(show_whitespace);
+        self.parent().set_diff_settings(settings);
     }
-}
 
-#[allow(dead_code)]
-pub struct DiffFileView {
-    root: gtk::Box,
-    label: gtk::Label,
-    parent: Weak<DiffView>,
-    children: RefCell<Vec<Rc<DiffChunkView>>>
-}
+    fn on_settings_changed(&self, settings: DiffSettings) {
+        use crate::Config;
 
-impl ui::Parent for DiffFileView {
-    type View = DiffView;
+        if settings == self.settings.get() {
+            return;
+        }
 
-    fn parent(&self) -> Option<Rc<DiffView>> {
-        self.parent.upgrade()
+        let key = self.repo_key();
+        let saved = Config::set_repo_setting(&key, "diff_whitespace", settings.whitespace.as_str())
+            .and_then(|_| Config::set_repo_setting(&key, "diff_context_lines", &settings.context_lines.to_string()))
+            .and_then(|_| Config::set_repo_setting(&key, "diff_algorithm", settings.algorithm.as_str()))
+            .and_then(|_| Config::set_repo_setting(&key, "diff_detect_renames", &settings.detect_renames.to_string()))
+            .and_then(|_| Config::set_repo_setting(&key, "diff_rename_threshold", &settings.rename_threshold.to_string()))
+            .and_then(|_| Config::set_repo_setting(&key, "diff_detect_copies", &settings.detect_copies.to_string()))
+            .and_then(|_| Config::set_repo_setting(&key, "diff_copy_threshold", &settings.copy_threshold.to_string()));
+        self.check_saved(saved);
+
+        self.settings.set(settings);
+        self.parent().set_diff_settings(settings);
     }
-}
-
-impl DiffFileView {
-    pub fn new(parent: Weak<DiffView>, context: DiffContext, patch: git2::Patch, path: &str) -> Rc<DiffFileView> {
-        let root = gtk::Box::new(gtk::Orientation::Vertical, 0);
-        root.get_style_context().add_class("file-border");
-        
-        let label = gtk::Label::new(path);
-        label.set_xalign(0.0);
-        label.set_ellipsize(pango::EllipsizeMode::Middle);
-        label.get_style_context().add_class("file-label");
 
-        root.add(&label);
+    fn on_limits_changed(&self, limits: DiffLimits) {
+        use crate::Config;
 
-        let view = Rc::new(DiffFileView {
-            root,
-            label,
-            parent,
-            children: RefCell::new(vec![])
-        });
+        let key = self.repo_key();
+        let saved = Config::set_repo_setting(&key, "diff_max_lines", &limits.max_lines.to_string())
+            .and_then(|_| Config::set_repo_setting(&key, "diff_max_bytes", &limits.max_bytes.to_string()));
+        self.check_saved(saved);
 
-        for hunk_idx in 0..patch.num_hunks() {
-            let hunk = patch.hunk(hunk_idx).unwrap().0;
-            let header = ::std::str::from_utf8(hunk.header()).unwrap();
+        self.limits.set(limits);
+    }
 
-            let chunk_view = DiffChunkView::new(Rc::downgrade(&view), context, &patch, header, hunk_idx);
-            view.root.add(chunk_view.widget());
-            view.children.borrow_mut().push(chunk_view);
-        }
+    fn on_show_whitespace_changed(&self, show_whitespace: bool) {
+        use crate::Config;
 
-        view
+        self.check_saved(Config::set_repo_setting(&self.repo_key(), "diff_show_whitespace", &show_whitespace.to_string()));
+        self.show_whitespace.set(show_whitespace);
     }
 
-    pub fn widget(&self) -> &gtk::Box {
-        &self.root
+    fn load_file_lines(&self, index: usize, file: &FileDiff) {
+        self.parent().load_file_lines(index, file.old_id, file.old_path.clone());
     }
-}
-
-trait HumanDiffLineExt<'a> {
-    fn origin_human(&self) -> char;
-    fn content_str(&self) -> Option<&str>;
-}
 
-impl<'a> HumanDiffLineExt<'a> for git2::DiffLine<'a> {
-    fn origin_human(&self) -> char {
-        let ch = self.origin();
-        match ch {
-            '=' | '>' | '<' => '\\',
-            _ => ch
+    fn load_file_patches(&self, requests: Vec<PatchRequest>) {
+        if !requests.is_empty() {
+            self.parent().load_file_patches(requests);
         }
     }
 
-    fn content_str(&self) -> Option<&str> {
-        match ::std::str::from_utf8(self.content()) {
-            Ok(v) => {
-                // Check for weird NL string
-                if v.trim_end().ends_with(NO_NL_STR) {
-                    Some(NO_NL_STR)
-                } else {
-                    Some(v.trim_end())
-                }
-            },
-            Err(_) => None
-        }
+    fn set_context(&self, context: Option<DiffContext>) {
+        self.context.set(context);
+        self.view().show_apply_controls(context == Some(DiffContext::Patch));
     }
-                
-}
 
-#[allow(dead_code)]
-pub struct DiffChunkView {
-    presenter: DiffChunkPresenter<DiffChunkView>,
-    list_store: gtk::ListStore,
-    label: gtk::Label,
-    primary_button: gtk::Button,
-    lines_tree: gtk::TreeView,
-    count_tree: gtk::TreeView,
-    root: gtk::Box,
-    parent: Weak<DiffFileView>
-}
+    fn on_apply_clicked(&self, target: ApplyTarget) {
+        self.parent().apply_patch(target);
+    }
 
-impl ui::Parent for DiffChunkView {
-    type View = DiffFileView;
+    fn on_primary_button_clicked(&self) {
+        
+    }
 
-    fn parent(&self) -> Option<Rc<DiffFileView>> {
-        self.parent.upgrade()
+    /// Takes the selected lines as file, hunk and line indices, in order.
+    fn on_selected_lines(&self, lines: &[(usize, usize, usize)]) {
+        let has_selection = !lines.is_empty();
+        let is_contiguous = lines.windows(2)
+            .all(|s| (s[0].0, s[0].1) == (s[1].0, s[1].1) && s[1].2 == s[0].2 + 1);
+
+        let (selected, all) = match self.context.get() {
+            Some(DiffContext::Committed) => ("Revert Selected Lines", "Revert All Lines"),
+            Some(DiffContext::Staged) => ("Unstage Selected Lines", "Unstage All Lines"),
+            Some(DiffContext::Comparison) | Some(DiffContext::Patch) | None => return self.view().hide_primary_button()
+        };
+
+        if has_selection && is_contiguous {
+            self.view().show_primary_action(selected, true)
+        } else if !has_selection {
+            self.view().show_primary_action(all, true)
+        } else {
+            self.view().show_primary_action("Incontiguous selection", false)
+        }
     }
 }
 
-trait DiffChunkViewable {
-    fn show_revert_selected_lines(&self);
-    fn show_unstage_selected_lines(&self);
-    fn show_stage_selected_lines(&self);
-    fn show_revert_all_lines(&self);
-    fn show_stage_all_lines(&self);
-    fn show_unstage_all_lines(&self);
-    fn show_incontiguous_selection_error(&self);
-    fn on_primary_button_clicked(&self);
-    fn on_selected_lines(&self, rows: &[usize]);
-}
+/// The spans of every line of a hunk.
+type HunkSpans = Vec<Vec<Span>>;
 
-#[derive(Copy, Clone, Debug)]
-pub enum DiffContext {
-    Committed,
-    Staged,
-    Working
+/// Previews of the old and new versions of an image.
+type ImagePair = (Option<Pixbuf>, Option<Pixbuf>);
+
+/// The old side of a file, read whole, along with the syntax of each of its lines.
+struct FileLines {
+    lines: Vec<String>,
+    spans: Vec<Vec<Span>>
 }
 
-struct DiffChunkPresenter<V: DiffChunkViewable> {
-    view: RefCell<Weak<V>>,
-    context: DiffContext
+pub struct DiffView {
+    presenter: DiffPresenter<DiffView>,
+    whitespace_combo: gtk::ComboBoxText,
+    context_spin: gtk::SpinButton,
+    algorithm_combo: gtk::ComboBoxText,
+    renames_toggle: gtk::CheckButton,
+    rename_spin: gtk::SpinButton,
+    copies_toggle: gtk::CheckButton,
+    copy_spin: gtk::SpinButton,
+    split_toggle: gtk::ToggleButton,
+    markers_toggle: gtk::ToggleButton,
+    max_lines_spin: gtk::SpinButton,
+    max_size_spin: gtk::SpinButton,
+    primary_button: gtk::Button,
+    apply_box: gtk::Box,
+    apply_target_combo: gtk::ComboBoxText,
+    apply_button: gtk::Button,
+    is_syncing_settings: Cell<bool>,
+    layout: Cell<DiffLayout>,
+    diff: RefCell<(Vec<FileDiff>, DiffContext)>,
+    /// The highlighting of each hunk, worked out the first time one of its lines is drawn.
+    spans: RefCell<HashMap<(usize, usize), Rc<HunkSpans>>>,
+    images: RefCell<HashMap<usize, ImagePair>>,
+    /// Files whose patches have been asked for but not received yet.
+    requested: RefCell<HashSet<usize>>,
+    /// Files that are loaded whatever the limits, once asked for.
+    forced: RefCell<HashSet<usize>>,
+    /// The old side of files, loaded once more of their unchanged lines are shown.
+    file_lines: RefCell<HashMap<usize, Rc<FileLines>>>,
+    /// How many hidden lines are shown at the start and end of each gap, by file and
+    /// the hunk following the gap.
+    expanded: RefCell<HashMap<(usize, usize), (usize, usize)>>,
+    /// Files that only show their header.
+    collapsed: RefCell<HashSet<usize>>,
+    search_bar: gtk::SearchBar,
+    search_entry: gtk::SearchEntry,
+    regex_toggle: gtk::CheckButton,
+    case_toggle: gtk::CheckButton,
+    search_status: gtk::Label,
+    /// Everything the search found in the loaded files, in the order of the diff.
+    matches: RefCell<Vec<SearchMatch>>,
+    current_match: Cell<Option<usize>>,
+    row_menu: gtk::Menu,
+    copy_hunk_item: gtk::MenuItem,
+    copy_lines_item: gtk::MenuItem,
+    copy_file_item: gtk::MenuItem,
+    /// The row the context menu was opened on.
+    menu_row: Cell<Option<Row>>,
+    tree: gtk::TreeView,
+    root: gtk::Box
 }
 
-impl<V: DiffChunkViewable> DiffChunkPresenter<V> {
-    pub fn new(context: DiffContext) -> DiffChunkPresenter<V> {
-        DiffChunkPresenter { 
-            view: RefCell::new(Weak::new()),
-            context
-        }
+impl DiffViewable for DiffView {
+    fn set_settings(&self, settings: DiffSettings) {
+        self.is_syncing_settings.set(true);
+        self.whitespace_combo.set_active_id(settings.whitespace.as_str());
+        self.context_spin.set_value(settings.context_lines as f64);
+        self.algorithm_combo.set_active_id(settings.algorithm.as_str());
+        self.renames_toggle.set_active(settings.detect_renames);
+        self.rename_spin.set_value(settings.rename_threshold as f64);
+        self.copies_toggle.set_active(settings.detect_copies);
+        self.copy_spin.set_value(settings.copy_threshold as f64);
+        self.is_syncing_settings.set(false);
     }
 
-    pub fn start(&self) {
-        self.handle_on_selected_lines(&[]);
+    fn set_limits(&self, limits: DiffLimits) {
+        self.is_syncing_settings.set(true);
+        self.max_lines_spin.set_value(limits.max_lines as f64);
+        self.max_size_spin.set_value((limits.max_bytes / 1024) as f64);
+        self.is_syncing_settings.set(false);
     }
 
-    fn view(&self) -> Rc<V> {
-        self.view.borrow().upgrade().expect("The view exists")
+    fn set_show_whitespace(&self, show_whitespace: bool) {
+        self.is_syncing_settings.set(true);
+        self.markers_toggle.set_active(show_whitespace);
+        self.is_syncing_settings.set(false);
     }
 
-    fn handle_on_primary_button_clicked(&self) {
-        
+    fn show_primary_action(&self, label: &str, is_enabled: bool) {
+        self.primary_button.set_sensitive(is_enabled);
+        self.primary_button.set_label(label);
+        self.primary_button.show();
     }
 
-    fn handle_on_selected_lines(&self, lines: &[usize]) {
-        let has_selection = lines.len() > 0;
-        let is_contiguous = lines.windows(2).all(|s| s[1] == s[0] + 1);
+    fn hide_primary_button(&self) {
+        self.primary_button.hide();
+    }
 
-        match self.context {
-            DiffContext::Committed => {
-                if has_selection && is_contiguous {
-                    self.view().show_revert_selected_lines()
-                } else if !has_selection {
-                    self.view().show_revert_all_lines()
-                } else {
-                    self.view().show_incontiguous_selection_error()
-                }
-            }
-            DiffContext::Staged => {
-                if has_selection && is_contiguous {
-                    self.view().show_unstage_selected_lines()
-                } else if !has_selection {
-                    self.view().show_unstage_all_lines()
-                } else {
-                    self.view().show_incontiguous_selection_error()
-                }
-            }
-            DiffContext::Working => {
-                if has_selection && is_contiguous {
-                    self.view().show_stage_selected_lines()
-                } else if !has_selection {
-                    self.view().show_stage_all_lines()
-                } else {
-                    self.view().show_incontiguous_selection_error()
-                }
-            }
+    fn show_apply_controls(&self, is_shown: bool) {
+        if is_shown {
+            self.apply_box.show_all();
+        } else {
+            self.apply_box.hide();
         }
     }
 }
 
-impl DiffChunkViewable for DiffChunkView {
-    fn show_revert_selected_lines(&self) {
-        self.primary_button.set_sensitive(true);
-        self.primary_button.set_label("Revert Selected Lines");
-        self.primary_button.show_all();
-    }
-    
-    fn show_unstage_selected_lines(&self) {
-        self.primary_button.set_sensitive(true);
-        self.primary_button.set_label("Unstage Selected Lines");
-        self.primary_button.show_all();
-    }
-    
-    fn show_stage_selected_lines(&self) {
-        self.primary_button.set_sensitive(true);
-        self.primary_button.set_label("Stage Selected Lines");
-        self.primary_button.show_all();
-    }
-    
-    fn show_revert_all_lines(&self) {
-        self.primary_button.set_sensitive(true);
-        self.primary_button.set_label("Revert All Lines");
-        self.primary_button.show_all();
-    }
-    
-    fn show_stage_all_lines(&self) {
-        self.primary_button.set_sensitive(true);
-        self.primary_button.set_label("Stage All Lines");
-        self.primary_button.show_all();
-    }
-    
-    fn show_unstage_all_lines(&self) {
-        self.primary_button.set_sensitive(true);
-        self.primary_button.set_label("Unstage All Lines");
-        self.primary_button.show_all();
-    }
-
-    fn show_incontiguous_selection_error(&self) {
-        self.primary_button.set_sensitive(false);
-        self.primary_button.set_label("Incontiguous selection");
-        self.primary_button.show_all();
-    }
-
-    fn on_primary_button_clicked(&self) {
-        self.presenter.handle_on_primary_button_clicked();
-    }
+impl DiffView {
+    pub fn new(parent: Weak<BranchPresenter<BranchView>>) -> Rc<DiffView> {
+        let tree = gtk::TreeView::new();
+        tree.get_style_context().add_class("monospace");
+        tree.get_style_context().add_class("diff-container");
+        tree.get_selection().set_mode(gtk::SelectionMode::Multiple);
+        tree.set_headers_visible(false);
+        tree.set_show_expanders(false);
+        tree.set_level_indentation(0);
+        tree.set_enable_search(false);
+        connect_drag_selection(&tree);
+
+        // Only lines can be selected, not the headers between them.
+        tree.get_selection().set_select_function(Some(Box::new(|_, model, path, _| {
+            model.get_iter(path)
+                .map(|iter| Row::get(model, &iter).kind == RowKind::Line)
+                .unwrap_or(false)
+        })));
 
-    fn on_selected_lines(&self, rows: &[usize]) {
-        self.presenter.handle_on_selected_lines(&rows);
-    }
-}
+        let scroller = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+        scroller.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+        scroller.set_vexpand(true);
+        scroller.add(&tree);
+
+        let whitespace_combo = gtk::ComboBoxText::new();
+        whitespace_combo.append(WhitespaceMode::Show.as_str(), "Show whitespace changes");
+        whitespace_combo.append(WhitespaceMode::IgnoreAtEol.as_str(), "Ignore whitespace at line end");
+        whitespace_combo.append(WhitespaceMode::IgnoreChanges.as_str(), "Ignore amount of whitespace");
+        whitespace_combo.append(WhitespaceMode::IgnoreAll.as_str(), "Ignore all whitespace");
+
+        let context_spin = gtk::SpinButton::new_with_range(0.0, 100.0, 1.0);
+        context_spin.set_tooltip_text("Lines of context");
+
+        let algorithm_combo = gtk::ComboBoxText::new();
+        algorithm_combo.append(DiffAlgorithm::Myers.as_str(), "Default");
+        algorithm_combo.append(DiffAlgorithm::Patience.as_str(), "Patience");
+        algorithm_combo.append(DiffAlgorithm::Minimal.as_str(), "Minimal");
+
+        let renames_toggle = gtk::CheckButton::new_with_label("Detect renames");
+        let rename_spin = gtk::SpinButton::new_with_range(0.0, 100.0, 5.0);
+        let copies_toggle = gtk::CheckButton::new_with_label("Detect copies");
+        let copy_spin = gtk::SpinButton::new_with_range(0.0, 100.0, 5.0);
+
+        let renames_grid = gtk::Grid::new();
+        renames_grid.set_border_width(8);
+        renames_grid.set_row_spacing(4);
+        renames_grid.set_column_spacing(8);
+        renames_grid.attach(&renames_toggle, 0, 0, 1, 1);
+        renames_grid.attach(&rename_spin, 1, 0, 1, 1);
+        renames_grid.attach(&gtk::Label::new("% similar"), 2, 0, 1, 1);
+        renames_grid.attach(&copies_toggle, 0, 1, 1, 1);
+        renames_grid.attach(&copy_spin, 1, 1, 1, 1);
+        renames_grid.attach(&gtk::Label::new("% similar"), 2, 1, 1, 1);
+        renames_grid.show_all();
+
+        let renames_button = gtk::MenuButton::new();
+        renames_button.set_label("Renames");
+        let renames_popover = gtk::Popover::new(&renames_button);
+        renames_popover.add(&renames_grid);
+        renames_button.set_popover(&renames_popover);
+
+        let max_lines_spin = gtk::SpinButton::new_with_range(100.0, 1_000_000.0, 100.0);
+        let max_size_spin = gtk::SpinButton::new_with_range(16.0, 1_048_576.0, 64.0);
+
+        let limits_grid = gtk::Grid::new();
+        limits_grid.set_border_width(8);
+        limits_grid.set_row_spacing(4);
+        limits_grid.set_column_spacing(8);
+        limits_grid.attach(&gtk::Label::new("Collapse files with more than"), 0, 0, 1, 1);
+        limits_grid.attach(&max_lines_spin, 1, 0, 1, 1);
+        limits_grid.attach(&gtk::Label::new("changed lines"), 2, 0, 1, 1);
+        limits_grid.attach(&gtk::Label::new("or larger than"), 0, 1, 1, 1);
+        limits_grid.attach(&max_size_spin, 1, 1, 1, 1);
+        limits_grid.attach(&gtk::Label::new("KB"), 2, 1, 1, 1);
+        limits_grid.show_all();
+
+        let limits_button = gtk::MenuButton::new();
+        limits_button.set_label("Large files");
+        let limits_popover = gtk::Popover::new(&limits_button);
+        limits_popover.add(&limits_grid);
+        limits_button.set_popover(&limits_popover);
+
+        let collapse_button = gtk::Button::new_with_label("Collapse all");
+        let expand_button = gtk::Button::new_with_label("Expand all");
+
+        let split_toggle = gtk::ToggleButton::new_with_label("Side by side");
+
+        let markers_toggle = gtk::ToggleButton::new_with_label("¶");
+        markers_toggle.set_tooltip_text("Show whitespace and line endings");
+
+        let primary_button = gtk::Button::new_with_label("");
+        primary_button.set_no_show_all(true);
+
+        let apply_target_combo = gtk::ComboBoxText::new();
+        apply_target_combo.append("worktree", "To working tree");
+        apply_target_combo.append("index", "To index");
+        apply_target_combo.append("commits", "As commits");
+        apply_target_combo.set_active_id("worktree");
+        apply_target_combo.set_tooltip_text("As commits keeps the author and message of each patch, like git am");
+
+        let apply_button = gtk::Button::new_with_label("Apply patch");
+        apply_button.get_style_context().add_class("suggested-action");
+
+        let apply_box = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        apply_box.get_style_context().add_class("linked");
+        apply_box.add(&apply_target_combo);
+        apply_box.add(&apply_button);
+        apply_box.set_no_show_all(true);
+
+        let toolbar = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        toolbar.set_border_width(4);
+        toolbar.pack_start(&whitespace_combo, false, false, 0);
+        toolbar.pack_start(&gtk::Label::new("Context:"), false, false, 0);
+        toolbar.pack_start(&context_spin, false, false, 0);
+        toolbar.pack_start(&algorithm_combo, false, false, 0);
+        toolbar.pack_start(&renames_button, false, false, 0);
+        toolbar.pack_start(&limits_button, false, false, 0);
+        toolbar.pack_start(&markers_toggle, false, false, 0);
+        toolbar.pack_end(&split_toggle, false, false, 0);
+        toolbar.pack_end(&expand_button, false, false, 0);
+        toolbar.pack_end(&collapse_button, false, false, 0);
+        toolbar.pack_end(&primary_button, false, false, 0);
+        toolbar.pack_end(&apply_box, false, false, 0);
+
+        let search_entry = gtk::SearchEntry::new();
+        search_entry.set_width_chars(30);
+
+        let previous_button = gtk::Button::new_from_icon_name("go-up-symbolic", gtk::IconSize::Button);
+        previous_button.set_tooltip_text("Previous match");
+        let next_button = gtk::Button::new_from_icon_name("go-down-symbolic", gtk::IconSize::Button);
+        next_button.set_tooltip_text("Next match");
+
+        let regex_toggle = gtk::CheckButton::new_with_label("Regular expression");
+        let case_toggle = gtk::CheckButton::new_with_label("Match case");
+
+        let search_status = gtk::Label::new(None);
+        search_status.get_style_context().add_class("dim-label");
+
+        let search_box = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        search_box.pack_start(&search_entry, false, false, 0);
+        search_box.pack_start(&previous_button, false, false, 0);
+        search_box.pack_start(&next_button, false, false, 0);
+        search_box.pack_start(&regex_toggle, false, false, 0);
+        search_box.pack_start(&case_toggle, false, false, 0);
+        search_box.pack_start(&search_status, false, false, 0);
+
+        let search_bar = gtk::SearchBar::new();
+        search_bar.set_show_close_button(true);
+        search_bar.connect_entry(&search_entry);
+        search_bar.add(&search_box);
+
+        let copy_hunk_item = gtk::MenuItem::new_with_label("Copy hunk as patch");
+        let copy_lines_item = gtk::MenuItem::new_with_label("Copy selected lines as patch");
+        let copy_file_item = gtk::MenuItem::new_with_label("Copy file as patch");
+        let row_menu = gtk::Menu::new();
+        row_menu.append(&copy_hunk_item);
+        row_menu.append(&copy_lines_item);
+        row_menu.append(&copy_file_item);
+        row_menu.show_all();
 
-impl DiffChunkView {
-    pub fn new(parent: Weak<DiffFileView>, context: DiffContext, patch: &git2::Patch, header: &str, hunk_idx: usize) -> Rc<DiffChunkView> {
         let root = gtk::Box::new(gtk::Orientation::Vertical, 0);
-        root.get_style_context().add_class("tree-border");
+        root.pack_start(&toolbar, false, false, 0);
+        root.pack_start(&search_bar, false, false, 0);
+        root.pack_start(&scroller, true, true, 0);
+
+        let view = view!(DiffView {
+            presenter: DiffPresenter::new(parent),
+            whitespace_combo,
+            context_spin,
+            algorithm_combo,
+            renames_toggle,
+            rename_spin,
+            copies_toggle,
+            copy_spin,
+            split_toggle,
+            markers_toggle,
+            max_lines_spin,
+            max_size_spin,
+            primary_button,
+            apply_box,
+            apply_target_combo,
+            apply_button,
+            is_syncing_settings: Cell::new(false),
+            layout: Cell::new(DiffLayout::Unified),
+            diff: RefCell::new((vec![], DiffContext::Committed)),
+            spans: RefCell::new(HashMap::new()),
+            images: RefCell::new(HashMap::new()),
+            requested: RefCell::new(HashSet::new()),
+            forced: RefCell::new(HashSet::new()),
+            file_lines: RefCell::new(HashMap::new()),
+            expanded: RefCell::new(HashMap::new()),
+            collapsed: RefCell::new(HashSet::new()),
+            search_bar,
+            search_entry,
+            regex_toggle,
+            case_toggle,
+            search_status,
+            matches: RefCell::new(vec![]),
+            current_match: Cell::new(None),
+            row_menu,
+            copy_hunk_item,
+            copy_lines_item,
+            copy_file_item,
+            menu_row: Cell::new(None),
+            tree,
+            root
+        });
 
-        let header_box = gtk::Box::new(gtk::Orientation::Horizontal, 0);
-        header_box.get_style_context().add_class("diff-header");
+        view.whitespace_combo.connect_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_settings_changed();
+        }));
 
-        let label = gtk::Label::new(header.trim());
-        label.set_xalign(0.0);
-        label.set_hexpand(true);
-        label.get_style_context().add_class("diff-label");
-        label.set_ellipsize(pango::EllipsizeMode::Middle);
-        
-        let button = gtk::Button::new_with_label("");
-        button.get_style_context().add_class("small-button");
+        view.context_spin.connect_value_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_settings_changed();
+        }));
+
+        view.algorithm_combo.connect_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_settings_changed();
+        }));
+
+        for toggle in &[&view.renames_toggle, &view.copies_toggle] {
+            toggle.connect_toggled(weak!(view => move |_| {
+                let view = try_upgrade!(view);
+                view.on_settings_changed();
+            }));
+        }
+
+        for spin in &[&view.rename_spin, &view.copy_spin] {
+            spin.connect_value_changed(weak!(view => move |_| {
+                let view = try_upgrade!(view);
+                view.on_settings_changed();
+            }));
+        }
 
-        header_box.add(&label);
-        header_box.add(&button);
+        for spin in &[&view.max_lines_spin, &view.max_size_spin] {
+            spin.connect_value_changed(weak!(view => move |_| {
+                let view = try_upgrade!(view);
+                view.on_limits_changed();
+            }));
+        }
 
-        root.add(&header_box);
+        if let Some(adjustment) = view.tree.get_vadjustment() {
+            adjustment.connect_value_changed(weak!(view => move |_| {
+                let view = try_upgrade!(view);
+                view.load_visible();
+            }));
 
-        let count_tree = gtk::TreeView::new();
-        count_tree.get_style_context().add_class("line-count");
-        count_tree.get_selection().set_mode(gtk::SelectionMode::None);
+            adjustment.connect_changed(weak!(view => move |_| {
+                let view = try_upgrade!(view);
+                view.load_visible();
+            }));
+        }
 
-        let lines_tree = gtk::TreeView::new();
-        lines_tree.get_style_context().add_class("monospace");
-        lines_tree.get_selection().set_mode(gtk::SelectionMode::Multiple);
+        collapse_button.connect_clicked(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.set_all_collapsed(true);
+        }));
 
-        let first_clicked: Arc<RwLock<Option<gtk::TreePath>>> = Arc::new(RwLock::new(None)); 
+        expand_button.connect_clicked(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.set_all_collapsed(false);
+        }));
 
-        lines_tree.connect_button_press_event(clone!(first_clicked => move |tree, event| {
-            let (x, y) = event.get_position();
-            let res = match tree.get_path_at_pos(x as i32, y as i32) {
-                Some(v) => v,
-                None => { return gtk::Inhibit(false); }
-            };
-            let path = match res.0 {
-                Some(v) => v,
-                None => { return gtk::Inhibit(false); }
-            };
+        // The tree keeps track of which files are open, however they were toggled.
+        view.tree.connect_row_collapsed(weak!(view => move |_, _, path| {
+            let view = try_upgrade!(view);
+            if let [f] = path.get_indices()[..] {
+                view.collapsed.borrow_mut().insert(f as usize);
+            }
+        }));
 
-            let mut guard = first_clicked.write().unwrap();
-            *guard = Some(path);
-            gtk::Inhibit(false)
+        view.tree.connect_row_expanded(weak!(view => move |_, _, path| {
+            let view = try_upgrade!(view);
+            if let [f] = path.get_indices()[..] {
+                view.collapsed.borrow_mut().remove(&(f as usize));
+            }
         }));
 
-        lines_tree.connect_motion_notify_event(clone!(first_clicked => move |tree, event| {
-            let lock = first_clicked.read().unwrap();
-            
-            let tree_path = match &*lock {
-                Some(v) => v,
-                None => {
-                    return gtk::Inhibit(false);
-                }
-            };
+        view.tree.connect_key_press_event(weak!(view => move |_, event| {
+            let view = try_upgrade!(view, gtk::Inhibit(false));
 
-            let (x, y) = event.get_position();
-            let res = match tree.get_path_at_pos(x as i32, y as i32) {
-                Some(v) => v,
-                None => { return gtk::Inhibit(false); }
-            };
-            let new_path = match res.0 {
-                Some(v) => v,
-                None => { return gtk::Inhibit(false); }
+            let (kind, is_forward) = match event.get_keyval() {
+                key::n => (RowKind::Hunk, true),
+                key::p => (RowKind::Hunk, false),
+                key::N => (RowKind::File, true),
+                key::P => (RowKind::File, false),
+                _ => return gtk::Inhibit(false)
             };
 
-            let selection = tree.get_selection();
-            selection.unselect_all();
-            selection.select_range(&tree_path, &new_path);
-            gtk::Inhibit(false)
+            view.jump(kind, is_forward);
+            gtk::Inhibit(true)
         }));
 
-        lines_tree.connect_button_release_event(clone!(first_clicked => move |_, _| {
-            let mut guard = first_clicked.write().unwrap();
-            *guard = None;
+        view.root.connect_key_press_event(weak!(view => move |_, event| {
+            let view = try_upgrade!(view, gtk::Inhibit(false));
+            let is_control = event.get_state().contains(gdk::ModifierType::CONTROL_MASK);
 
-            gtk::Inhibit(false)
+            if !is_control || (event.get_keyval() != key::f && event.get_keyval() != key::F) {
+                return gtk::Inhibit(false);
+            }
+
+            view.search_bar.set_search_mode(true);
+            view.search_entry.grab_focus();
+            view.search_entry.select_region(0, -1);
+            gtk::Inhibit(true)
         }));
 
-        let list_store = gtk::ListStore::new(&[
-            gdk::RGBA::static_type(),
-            String::static_type(),
-            String::static_type(),
-            String::static_type(),
-            String::static_type(),
-            gdk::RGBA::static_type()
-        ]);
+        view.search_entry.connect_search_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_search_changed();
+        }));
 
-        let trees_box = gtk::Box::new(gtk::Orientation::Horizontal, 0);
-        trees_box.add(&count_tree);
+        for toggle in &[&view.regex_toggle, &view.case_toggle] {
+            toggle.connect_toggled(weak!(view => move |_| {
+                let view = try_upgrade!(view);
+                view.on_search_changed();
+            }));
+        }
 
-        let scroller = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
-        scroller.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Never);
-        scroller.add(&lines_tree);
-        scroller.set_hexpand(true);
-        trees_box.add(&scroller);
+        view.search_entry.connect_activate(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.go_to_match(true);
+        }));
+
+        view.search_entry.connect_next_match(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.go_to_match(true);
+        }));
 
-        root.add(&trees_box);
+        view.search_entry.connect_previous_match(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.go_to_match(false);
+        }));
 
-        fn append_column(tree: &gtk::TreeView, id: i32, title: &str, is_colored: bool) -> gtk::TreeViewColumn {
-            let column = gtk::TreeViewColumn::new();
-            let cell = gtk::CellRendererText::new();
+        // Shift+Enter goes back, like Enter goes forward.
+        view.search_entry.connect_key_press_event(weak!(view => move |_, event| {
+            let view = try_upgrade!(view, gtk::Inhibit(false));
+            let is_shift = event.get_state().contains(gdk::ModifierType::SHIFT_MASK);
 
-            if id <= 2 {
-                cell.set_alignment(0.5, 0.5);
+            match event.get_keyval() {
+                key::Return | key::KP_Enter if is_shift => {
+                    view.go_to_match(false);
+                    gtk::Inhibit(true)
+                }
+                _ => gtk::Inhibit(false)
             }
+        }));
 
-            column.pack_start(&cell, true);
-            column.set_resizable(true);
-            column.set_title(title);
-            column.add_attribute(&cell, "text", id);
-            if is_colored {
-                column.add_attribute(&cell, "background-rgba", 0);
-            } else {
-                column.add_attribute(&cell, "background-rgba", 5);
-                column.set_min_width(24);
+        next_button.connect_clicked(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.go_to_match(true);
+        }));
+
+        previous_button.connect_clicked(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.go_to_match(false);
+        }));
+
+        // Closing the bar clears the highlighting and hands the keys back to the diff.
+        view.search_bar.connect_property_search_mode_enabled_notify(weak!(view => move |bar| {
+            let view = try_upgrade!(view);
+            view.search();
+
+            if !bar.get_search_mode() {
+                view.tree.grab_focus();
             }
-            tree.append_column(&column);
+        }));
 
-            column
-        }
-        
-        let column = gtk::TreeViewColumn::new();
-        let cell = gtk::CellRendererText::new();
-        column.pack_start(&cell, true);
-        column.set_visible(false);
-        
-        append_column(&count_tree, 1, "Old", false);
-        append_column(&count_tree, 2, "New", false);
-        append_column(&lines_tree, 3, "Origin", true);
-        append_column(&lines_tree, 4, "Line", true);
+        view.markers_toggle.connect_toggled(weak!(view => move |toggle| {
+            let view = try_upgrade!(view);
 
-        count_tree.set_headers_visible(false);
-        lines_tree.set_headers_visible(false);
-        
-        count_tree.set_model(&list_store);
-        lines_tree.set_model(&list_store);
-
-        for i in 0..patch.num_lines_in_hunk(hunk_idx).unwrap() {
-            let line = patch.line_in_hunk(hunk_idx, i).unwrap();
-
-            let rgba = match line.origin() {
-                '<' | '+' => gdk::RGBA {
-                    red: 0.851,
-                    green: 0.925,
-                    blue: 0.812,
-                    alpha: 1.0
-                },
-                '>' | '-' => gdk::RGBA {
-                    red: 0.918,
-                    green: 0.835,
-                    blue: 0.835,
-                    alpha: 1.0
-                },
-                _ => gdk::RGBA::white()
-            };
+            if !view.is_syncing_settings.get() {
+                view.presenter.on_show_whitespace_changed(toggle.get_active());
+                view.tree.queue_draw();
+            }
+        }));
 
-            (&list_store).insert_with_values(None, &[0, 1, 2, 3, 4, 5], &[
-                &rgba,
-                &line.old_lineno().map(|x| x.to_string()).unwrap_or("".to_string()),
-                &line.new_lineno().map(|x| x.to_string()).unwrap_or("".to_string()),
-                &line.origin_human().to_string(),
-                &line.content_str().unwrap_or("<unknown>"),
-                &gdk::RGBA { red: 0.95, green: 0.95, blue: 0.95, alpha: 1.0 }
-            ]);
-        }
-        
-        root.show_all();
-
-        let presenter = DiffChunkPresenter::new(context);
-
-        let view = view!(DiffChunkView {
-            presenter,
-            list_store,
-            label,
-            primary_button: button,
-            count_tree,
-            lines_tree,
-            root,
-            parent
-        });
+        view.split_toggle.connect_toggled(weak!(view => move |toggle| {
+            let view = try_upgrade!(view);
+            let layout = if toggle.get_active() { DiffLayout::Split } else { DiffLayout::Unified };
+            view.layout.set(layout);
+            DiffView::render(&view);
+        }));
 
-        view.lines_tree.connect_focus_out_event(weak!(view => move |_, _| {
+        view.tree.connect_focus_out_event(weak!(view => move |_, _| {
             let view = try_upgrade!(view, gtk::Inhibit(false));
 
             gtk::idle_add(weak!(view => move || {
                 let view = try_upgrade!(view, gtk::Continue(false));
-                let tree = &view.lines_tree;
+                let tree = &view.tree;
 
                 let top_level = match tree.get_toplevel() {
                     Some(v) => v,
@@ -517,49 +742,1480 @@ impl DiffChunkView {
                     }
                 };
 
-                let focused_widget = match window.get_focus() {
-                    Some(v) => v,
-                    None => {
-                        tree.get_selection().unselect_all();
-                        return gtk::Continue(false);
-                    }
-                };
+                let is_focused = window.get_focus()
+                    .map(|x| x.is_ancestor(view.widget()))
+                    .unwrap_or(false);
 
-                if !focused_widget.is_ancestor(view.widget()) {
+                if !is_focused {
                     tree.get_selection().unselect_all();
                 }
 
                 gtk::Continue(false)
             }));
-            
+
             gtk::Inhibit(false)
         }));
 
-        view.lines_tree.get_selection().connect_changed(weak!(view => move |selection| {
-            // println!("Selection changed");
+        view.tree.get_selection().connect_changed(weak!(view => move |_| {
             let view = try_upgrade!(view);
+            view.on_selection_changed();
+        }));
 
-            // println!("View lives");
+        view.tree.connect_row_activated(weak!(view => move |tree, path, _| {
+            let view = try_upgrade!(view);
+            let model = try_unwrap!(tree.get_model());
+            let iter = try_unwrap!(model.get_iter(path));
+            view.on_row_activated(Row::get(&model, &iter));
+        }));
 
-            let rows: Vec<_> = selection.get_selected_rows().0
-                .into_iter()
-                .map(|x| x.get_indices()[0] as usize)
-                .collect();
-                
-            view.on_selected_lines(&rows);
+        // Opening or closing a file, or loading one or more of its lines takes a single
+        // click, like a button would.
+        view.tree.connect_button_release_event(weak!(view => move |tree, event| {
+            let view = try_upgrade!(view, gtk::Inhibit(false));
+            if event.get_button() != 1 {
+                return gtk::Inhibit(false);
+            }
+
+            let (x, y) = event.get_position();
+            let path = try_unwrap!(tree.get_path_at_pos(x as i32, y as i32).and_then(|x| x.0), gtk::Inhibit(false));
+            let model = try_unwrap!(tree.get_model(), gtk::Inhibit(false));
+            let iter = try_unwrap!(model.get_iter(&path), gtk::Inhibit(false));
+            let row = Row::get(&model, &iter);
+
+            match row.kind {
+                RowKind::File => view.toggle_file(row.file),
+                RowKind::Placeholder => view.force_load(row.file),
+                RowKind::Expand => view.expand_gap(row.file, row.hunk),
+                _ => {}
+            }
+
+            gtk::Inhibit(false)
+        }));
+
+        view.tree.connect_button_press_event(weak!(view => move |_, event| {
+            let view = try_upgrade!(view, gtk::Inhibit(false));
+            gtk::Inhibit(view.on_button_pressed(event))
+        }));
+
+        view.copy_hunk_item.connect_activate(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            let row = try_unwrap!(view.menu_row.get());
+            view.copy_hunk(row.file, row.hunk);
+        }));
+
+        view.copy_lines_item.connect_activate(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.copy_selected_lines();
+        }));
+
+        view.copy_file_item.connect_activate(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            let row = try_unwrap!(view.menu_row.get());
+            view.copy_file(row.file);
         }));
 
         view.primary_button.connect_clicked(weak!(view => move |_| {
             let view = try_upgrade!(view);
-            view.on_primary_button_clicked();
+            view.presenter.on_primary_button_clicked();
         }));
 
-        view.presenter.start();
+        view.apply_button.connect_clicked(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            let target = match view.apply_target_combo.get_active_id().as_ref().map(|x| x.as_str()) {
+                Some("index") => ApplyTarget::Index,
+                Some("commits") => ApplyTarget::Commits,
+                _ => ApplyTarget::WorkingTree
+            };
+            view.presenter.on_apply_clicked(target);
+        }));
 
+        view.restore_settings();
         view
     }
 
-    pub fn widget(&self) -> &gtk::Box {
-        &self.root
+    pub fn restore_settings(&self) {
+        self.presenter.restore_settings();
+    }
+
+    fn settings(&self) -> DiffSettings {
+        let defaults = DiffSettings::default();
+
+        DiffSettings {
+            whitespace: self.whitespace_combo.get_active_id()
+                .and_then(|x| WhitespaceMode::from_str(&x))
+                .unwrap_or(defaults.whitespace),
+            context_lines: self.context_spin.get_value_as_int() as u32,
+            algorithm: self.algorithm_combo.get_active_id()
+                .and_then(|x| DiffAlgorithm::from_str(&x))
+                .unwrap_or(defaults.algorithm),
+            detect_renames: self.renames_toggle.get_active(),
+            rename_threshold: self.rename_spin.get_value_as_int() as u16,
+            detect_copies: self.copies_toggle.get_active(),
+            copy_threshold: self.copy_spin.get_value_as_int() as u16
+        }
+    }
+
+    fn on_settings_changed(&self) {
+        self.rename_spin.set_sensitive(self.renames_toggle.get_active());
+        self.copy_spin.set_sensitive(self.copies_toggle.get_active());
+
+        if self.is_syncing_settings.get() {
+            return;
+        }
+
+        self.presenter.on_settings_changed(self.settings());
+    }
+
+    fn on_limits_changed(&self) {
+        if self.is_syncing_settings.get() {
+            return;
+        }
+
+        self.presenter.on_limits_changed(DiffLimits {
+            max_lines: self.max_lines_spin.get_value_as_int() as usize,
+            max_bytes: self.max_size_spin.get_value_as_int() as usize * 1024
+        });
+
+        self.tree.queue_draw();
+        self.load_visible();
+    }
+
+    pub fn set_diff(view: &Rc<DiffView>, files: Vec<FileDiff>, context: DiffContext) {
+        // Nothing may be drawn from the old rows once the files they point into are gone.
+        view.tree.set_model(None::<&gtk::TreeStore>);
+        view.presenter.set_context(if files.is_empty() { None } else { Some(context) });
+        *view.diff.borrow_mut() = (files, context);
+        view.spans.borrow_mut().clear();
+        view.images.borrow_mut().clear();
+        view.requested.borrow_mut().clear();
+        view.forced.borrow_mut().clear();
+        view.file_lines.borrow_mut().clear();
+        view.expanded.borrow_mut().clear();
+        view.collapsed.borrow_mut().clear();
+        view.search();
+        DiffView::render(view);
+    }
+
+    /// Puts in the patches of files that were only listed before. The rest of the rows
+    /// are kept, so that scrolling and the selection stay as they are.
+    pub fn set_file_patches(&self, patches: Vec<(usize, FileDiff)>) {
+        for (f, file) in patches {
+            self.requested.borrow_mut().remove(&f);
+
+            {
+                let mut diff = self.diff.borrow_mut();
+                match diff.0.get_mut(f) {
+                    Some(v) if v.path() == file.path() => v.set_patch(file),
+                    _ => continue
+                }
+            }
+
+            self.spans.borrow_mut().retain(|key, _| key.0 != f);
+            self.images.borrow_mut().remove(&f);
+            self.refill_file(f);
+        }
+
+        self.search();
+        self.tree.queue_draw();
+    }
+
+    pub fn set_file_lines(&self, f: usize, id: git2::Oid, lines: Vec<String>) {
+        let language = match self.diff.borrow().0.get(f) {
+            Some(file) if file.old_id == id => Language::for_path(file.path()),
+            _ => return
+        };
+
+        let spans = language.map(|x| file_syntax_spans(&lines, x)).unwrap_or_default();
+        self.file_lines.borrow_mut().insert(f, Rc::new(FileLines { lines, spans }));
+        self.refill_file(f);
+    }
+
+    /// Replaces the rows below a file's header, leaving the other rows alone.
+    fn refill_file(&self, f: usize) {
+        let store = try_unwrap!(self.tree.get_model().and_then(|x| x.downcast::<gtk::TreeStore>().ok()));
+        let file_iter = try_unwrap!(store.iter_nth_child(None, f as i32));
+        let is_collapsed = self.collapsed.borrow().contains(&f);
+
+        while let Some(child) = store.iter_children(Some(&file_iter)) {
+            store.remove(&child);
+        }
+
+        let (ref files, _) = *self.diff.borrow();
+        self.insert_file_rows(&store, &file_iter, f, &files[f]);
+
+        if !is_collapsed {
+            self.tree.expand_row(&gtk::TreePath::new_from_indicesv(&[f as i32]), false);
+        }
+    }
+
+    fn toggle_file(&self, f: usize) {
+        let path = gtk::TreePath::new_from_indicesv(&[f as i32]);
+
+        if self.tree.row_expanded(&path) {
+            self.tree.collapse_row(&path);
+        } else {
+            self.tree.expand_row(&path, false);
+        }
+    }
+
+    fn set_all_collapsed(&self, is_collapsed: bool) {
+        if is_collapsed {
+            self.tree.collapse_all();
+        } else {
+            self.tree.expand_all();
+        }
+    }
+
+    /// Scrolls a file's header to the top, opening the file if it was collapsed.
+    pub fn scroll_to_file(&self, path: &str) {
+        let f = try_unwrap!(self.diff.borrow().0.iter().position(|x| x.path() == path));
+        let path = gtk::TreePath::new_from_indicesv(&[f as i32]);
+
+        self.tree.expand_row(&path, false);
+        self.tree.scroll_to_cell(Some(&path), None::<&gtk::TreeViewColumn>, true, 0.0, 0.0);
+    }
+
+    /// Scrolls to the next or previous file or hunk header after the first row in view.
+    /// Hunks of collapsed files are skipped.
+    fn jump(&self, kind: RowKind, is_forward: bool) {
+        let model = try_unwrap!(self.tree.get_model());
+        let top = self.tree.get_visible_range().map(|x| x.0.get_indices()).unwrap_or_default();
+        let mut targets = vec![];
+
+        let file_iter = try_unwrap!(model.iter_children(None));
+        loop {
+            let path = try_unwrap!(model.get_path(&file_iter));
+
+            if kind == RowKind::File {
+                targets.push(path);
+            } else if self.tree.row_expanded(&path) {
+                if let Some(child) = model.iter_children(Some(&file_iter)) {
+                    loop {
+                        if Row::get(&model, &child).kind == kind {
+                            targets.extend(model.get_path(&child));
+                        }
+
+                        if !model.iter_next(&child) {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if !model.iter_next(&file_iter) {
+                break;
+            }
+        }
+
+        let target = if is_forward {
+            targets.into_iter().find(|x| x.get_indices() > top)
+        } else {
+            targets.into_iter().rev().find(|x| x.get_indices() < top)
+        };
+
+        if let Some(path) = target {
+            self.tree.scroll_to_cell(Some(&path), None::<&gtk::TreeViewColumn>, true, 0.0, 0.0);
+        }
+    }
+
+    fn on_search_changed(&self) {
+        self.current_match.set(None);
+        self.search();
+        self.go_to_match(true);
+    }
+
+    /// Finds the search text in the lines of every loaded file. The current match is
+    /// kept if it is still found, so that files loading in the meantime do not move it.
+    fn search(&self) {
+        let current = self.current_match.get().and_then(|i| self.matches.borrow().get(i).cloned());
+        let text = self.search_entry.get_text().map(|x| x.to_string()).unwrap_or_default();
+        self.search_entry.get_style_context().remove_class("error");
+        self.matches.borrow_mut().clear();
+        self.current_match.set(None);
+
+        if text.is_empty() || !self.search_bar.get_search_mode() {
+            self.search_status.set_text("");
+            self.tree.queue_draw();
+            return;
+        }
+
+        let pattern = match Pattern::new(&text, self.regex_toggle.get_active(), self.case_toggle.get_active()) {
+            Ok(v) => v,
+            Err(err) => {
+                self.search_entry.get_style_context().add_class("error");
+                self.search_status.set_text(&err);
+                self.tree.queue_draw();
+                return;
+            }
+        };
+
+        {
+            let (ref files, _) = *self.diff.borrow();
+            let mut matches = self.matches.borrow_mut();
+
+            for (f, file) in files.iter().enumerate() {
+                for (h, hunk) in file.hunks.iter().enumerate() {
+                    for (i, line) in hunk.lines.iter().enumerate() {
+                        if let '=' | '<' | '>' = line.origin {
+                            continue;
+                        }
+
+                        let found = pattern.find_all(&line.content_str());
+                        matches.extend(found.into_iter().map(|range| SearchMatch { file: f, hunk: h, line: i, range }));
+                    }
+                }
+            }
+
+            self.current_match.set(current.and_then(|x| matches.iter().position(|m| *m == x)));
+        }
+
+        self.update_search_status();
+        self.tree.queue_draw();
+    }
+
+    fn update_search_status(&self) {
+        let count = self.matches.borrow().len();
+        let mut status = match (count, self.current_match.get()) {
+            (0, _) => "No matches".to_string(),
+            (_, Some(i)) => format!("{} of {}", i + 1, count),
+            (1, None) => "1 match".to_string(),
+            (_, None) => format!("{} matches", count)
+        };
+
+        let unloaded = self.diff.borrow().0.iter().filter(|x| !x.is_loaded).count();
+        if unloaded == 1 {
+            status.push_str(", 1 file not loaded");
+        } else if unloaded > 1 {
+            status.push_str(&format!(", {} files not loaded", unloaded));
+        }
+
+        self.search_status.set_text(&status);
+    }
+
+    /// Moves to the next or previous match, wrapping around at either end. Without a
+    /// current match, the search starts from the first row in view.
+    fn go_to_match(&self, is_forward: bool) {
+        let target = {
+            let matches = self.matches.borrow();
+            let count = matches.len();
+
+            if count == 0 {
+                return;
+            }
+
+            match self.current_match.get() {
+                Some(i) if is_forward => (i + 1) % count,
+                Some(i) => (i + count - 1) % count,
+                None => {
+                    let top = self.top_row().map(|x| (x.file, x.hunk)).unwrap_or((0, 0));
+
+                    if is_forward {
+                        matches.iter().position(|m| (m.file, m.hunk) >= top).unwrap_or(0)
+                    } else {
+                        matches.iter().rposition(|m| (m.file, m.hunk) < top).unwrap_or(count - 1)
+                    }
+                }
+            }
+        };
+
+        self.current_match.set(Some(target));
+        self.update_search_status();
+
+        let found = self.matches.borrow()[target].clone();
+        self.scroll_to_line(found.file, found.hunk, found.line);
+        self.tree.queue_draw();
+    }
+
+    fn top_row(&self) -> Option<Row> {
+        let model = self.tree.get_model()?;
+        let (start, _) = self.tree.get_visible_range()?;
+        let iter = model.get_iter(&start)?;
+        Some(Row::get(&model, &iter))
+    }
+
+    /// Centers a hunk's line in view, opening its file if it was collapsed.
+    fn scroll_to_line(&self, f: usize, h: usize, i: usize) {
+        let model = try_unwrap!(self.tree.get_model());
+        let file_iter = try_unwrap!(model.iter_nth_child(None, f as i32));
+        self.tree.expand_row(&gtk::TreePath::new_from_indicesv(&[f as i32]), false);
+
+        let child = try_unwrap!(model.iter_children(Some(&file_iter)));
+        loop {
+            let row = Row::get(&model, &child);
+
+            if row.kind == RowKind::Line && row.hunk == h && (row.old == Some(i) || row.new == Some(i)) {
+                let path = try_unwrap!(model.get_path(&child));
+                self.tree.scroll_to_cell(Some(&path), None::<&gtk::TreeViewColumn>, true, 0.5, 0.0);
+                return;
+            }
+
+            if !model.iter_next(&child) {
+                return;
+            }
+        }
+    }
+
+    /// The spans marking what the search found in a line, with the current match
+    /// standing out from the others.
+    fn match_spans(&self, f: usize, h: usize, i: usize) -> Vec<Span> {
+        let matches = self.matches.borrow();
+        let current = self.current_match.get();
+        let start = matches.partition_point(|m| (m.file, m.hunk, m.line) < (f, h, i));
+
+        matches[start..].iter()
+            .enumerate()
+            .take_while(|(_, m)| (m.file, m.hunk, m.line) == (f, h, i))
+            .map(|(n, m)| Span {
+                range: m.range.clone(),
+                style: if current == Some(start + n) { Style::CurrentMatch } else { Style::Match }
+            })
+            .collect()
+    }
+
+    /// Adds the rows below a file's header: a placeholder until its patch is loaded,
+    /// then its binary summary or its hunks, with the gaps between them.
+    fn insert_file_rows(&self, store: &gtk::TreeStore, file_iter: &gtk::TreeIter, f: usize, file: &FileDiff) {
+        if !file.is_loaded {
+            Row::new(RowKind::Placeholder, f, 0, None, None).insert(store, Some(file_iter));
+            return;
+        }
+
+        if let Some(ref binary) = file.binary {
+            Row::new(RowKind::Binary, f, 0, None, None).insert(store, Some(file_iter));
+
+            if binary.old_image.is_some() || binary.new_image.is_some() {
+                Row::new(RowKind::Image, f, 0, None, None).insert(store, Some(file_iter));
+            }
+        }
+
+        for (h, hunk) in file.hunks.iter().enumerate() {
+            self.insert_gap_rows(store, file_iter, f, file, h);
+            Row::new(RowKind::Hunk, f, h, None, None).insert(store, Some(file_iter));
+
+            let rows = match self.layout.get() {
+                DiffLayout::Unified => (0..hunk.lines.len()).map(|i| (Some(i), Some(i))).collect(),
+                DiffLayout::Split => split_rows(&hunk.lines)
+            };
+
+            for (old, new) in rows {
+                Row::new(RowKind::Line, f, h, old, new).insert(store, Some(file_iter));
+            }
+        }
+
+        if !file.hunks.is_empty() {
+            self.insert_gap_rows(store, file_iter, f, file, file.hunks.len());
+        }
+    }
+
+    /// Adds the unchanged lines shown so far before hunk `g`, or after the last hunk,
+    /// around a row for showing more of them.
+    fn insert_gap_rows(&self, store: &gtk::TreeStore, file_iter: &gtk::TreeIter, f: usize, file: &FileDiff, g: usize) {
+        if !has_old_side(file) {
+            return;
+        }
+
+        let total = self.file_lines.borrow().get(&f).map(|x| x.lines.len());
+        let range = match gap(&file.hunks, g, total) {
+            Some(v) => v,
+            None => {
+                Row::new(RowKind::Expand, f, g, None, None).insert(store, Some(file_iter));
+                return;
+            }
+        };
+
+        if range.start >= range.end {
+            return;
+        }
+
+        let (above, below) = self.expanded.borrow().get(&(f, g)).cloned().unwrap_or((0, 0));
+        let context = |lines: Range<usize>| for i in lines {
+            Row::new(RowKind::Context, f, g, Some(i), None).insert(store, Some(file_iter));
+        };
+
+        if total.is_none() {
+            Row::new(RowKind::Expand, f, g, None, None).insert(store, Some(file_iter));
+        } else if above + below >= range.len() {
+            context(range);
+        } else {
+            context(range.start..range.start + above);
+            Row::new(RowKind::Expand, f, g, None, None).insert(store, Some(file_iter));
+            context(range.end - below..range.end);
+        }
+    }
+
+    /// Shows more of the unchanged lines before hunk `g`, loading the file first if
+    /// needed. Gaps between hunks grow from both ends.
+    fn expand_gap(&self, f: usize, g: usize) {
+        let (ref files, _) = *self.diff.borrow();
+        let file = try_unwrap!(files.get(f));
+
+        {
+            let mut expanded = self.expanded.borrow_mut();
+            let (above, below) = expanded.entry((f, g)).or_insert((0, 0));
+
+            if g == 0 {
+                *below += EXPAND_STEP;
+            } else if g == file.hunks.len() {
+                *above += EXPAND_STEP;
+            } else {
+                *above += EXPAND_STEP;
+                *below += EXPAND_STEP;
+            }
+        }
+
+        if self.file_lines.borrow().contains_key(&f) {
+            self.refill_file(f);
+        } else {
+            self.presenter.load_file_lines(f, file);
+        }
+    }
+
+    fn is_auto_loaded(&self, file: &FileDiff) -> bool {
+        let limits = self.presenter.limits.get();
+
+        !file.is_generated &&
+            file.size <= limits.max_bytes &&
+            file.changed_lines.map(|x| x <= limits.max_lines).unwrap_or(true)
+    }
+
+    /// Asks for the patches of the files scrolled into view that are not loaded yet.
+    fn load_visible(&self) {
+        let (start, end) = match self.tree.get_visible_range() {
+            Some(v) => v,
+            None => return
+        };
+
+        let first = start.get_indices().first().cloned().unwrap_or(0).max(0) as usize;
+        let last = end.get_indices().first().cloned().unwrap_or(0).max(0) as usize;
+
+        let requests: Vec<PatchRequest> = {
+            let (ref files, _) = *self.diff.borrow();
+            let requested = self.requested.borrow();
+            let forced = self.forced.borrow();
+            let limits = self.presenter.limits.get();
+
+            let collapsed = self.collapsed.borrow();
+
+            (first..=last)
+                .filter(|f| !collapsed.contains(f))
+                .filter_map(|f| files.get(f).map(|file| (f, file)))
+                .filter(|(f, file)| !file.is_loaded && !requested.contains(f))
+                .filter(|(f, file)| forced.contains(f) || self.is_auto_loaded(file))
+                .map(|(f, file)| PatchRequest {
+                    index: f,
+                    old_path: file.old_path.clone(),
+                    new_path: file.new_path.clone(),
+                    max_lines: if forced.contains(&f) { None } else { Some(limits.max_lines) }
+                })
+                .collect()
+        };
+
+        self.requested.borrow_mut().extend(requests.iter().map(|x| x.index));
+        self.presenter.load_file_patches(requests);
+        self.tree.queue_draw();
+    }
+
+    fn force_load(&self, f: usize) {
+        self.forced.borrow_mut().insert(f);
+        self.load_visible();
+    }
+
+    /// Rebuilds the rows for the current layout. The rows only point into the files,
+    /// so this stays cheap however long the lines are.
+    fn render(view: &Rc<DiffView>) {
+        let store = gtk::TreeStore::new(&[i32::static_type(); 5]);
+        let layout = view.layout.get();
+
+        {
+            let (ref files, _) = *view.diff.borrow();
+
+            for (f, file) in files.iter().enumerate() {
+                let file_iter = Row::new(RowKind::File, f, 0, None, None).insert(&store, None);
+                view.insert_file_rows(&store, &file_iter, f, file);
+            }
+        }
+
+        for column in view.tree.get_columns() {
+            view.tree.remove_column(&column);
+        }
+
+        let cells = match layout {
+            DiffLayout::Unified => vec![
+                CellRole::Number(Side::Old),
+                CellRole::Number(Side::New),
+                CellRole::Origin(Side::Old),
+                CellRole::Content(Side::Old)
+            ],
+            DiffLayout::Split => vec![
+                CellRole::Number(Side::Old),
+                CellRole::Origin(Side::Old),
+                CellRole::Content(Side::Old),
+                CellRole::Number(Side::New),
+                CellRole::Origin(Side::New),
+                CellRole::Content(Side::New)
+            ]
+        };
+
+        for role in cells {
+            DiffView::append_column(view, role, layout);
+        }
+
+        let collapsed = view.collapsed.borrow().clone();
+        view.tree.set_model(Some(&store));
+        view.tree.expand_all();
+
+        for f in collapsed {
+            view.tree.collapse_row(&gtk::TreePath::new_from_indicesv(&[f as i32]));
+        }
+
+        view.on_selection_changed();
+        view.load_visible();
+    }
+
+    fn append_column(view: &Rc<DiffView>, role: CellRole, layout: DiffLayout) {
+        let column = gtk::TreeViewColumn::new();
+        let cell = gtk::CellRendererText::new();
+        column.pack_start(&cell, true);
+
+        match role {
+            CellRole::Number(_) => {
+                cell.set_alignment(0.5, 0.5);
+                cell.set_property_scale(0.8);
+                cell.set_property_foreground(Some("#999"));
+                column.set_min_width(24);
+            }
+            CellRole::Origin(_) => {}
+            CellRole::Content(_) => {
+                column.set_expand(true);
+
+                // The sides share the width, so long lines are cut short instead of
+                // pushing the new side out of view.
+                if layout == DiffLayout::Split {
+                    cell.set_property_ellipsize(pango::EllipsizeMode::End);
+                }
+            }
+        }
+
+        TreeViewColumnExt::set_cell_data_func(&column, &cell, Some(Box::new(weak!(view => move |_, renderer, model, iter| {
+            let view = try_upgrade!(view);
+            let cell = try_unwrap!(renderer.downcast_ref::<gtk::CellRendererText>());
+            let is_selected = view.tree.get_selection().iter_is_selected(iter);
+            view.render_cell(role, cell, &Row::get(model, iter), is_selected);
+        }))));
+
+        // Images are shown next to each other in the first content column.
+        if role == CellRole::Content(Side::Old) {
+            for side in &[Side::Old, Side::New] {
+                let side = *side;
+                let image_cell = gtk::CellRendererPixbuf::new();
+                image_cell.set_alignment(0.0, 0.0);
+                image_cell.set_padding(4, 4);
+                column.pack_start(&image_cell, false);
+
+                TreeViewColumnExt::set_cell_data_func(&column, &image_cell, Some(Box::new(weak!(view => move |_, renderer, model, iter| {
+                    let view = try_upgrade!(view);
+                    let row = Row::get(model, iter);
+                    let image = if row.kind == RowKind::Image { view.image(row.file, side) } else { None };
+                    renderer.set_visible(image.is_some());
+                    renderer.set_property("pixbuf", &image).unwrap_or(());
+                }))));
+            }
+        }
+
+        view.tree.append_column(&column);
+    }
+
+    /// Fills in a cell for the row being drawn.
+    fn render_cell(&self, role: CellRole, cell: &gtk::CellRendererText, row: &Row, is_selected: bool) {
+        let (ref files, _) = *self.diff.borrow();
+        let file = match files.get(row.file) {
+            Some(v) => v,
+            None => return
+        };
+
+        let (markup, background) = match row.kind {
+            RowKind::File => {
+                let markup = match role {
+                    CellRole::Content(Side::Old) => {
+                        let arrow = if self.collapsed.borrow().contains(&row.file) { "▸" } else { "▾" };
+                        let annotations = file.annotations().join(", ");
+                        let mut markup = format!("{} <b>{}</b>", arrow, markup_escape_text(&file.label()));
+
+                        if !annotations.is_empty() {
+                            markup.push_str(&format!("  <span foreground=\"#888\">{}</span>", markup_escape_text(&annotations)));
+                        }
+
+                        markup
+                    }
+                    _ => String::new()
+                };
+                (markup, FILE_COLOR)
+            }
+            RowKind::Hunk => {
+                let markup = match role {
+                    CellRole::Content(Side::Old) => {
+                        let header = file.hunks[row.hunk].header.trim();
+                        format!("<span foreground=\"#666\">{}</span>", markup_escape_text(header))
+                    }
+                    _ => String::new()
+                };
+                (markup, HUNK_COLOR)
+            }
+            RowKind::Binary => {
+                let markup = match (role, &file.binary) {
+                    (CellRole::Content(Side::Old), Some(binary)) => {
+                        let mut summary = binary::summary(binary);
+                        if binary.old_image.is_some() && binary.new_image.is_some() {
+                            summary.push_str(" (activate to compare the images)");
+                        }
+                        markup_escape_text(&summary).to_string()
+                    }
+                    _ => String::new()
+                };
+                (markup, gdk::RGBA::white())
+            }
+            RowKind::Image => (String::new(), gdk::RGBA::white()),
+            RowKind::Placeholder => {
+                let markup = match role {
+                    CellRole::Content(Side::Old) => self.placeholder_markup(row.file, file),
+                    _ => String::new()
+                };
+                (markup, gdk::RGBA::white())
+            }
+            RowKind::Expand => {
+                let markup = match role {
+                    CellRole::Content(Side::Old) => self.expand_markup(row.file, file, row.hunk),
+                    _ => String::new()
+                };
+                (markup, HUNK_COLOR)
+            }
+            RowKind::Context => {
+                let file_lines = self.file_lines.borrow().get(&row.file).cloned();
+                let i = row.old.unwrap_or(0);
+                let line = file_lines.as_ref().and_then(|x| x.lines.get(i));
+                let (text, ending) = split_ending(line.map(|x| x.as_str()).unwrap_or(""));
+                let offset = if row.hunk == 0 { 0 } else { file.hunks[row.hunk - 1].offset() };
+
+                let markup = match role {
+                    CellRole::Number(Side::Old) => (i + 1).to_string(),
+                    CellRole::Number(Side::New) => (i as i64 + 1 + offset).to_string(),
+                    CellRole::Origin(_) => String::new(),
+                    CellRole::Content(_) => {
+                        let spans = file_lines.as_ref().and_then(|x| x.spans.get(i)).map(|x| x.as_slice()).unwrap_or(&[]);
+
+                        if self.presenter.show_whitespace.get() {
+                            whitespace_markup(text, ending, spans, false)
+                        } else {
+                            highlight::to_markup(text, spans)
+                        }
+                    }
+                };
+                (markup, if let CellRole::Number(_) = role { COUNT_COLOR } else { gdk::RGBA::white() })
+            }
+            RowKind::Line => {
+                let hunk = &file.hunks[row.hunk];
+                let (side, index) = match role {
+                    CellRole::Number(Side::Old) if self.layout.get() == DiffLayout::Unified => (Side::Old, row.old),
+                    CellRole::Number(Side::New) if self.layout.get() == DiffLayout::Unified => (Side::New, row.old),
+                    CellRole::Number(side) | CellRole::Origin(side) | CellRole::Content(side) => {
+                        (side, if side == Side::Old { row.old } else { row.new })
+                    }
+                };
+
+                match index.map(|i| (i, &hunk.lines[i])) {
+                    Some((i, line)) => match role {
+                        CellRole::Number(_) => {
+                            let lineno = if side == Side::Old { line.old_lineno } else { line.new_lineno };
+                            (lineno.map(|x| x.to_string()).unwrap_or_default(), COUNT_COLOR)
+                        }
+                        CellRole::Origin(_) => (line.origin_human().to_string(), line_color(line.origin)),
+                        CellRole::Content(_) => {
+                            let mut spans = self.hunk_spans(file, row.file, row.hunk)[i].clone();
+                            spans.extend(self.match_spans(row.file, row.hunk, i));
+                            let show_whitespace = self.presenter.show_whitespace.get();
+                            (line_markup(line, &spans, show_whitespace), line_color(line.origin))
+                        }
+                    },
+                    None => (String::new(), EMPTY_COLOR)
+                }
+            }
+        };
+
+        cell.set_property_markup(Some(markup.as_str()));
+        // Selected lines are left uncolored so that the selection shows.
+        cell.set_property_cell_background_rgba(if is_selected { None } else { Some(&background) });
+    }
+
+    /// Explains why a file is not shown yet, or that it is on its way.
+    fn placeholder_markup(&self, f: usize, file: &FileDiff) -> String {
+        if self.requested.borrow().contains(&f) || self.is_auto_loaded(file) {
+            return "<span foreground=\"#999\" style=\"italic\">Loading diff…</span>".to_string();
+        }
+
+        let limits = self.presenter.limits.get();
+        let reason = match file.changed_lines {
+            Some(n) if n > limits.max_lines => format!("{} changed lines", n),
+            _ if file.is_generated => "generated file".to_string(),
+            _ => format!("large file, {}", binary::format_size(file.size))
+        };
+
+        format!("<span foreground=\"#3465a4\" underline=\"single\">Load diff</span> <span foreground=\"#999\">({})</span>",
+            markup_escape_text(&reason))
+    }
+
+    fn expand_markup(&self, f: usize, file: &FileDiff, g: usize) -> String {
+        let total = self.file_lines.borrow().get(&f).map(|x| x.lines.len());
+        let (above, below) = self.expanded.borrow().get(&(f, g)).cloned().unwrap_or((0, 0));
+        let step = if g == 0 || g == file.hunks.len() { EXPAND_STEP } else { EXPAND_STEP * 2 };
+
+        let text = match gap(&file.hunks, g, total) {
+            Some(range) => {
+                let hidden = range.len().saturating_sub(above + below);
+                if hidden <= step {
+                    format!("⋯ Show {} hidden lines", hidden)
+                } else {
+                    format!("⋯ Show {} of {} hidden lines", step, hidden)
+                }
+            }
+            None => "⋯ Show more lines".to_string()
+        };
+
+        format!("<span foreground=\"#3465a4\">{}</span>", text)
+    }
+
+    fn hunk_spans(&self, file: &FileDiff, f: usize, h: usize) -> Rc<HunkSpans> {
+        self.spans.borrow_mut()
+            .entry((f, h))
+            .or_insert_with(|| {
+                let language = Language::for_path(file.path());
+                Rc::new(line_spans(&file.hunks[h].lines, language))
+            })
+            .clone()
+    }
+
+    fn image(&self, f: usize, side: Side) -> Option<Pixbuf> {
+        let mut images = self.images.borrow_mut();
+        let (old, new) = images.entry(f).or_insert_with(|| {
+            let (ref files, _) = *self.diff.borrow();
+            let binary = files.get(f).and_then(|x| x.binary.as_ref());
+            let load = |data: Option<&Vec<u8>>| data
+                .and_then(|x| binary::load_image(x))
+                .map(|x| binary::thumbnail(&x));
+
+            (load(binary.and_then(|x| x.old_image.as_ref())), load(binary.and_then(|x| x.new_image.as_ref())))
+        });
+
+        match side {
+            Side::Old => old.clone(),
+            Side::New => new.clone()
+        }
+    }
+
+    fn selected_rows(&self) -> Vec<Row> {
+        let (paths, model) = self.tree.get_selection().get_selected_rows();
+
+        paths.iter()
+            .filter_map(|x| model.get_iter(x))
+            .map(|x| Row::get(&model, &x))
+            .collect()
+    }
+
+    /// The selected lines as file, hunk and line indices, in order.
+    fn selected_lines(&self) -> Vec<(usize, usize, usize)> {
+        let mut lines: Vec<(usize, usize, usize)> = self.selected_rows()
+            .into_iter()
+            .flat_map(|row| row.old.into_iter().chain(row.new).map(move |i| (row.file, row.hunk, i)))
+            .collect();
+        lines.sort();
+        lines.dedup();
+        lines
+    }
+
+    fn on_selection_changed(&self) {
+        self.presenter.on_selected_lines(&self.selected_lines());
+    }
+
+    /// Shows the copy menu for the row under the pointer on a right click.
+    fn on_button_pressed(&self, event: &gdk::EventButton) -> bool {
+        if event.get_event_type() != gdk::EventType::ButtonPress || event.get_button() != 3 {
+            return false;
+        }
+
+        let (x, y) = event.get_position();
+        let path = match self.tree.get_path_at_pos(x as i32, y as i32).and_then(|x| x.0) {
+            Some(v) => v,
+            None => return false
+        };
+        let model = try_unwrap!(self.tree.get_model(), false);
+        let iter = try_unwrap!(model.get_iter(&path), false);
+        let row = Row::get(&model, &iter);
+
+        let is_loaded = self.diff.borrow().0.get(row.file).map(|x| x.is_loaded).unwrap_or(false);
+        let is_in_hunk = matches!(row.kind, RowKind::Hunk | RowKind::Line);
+
+        self.copy_hunk_item.set_sensitive(is_loaded && is_in_hunk);
+        self.copy_lines_item.set_sensitive(!self.selected_lines().is_empty());
+        self.copy_file_item.set_sensitive(is_loaded);
+        self.menu_row.set(Some(row));
+        self.row_menu.popup_at_pointer(Some(&**event));
+
+        true
+    }
+
+    fn copy_to_clipboard(&self, text: &str) {
+        gtk::Clipboard::get(&gdk::SELECTION_CLIPBOARD).set_text(text);
+    }
+
+    fn copy_hunk(&self, f: usize, h: usize) {
+        let (ref files, _) = *self.diff.borrow();
+        let file = try_unwrap!(files.get(f));
+
+        if h < file.hunks.len() {
+            self.copy_to_clipboard(&patch::file_patch(file, &file.hunks[h..=h]));
+        }
+    }
+
+    fn copy_file(&self, f: usize) {
+        let (ref files, _) = *self.diff.borrow();
+        let file = try_unwrap!(files.get(f));
+        self.copy_to_clipboard(&patch::file_patch(file, &file.hunks));
+    }
+
+    /// Copies a patch of only the selected changes, which may span several hunks
+    /// and files.
+    fn copy_selected_lines(&self) {
+        let lines = self.selected_lines();
+        let (ref files, _) = *self.diff.borrow();
+        let mut text = String::new();
+
+        let mut selected_files: Vec<usize> = lines.iter().map(|x| x.0).collect();
+        selected_files.dedup();
+
+        for f in selected_files {
+            let file = try_unwrap!(files.get(f));
+            let hunks: Vec<DiffHunk> = file.hunks.iter()
+                .enumerate()
+                .filter_map(|(h, hunk)| {
+                    let chosen: HashSet<usize> = lines.iter()
+                        .filter(|x| x.0 == f && x.1 == h)
+                        .map(|x| x.2)
+                        .collect();
+                    patch::select_lines(hunk, &chosen)
+                })
+                .collect();
+
+            if !hunks.is_empty() {
+                text.push_str(&patch::file_patch(file, &hunks));
+            }
+        }
+
+        if !text.is_empty() {
+            self.copy_to_clipboard(&text);
+        }
+    }
+
+    fn on_row_activated(&self, row: Row) {
+        match row.kind {
+            RowKind::Placeholder => return self.force_load(row.file),
+            RowKind::Expand => return self.expand_gap(row.file, row.hunk),
+            _ => {}
+        }
+
+        if row.kind != RowKind::Binary && row.kind != RowKind::Image {
+            return;
+        }
+
+        let (ref files, _) = *self.diff.borrow();
+        let file = try_unwrap!(files.get(row.file));
+        let binary = try_unwrap!(file.binary.as_ref());
+
+        if binary.old_image.is_none() && binary.new_image.is_none() {
+            return;
+        }
+
+        let window = gtk::Window::new(gtk::WindowType::Toplevel);
+        window.set_title(file.path());
+        window.set_default_size(1000, 700);
+
+        if let Some(parent) = self.root.get_toplevel().and_then(|x| x.downcast::<gtk::Window>().ok()) {
+            window.set_transient_for(Some(&parent));
+        }
+
+        let scroller = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+        scroller.add(BinaryDiffView::new(binary).widget());
+        window.add(&scroller);
+        window.show_all();
+    }
+
+    pub fn widget(&self) -> &gtk::Box {
+        &self.root
+    }
+}
+
+/// Lets a range of lines be selected by dragging across them.
+fn connect_drag_selection(tree: &gtk::TreeView) {
+    let first_clicked: Rc<RefCell<Option<gtk::TreePath>>> = Rc::new(RefCell::new(None));
+
+    tree.connect_button_press_event(clone!(first_clicked => move |tree, event| {
+        let (x, y) = event.get_position();
+        let res = match tree.get_path_at_pos(x as i32, y as i32) {
+            Some(v) => v,
+            None => { return gtk::Inhibit(false); }
+        };
+        let path = match res.0 {
+            Some(v) => v,
+            None => { return gtk::Inhibit(false); }
+        };
+
+        *first_clicked.borrow_mut() = Some(path);
+        gtk::Inhibit(false)
+    }));
+
+    tree.connect_motion_notify_event(clone!(first_clicked => move |tree, event| {
+        let first = first_clicked.borrow();
+
+        let tree_path = match &*first {
+            Some(v) => v,
+            None => {
+                return gtk::Inhibit(false);
+            }
+        };
+
+        let (x, y) = event.get_position();
+        let res = match tree.get_path_at_pos(x as i32, y as i32) {
+            Some(v) => v,
+            None => { return gtk::Inhibit(false); }
+        };
+        let new_path = match res.0 {
+            Some(v) => v,
+            None => { return gtk::Inhibit(false); }
+        };
+
+        let selection = tree.get_selection();
+        selection.unselect_all();
+        selection.select_range(tree_path, &new_path);
+        gtk::Inhibit(false)
+    }));
+
+    tree.connect_button_release_event(clone!(first_clicked => move |_, _| {
+        *first_clicked.borrow_mut() = None;
+        gtk::Inhibit(false)
+    }));
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DiffLayout {
+    Unified,
+    /// Removed lines on the left and added lines on the right.
+    Split
+}
+
+/// Files past either limit are collapsed until asked for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DiffLimits {
+    pub max_lines: usize,
+    pub max_bytes: usize
+}
+
+impl Default for DiffLimits {
+    fn default() -> DiffLimits {
+        DiffLimits {
+            max_lines: 2000,
+            max_bytes: 512 * 1024
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DiffContext {
+    Committed,
+    Staged,
+    /// Two arbitrary commits or refs, where there is nothing to act on.
+    Comparison,
+    /// A patch read from a file or the clipboard, which can only be applied as a whole.
+    Patch
+}
+
+/// A piece of a hunk's line found by searching the diff, as a byte range of its text.
+#[derive(Clone, Debug, PartialEq)]
+struct SearchMatch {
+    file: usize,
+    hunk: usize,
+    line: usize,
+    range: Range<usize>
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Side {
+    Old,
+    New
+}
+
+/// What a column of the diff shows. In the unified layout there is a single origin and
+/// content column, which use the old side.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CellRole {
+    Number(Side),
+    Origin(Side),
+    Content(Side)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RowKind {
+    File,
+    Hunk,
+    Line,
+    Binary,
+    Image,
+    /// Stands in for the patch of a file that is not loaded.
+    Placeholder,
+    /// Shows more of the unchanged lines hidden before a hunk, or after the last one.
+    Expand,
+    /// An unchanged line from outside the hunks, by its index in the old side.
+    Context
+}
+
+/// A row of the diff's tree store, which only refers to a file, hunk and the lines
+/// shown on either side of it. Both sides are the same line in the unified layout.
+#[derive(Copy, Clone, Debug)]
+struct Row {
+    kind: RowKind,
+    file: usize,
+    hunk: usize,
+    old: Option<usize>,
+    new: Option<usize>
+}
+
+impl Row {
+    fn new(kind: RowKind, file: usize, hunk: usize, old: Option<usize>, new: Option<usize>) -> Row {
+        Row { kind, file, hunk, old, new }
+    }
+
+    fn get<M: IsA<gtk::TreeModel>>(model: &M, iter: &gtk::TreeIter) -> Row {
+        let value = |column| model.get_value(iter, column).get::<i32>().unwrap_or(-1);
+        let index = |column| {
+            let v = value(column);
+            if v < 0 { None } else { Some(v as usize) }
+        };
+
+        let kind = match value(0) {
+            0 => RowKind::File,
+            1 => RowKind::Hunk,
+            3 => RowKind::Binary,
+            4 => RowKind::Image,
+            5 => RowKind::Placeholder,
+            6 => RowKind::Expand,
+            7 => RowKind::Context,
+            _ => RowKind::Line
+        };
+
+        Row {
+            kind,
+            file: index(1).unwrap_or(0),
+            hunk: index(2).unwrap_or(0),
+            old: index(3),
+            new: index(4)
+        }
+    }
+
+    fn insert(&self, store: &gtk::TreeStore, parent: Option<&gtk::TreeIter>) -> gtk::TreeIter {
+        let kind = match self.kind {
+            RowKind::File => 0,
+            RowKind::Hunk => 1,
+            RowKind::Line => 2,
+            RowKind::Binary => 3,
+            RowKind::Image => 4,
+            RowKind::Placeholder => 5,
+            RowKind::Expand => 6,
+            RowKind::Context => 7
+        };
+        let index = |x: Option<usize>| x.map(|x| x as i32).unwrap_or(-1);
+
+        store.insert_with_values(parent, None, &[0, 1, 2, 3, 4], &[
+            &kind,
+            &(self.file as i32),
+            &(self.hunk as i32),
+            &index(self.old),
+            &index(self.new)
+        ])
+    }
+}
+
+trait HumanDiffLineExt {
+    fn origin_human(&self) -> char;
+    fn content_str(&self) -> Cow<'_, str>;
+}
+
+impl HumanDiffLineExt for DiffLine {
+    fn origin_human(&self) -> char {
+        let ch = self.origin;
+        match ch {
+            '=' | '>' | '<' => '\\',
+            _ => ch
+        }
+    }
+
+    /// Lines that are not valid UTF-8 are shown with the invalid bytes replaced.
+    fn content_str(&self) -> Cow<'_, str> {
+        let text = String::from_utf8_lossy(&self.content);
+
+        // Check for weird NL string
+        if text.trim_end().ends_with(NO_NL_STR) {
+            return Cow::Borrowed(NO_NL_STR);
+        }
+
+        match text {
+            Cow::Borrowed(v) => Cow::Borrowed(v.trim_end()),
+            Cow::Owned(v) => Cow::Owned(v.trim_end().to_string())
+        }
+    }
+}
+
+/// Files that were added have no unchanged lines, and deleted ones are removed whole.
+fn has_old_side(file: &FileDiff) -> bool {
+    !matches!(file.status, git2::Delta::Added | git2::Delta::Untracked | git2::Delta::Deleted)
+}
+
+/// The zero-based old lines hidden before hunk `g`, or after the last hunk when `g` is
+/// the number of hunks. The end of the file is only known once its lines are loaded.
+fn gap(hunks: &[DiffHunk], g: usize, total: Option<usize>) -> Option<Range<usize>> {
+    let start = if g == 0 { 0 } else { hunks[g - 1].old_end() as usize - 1 };
+    let end = match hunks.get(g) {
+        Some(hunk) => hunk.old_first() as usize - 1,
+        None => total?
+    };
+
+    Some(start..end.max(start))
+}
+
+/// Pairs up a hunk's lines for the split layout. Context lines appear on both sides,
+/// and each run of removals is lined up against the run of additions following it.
+fn split_rows(lines: &[DiffLine]) -> Vec<(Option<usize>, Option<usize>)> {
+    let mut rows = vec![];
+    let mut old = vec![];
+    let mut new = vec![];
+
+    fn flush(rows: &mut Vec<(Option<usize>, Option<usize>)>, old: &mut Vec<usize>, new: &mut Vec<usize>) {
+        let count = old.len().max(new.len());
+        for i in 0..count {
+            rows.push((old.get(i).cloned(), new.get(i).cloned()));
+        }
+        old.clear();
+        new.clear();
+    }
+
+    for (i, line) in lines.iter().enumerate() {
+        match line.origin {
+            '-' | '>' => {
+                if !new.is_empty() {
+                    flush(&mut rows, &mut old, &mut new);
+                }
+                old.push(i);
+            }
+            '+' | '<' => new.push(i),
+            _ => {
+                flush(&mut rows, &mut old, &mut new);
+                rows.push((Some(i), Some(i)));
+            }
+        }
+    }
+
+    flush(&mut rows, &mut old, &mut new);
+    rows
+}
+
+/// Colors the syntax of every line of a hunk. The old and new sides of the file are
+/// highlighted separately, so that a comment opened on one side does not leak into the
+/// other; context lines belong to both.
+fn syntax_spans(lines: &[DiffLine], language: &Language) -> Vec<Vec<Span>> {
+    let mut old_state = syntax::State::default();
+    let mut new_state = syntax::State::default();
+
+    lines.iter()
+        .map(|line| {
+            if line.origin == '=' || line.origin == '<' || line.origin == '>' {
+                return vec![];
+            }
+
+            let text = &line.content_str();
+
+            match line.origin {
+                '-' => {
+                    let (spans, state) = language.highlight(text, old_state);
+                    old_state = state;
+                    spans
+                }
+                '+' => {
+                    let (spans, state) = language.highlight(text, new_state);
+                    new_state = state;
+                    spans
+                }
+                _ => {
+                    let (spans, state) = language.highlight(text, new_state);
+                    old_state = state;
+                    new_state = state;
+                    spans
+                }
+            }
+        })
+        .collect()
+}
+
+/// Colors the syntax of every line of a file from its start, so that lines inside a
+/// comment or string opened further up are colored as such.
+fn file_syntax_spans(lines: &[String], language: &Language) -> Vec<Vec<Span>> {
+    let mut state = syntax::State::default();
+
+    lines.iter()
+        .map(|line| {
+            let (spans, next) = language.highlight(split_ending(line).0, state);
+            state = next;
+            spans
+        })
+        .collect()
+}
+
+/// Returns the spans for every line of a hunk: its syntax, if the language is known,
+/// and the words that changed between each removed line and the added line it is
+/// paired with.
+fn line_spans(lines: &[DiffLine], language: Option<&Language>) -> Vec<Vec<Span>> {
+    let mut spans = match language {
+        Some(language) => syntax_spans(lines, language),
+        None => vec![vec![]; lines.len()]
+    };
+
+    for (old, new) in split_rows(lines) {
+        let (old, new) = match (old, new) {
+            (Some(old), Some(new)) if lines[old].origin == '-' && lines[new].origin == '+' => (old, new),
+            _ => continue
+        };
+
+        let old_text = lines[old].content_str();
+        let new_text = lines[new].content_str();
+
+        if let Some(changes) = highlight::word_changes(&old_text, &new_text) {
+            spans[old].extend(changes.old.into_iter().map(|range| Span { range, style: Style::Removed }));
+            spans[new].extend(changes.new.into_iter().map(|range| Span { range, style: Style::Added }));
+        }
+    }
+
+    spans
+}
+
+fn line_markup(line: &DiffLine, spans: &[Span], show_whitespace: bool) -> String {
+    if !show_whitespace {
+        return highlight::to_markup(&line.content_str(), spans);
+    }
+
+    if let '=' | '<' | '>' = line.origin {
+        let text = format!("⊘ {}", NO_NL_STR);
+        return highlight::to_markup(&text, &[Span { range: 0..text.len(), style: Style::Whitespace }]);
+    }
+
+    let raw = String::from_utf8_lossy(&line.content);
+    let (text, ending) = split_ending(&raw);
+    whitespace_markup(text, ending, spans, line.origin == '+')
+}
+
+/// Splits the line ending off a line, giving the markers that show it.
+fn split_ending(raw: &str) -> (&str, &'static str) {
+    if let Some(v) = raw.strip_suffix("\r\n") {
+        (v, "␍↵")
+    } else if let Some(v) = raw.strip_suffix('\n') {
+        (v, "↵")
+    } else {
+        (raw, "")
+    }
+}
+
+/// Marks up a line with its whitespace and line ending made visible.
+fn whitespace_markup(text: &str, ending: &str, spans: &[Span], is_added: bool) -> String {
+    let (mut shown, mut spans) = highlight::show_whitespace(text, spans, is_added);
+    spans.push(Span { range: shown.len()..shown.len() + ending.len(), style: Style::Whitespace });
+    shown.push_str(ending);
+
+    highlight::to_markup(&shown, &spans)
+}
+
+fn line_color(origin: char) -> gdk::RGBA {
+    match origin {
+        '<' | '+' => gdk::RGBA {
+            red: 0.851,
+            green: 0.925,
+            blue: 0.812,
+            alpha: 1.0
+        },
+        '>' | '-' => gdk::RGBA {
+            red: 0.918,
+            green: 0.835,
+            blue: 0.835,
+            alpha: 1.0
+        },
+        _ => gdk::RGBA::white()
+    }
+}
+
+const FILE_COLOR: gdk::RGBA = gdk::RGBA { red: 0.933, green: 0.933, blue: 0.933, alpha: 1.0 };
+const HUNK_COLOR: gdk::RGBA = gdk::RGBA { red: 0.965, green: 0.965, blue: 0.965, alpha: 1.0 };
+const COUNT_COLOR: gdk::RGBA = gdk::RGBA { red: 0.95, green: 0.95, blue: 0.95, alpha: 1.0 };
+const EMPTY_COLOR: gdk::RGBA = gdk::RGBA { red: 0.97, green: 0.97, blue: 0.97, alpha: 1.0 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(origins: &str) -> Vec<DiffLine> {
+        origins.chars()
+            .map(|origin| DiffLine { origin, old_lineno: None, new_lineno: None, content: b"x\n".to_vec() })
+            .collect()
+    }
+
+    #[test]
+    fn split_rows_pair_removals_with_the_additions_after_them() {
+        assert_eq!(split_rows(&lines(" --++ -+++")), vec![
+            (Some(0), Some(0)),
+            (Some(1), Some(3)),
+            (Some(2), Some(4)),
+            (Some(5), Some(5)),
+            (Some(6), Some(7)),
+            (None, Some(8)),
+            (None, Some(9))
+        ]);
+    }
+
+    #[test]
+    fn split_rows_keep_additions_before_removals_apart() {
+        assert_eq!(split_rows(&lines("+-")), vec![(None, Some(0)), (Some(1), None)]);
+        assert_eq!(split_rows(&lines("->+<")), vec![(Some(0), Some(2)), (Some(1), Some(3))]);
+    }
+
+    #[test]
+    fn gaps_lie_between_hunks_and_around_them() {
+        let hunk = |old_start, old_lines| DiffHunk { header: String::new(), old_start, old_lines, new_start: 0, new_lines: 0, lines: vec![] };
+        // Lines 5 to 7 changed, and lines inserted after line 20.
+        let hunks = vec![hunk(5, 3), hunk(20, 0)];
+
+        assert_eq!(gap(&hunks, 0, None), Some(0..4));
+        assert_eq!(gap(&hunks, 1, None), Some(7..20));
+        assert_eq!(gap(&hunks, 2, None), None);
+        assert_eq!(gap(&hunks, 2, Some(30)), Some(20..30));
+        assert_eq!(gap(&hunks, 2, Some(10)), Some(20..20));
+    }
+
+    #[test]
+    fn line_endings_are_split_off_with_their_markers() {
+        assert_eq!(split_ending("a\r\n"), ("a", "␍↵"));
+        assert_eq!(split_ending("a\n"), ("a", "↵"));
+        assert_eq!(split_ending("a\r"), ("a\r", ""));
+        assert_eq!(split_ending(""), ("", ""));
+    }
+
+    #[test]
+    fn file_spans_carry_comments_across_lines() {
+        let lines = vec!["/* a\n".to_string(), "b */\n".to_string(), "fn\n".to_string()];
+        let spans = file_syntax_spans(&lines, Language::for_path("a.rs").unwrap());
+        let styles: Vec<Vec<Style>> = spans.iter().map(|x| x.iter().map(|x| x.style).collect()).collect();
+
+        assert_eq!(styles, vec![vec![Style::Comment, Style::Comment], vec![Style::Comment], vec![Style::Keyword]]);
     }
 }
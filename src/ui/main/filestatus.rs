@@ -14,13 +14,15 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::rc::{Rc, Weak};
-use std::cell::RefCell;
-use std::path::Path;
+use std::cell::{Cell, RefCell};
 
 use git2;
 use gtk::prelude::*;
 use gtk;
+use glib::markup_escape_text;
 
+use crate::git::diff::{Comparison, DiffBase, DiffStats};
+use crate::git::history::{CommitDetails, CommitLink};
 use crate::ui::main::TreeItem;
 use super::branch::{BranchPresenter, BranchView};
 
@@ -29,7 +31,8 @@ pub trait FileStatusViewable {
     fn staged_view(&self) -> &FileListView;
     fn unstaged_view(&self) -> &FileListView;
     fn set_items(&self, staged: &[TreeItem], unstaged: &[TreeItem]);
-    fn show_commit(&self, statuses: &[TreeItem], commit: &git2::Commit);
+    fn show_commit(&self, statuses: &[TreeItem], commit: &CommitDetails);
+    fn show_comparison(&self, statuses: &[TreeItem], comparison: &Comparison);
 }
 
 pub struct FileStatusPresenter<V> {
@@ -52,11 +55,19 @@ impl<V: FileStatusViewable> FileStatusPresenter<V> {
     }
 
     pub fn set_history_statuses(&self, staged: &[TreeItem], unstaged: &[TreeItem]) {
-        self.view().set_items(&staged, &unstaged);
+        self.view().set_items(staged, unstaged);
     }
 
-    pub fn set_overview_statuses(&self, statuses: &[TreeItem], commit: &git2::Commit) {
-        self.view().show_commit(&statuses, commit);
+    pub fn set_overview_statuses(&self, statuses: &[TreeItem], commit: &CommitDetails) {
+        self.view().show_commit(statuses, commit);
+    }
+
+    pub fn set_comparison_statuses(&self, statuses: &[TreeItem], comparison: &Comparison) {
+        self.view().show_comparison(statuses, comparison);
+    }
+
+    fn on_swap_clicked(&self) {
+        self.parent().swap_comparison();
     }
 
     fn parent(&self) -> Rc<BranchPresenter<BranchView>> {
@@ -65,78 +76,221 @@ impl<V: FileStatusViewable> FileStatusPresenter<V> {
             .expect("Parent presenter to work")
     }
 
-    fn on_toggle_staged(&self, index: usize) {
-        let parent = self.parent();
-        let repo = parent.repo();
+    fn on_link_activated(&self, uri: &str) -> bool {
+        if !uri.starts_with(COMMIT_URI) {
+            return false;
+        }
 
-        {
-            let delta = &parent.deltas().borrow().0[index];
-            let head = repo.head().unwrap().peel(git2::ObjectType::Commit).unwrap();
-            repo.reset_default(Some(&head), Path::new(&delta.path)).unwrap();
+        match git2::Oid::from_str(&uri[COMMIT_URI.len()..]) {
+            Ok(id) => {
+                self.parent().select_commit(id);
+                true
+            }
+            Err(_) => false
         }
+    }
+
+    fn on_diff_base_changed(&self, id: git2::Oid, base: DiffBase) {
+        self.parent().show_commit_diff(id, base);
+    }
 
-        repo.index().unwrap().write().unwrap();
-        parent.on_uncommitted_changes_selected();
+    fn on_file_selected(&self, path: &str) {
+        self.parent().show_file_in_diff(path);
+    }
+
+    fn on_toggle_staged(&self, index: usize) {
+        let parent = self.parent();
+        let path = parent.deltas().borrow().0[index].path.clone();
+        parent.unstage_path(&path);
     }
 
     fn on_toggle_unstaged(&self, index: usize) {
         let parent = self.parent();
-        let repo = parent.repo();
+        let path = parent.deltas().borrow().1[index].path.clone();
+        parent.stage_path(&path);
+    }
+}
 
-        {
-            let delta = &parent.deltas().borrow().1[index];
-            repo.index().unwrap().add_path(&Path::new(&delta.path)).unwrap();
-        }
+/// Links in the overview that select a commit in the history.
+const COMMIT_URI: &str = "commit:";
 
-        repo.index().unwrap().write().unwrap();
-        parent.on_uncommitted_changes_selected();
-    }
+fn commit_links(links: &[CommitLink]) -> String {
+    links.iter()
+        .map(|x| format!("<a href=\"{}{}\">{}</a> {}", COMMIT_URI, x.id, x.short_id, markup_escape_text(&x.summary)))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
+fn format_stats(stats: &DiffStats) -> String {
+    format!(
+        "{} {}, <span foreground=\"#4e9a06\">+{}</span> <span foreground=\"#cc0000\">-{}</span>",
+        stats.files,
+        if stats.files == 1 { "file" } else { "files" },
+        stats.insertions,
+        stats.deletions
+    )
+}
+
+const CONFLICT_RESOLUTION_ID: &str = "resolution";
+
 pub struct OverviewView {
     label: gtk::Label,
-    root: gtk::ScrolledWindow
+    base_row: gtk::Box,
+    base_combo: gtk::ComboBoxText,
+    swap_button: gtk::Button,
+    commit_id: Cell<Option<git2::Oid>>,
+    is_syncing_base: Cell<bool>,
+    root: gtk::Box
 }
 
 impl OverviewView {
     fn new() -> OverviewView {
         let label = gtk::Label::new("");
         label.set_selectable(true);
+        label.set_line_wrap(true);
+        label.set_xalign(0.0);
+        label.set_yalign(0.0);
+        label.set_margin_start(6);
+        label.set_margin_end(6);
+        label.set_margin_top(6);
 
-        let root = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
-        root.get_style_context().add_class("white-background");
+        let scroller = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+        scroller.get_style_context().add_class("white-background");
         
-        root.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
-        root.add(&label);
+        scroller.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+        scroller.set_vexpand(true);
+        scroller.add(&label);
+
+        // Only shown for merges, which can be compared against any of their parents.
+        let base_label = gtk::Label::new("Compare with:");
+        let base_combo = gtk::ComboBoxText::new();
+        base_label.show();
+        base_combo.show();
+
+        let base_row = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        base_row.set_border_width(4);
+        base_row.set_no_show_all(true);
+        base_row.pack_start(&base_label, false, false, 0);
+        base_row.pack_start(&base_combo, false, false, 0);
+
+        // Only shown when comparing two commits or refs.
+        let swap_button = gtk::Button::new_with_label("Swap sides");
+        swap_button.set_no_show_all(true);
+        swap_button.set_halign(gtk::Align::Start);
+        swap_button.set_margin_start(4);
+        swap_button.set_margin_top(4);
+
+        let root = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        root.pack_start(&base_row, false, false, 0);
+        root.pack_start(&swap_button, false, false, 0);
+        root.pack_start(&scroller, true, true, 0);
 
         OverviewView {
             label,
+            base_row,
+            base_combo,
+            swap_button,
+            commit_id: Cell::new(None),
+            is_syncing_base: Cell::new(false),
             root
         }
     }
 
-    fn set_commit(&self, commit: &git2::Commit) {
-        use super::history::HumanCommitExt;
+    fn set_bases(&self, commit: &CommitDetails) {
+        self.is_syncing_base.set(true);
+        self.base_combo.remove_all();
 
-        let mut msg = String::new();
+        if commit.parents.len() > 1 {
+            for (n, parent) in commit.parents.iter().enumerate() {
+                self.base_combo.append(n.to_string().as_str(), &format!("Parent {} ({})", n + 1, parent.short_id));
+            }
 
-        msg.push_str("Commit: ");
-        msg.push_str(&commit.id_str());
-        msg.push('\n');
+            if commit.parents.len() == 2 {
+                self.base_combo.append(CONFLICT_RESOLUTION_ID, "Conflict resolution only");
+            }
 
-        msg.push_str("Author: ");
-        msg.push_str(&commit.author_str());
-        msg.push('\n');
+            let active = match commit.base {
+                DiffBase::Parent(n) => n.to_string(),
+                DiffBase::ConflictResolution => CONFLICT_RESOLUTION_ID.to_string()
+            };
+            self.base_combo.set_active_id(active.as_str());
+        }
+
+        self.base_row.set_visible(commit.parents.len() > 1);
+        self.is_syncing_base.set(false);
+    }
+
+    fn selected_base(&self) -> Option<DiffBase> {
+        let id = self.base_combo.get_active_id()?;
+
+        if id.as_str() == CONFLICT_RESOLUTION_ID {
+            return Some(DiffBase::ConflictResolution);
+        }
+
+        id.parse().ok().map(DiffBase::Parent)
+    }
+
+    fn set_comparison(&self, comparison: &Comparison) {
+        self.commit_id.set(None);
+        self.base_row.set_visible(false);
+        self.swap_button.set_visible(true);
+
+        let msg = format!(
+            "<b>From:</b> {}\n<b>To:</b> {}\n<b>Changes:</b> {}\n",
+            markup_escape_text(&comparison.from_label),
+            markup_escape_text(&comparison.to_label),
+            format_stats(&comparison.stats)
+        );
+
+        self.label.set_markup(&msg);
+    }
+
+    fn set_commit(&self, commit: &CommitDetails) {
+        self.commit_id.set(Some(commit.id));
+        self.swap_button.set_visible(false);
+        self.set_bases(commit);
+
+        let mut rows = vec![];
+
+        rows.push(("Commit", commit.id.to_string()));
+
+        if !commit.refs.is_empty() {
+            rows.push(("Refs", markup_escape_text(&commit.refs.join(", ")).to_string()));
+        }
+
+        if !commit.parents.is_empty() {
+            let title = if commit.parents.len() == 1 { "Parent" } else { "Parents" };
+            rows.push((title, commit_links(&commit.parents)));
+        }
+
+        if !commit.children.is_empty() {
+            let title = if commit.children.len() == 1 { "Child" } else { "Children" };
+            rows.push((title, commit_links(&commit.children)));
+        }
+
+        rows.push(("Author", markup_escape_text(&commit.author).to_string()));
+        rows.push(("Date", markup_escape_text(&commit.date).to_string()));
+
+        if let Some((ref committer, ref date)) = commit.committer {
+            rows.push(("Committer", markup_escape_text(committer).to_string()));
+            rows.push(("Commit date", markup_escape_text(date).to_string()));
+        }
+
+        rows.push(("Changes", format_stats(&commit.stats)));
+
+        let mut msg = String::new();
+
+        for (title, value) in rows {
+            msg.push_str(&format!("<b>{}:</b> {}\n", title, value.replace('\n', "\n    ")));
+        }
 
-        msg.push_str("Date: ");
-        msg.push_str(&commit.date().to_string());
-        msg.push('\n');
         msg.push('\n');
+        msg.push_str(&markup_escape_text(&commit.message));
 
-        self.label.set_label(&msg);
+        self.label.set_markup(&msg);
     }
 
-    fn widget(&self) -> &gtk::ScrolledWindow {
+    fn widget(&self) -> &gtk::Box {
         &self.root
     }
 }
@@ -165,6 +319,40 @@ impl FileStatusViewable for FileStatusView {
             root
         });
 
+        view.overview_view.label.connect_activate_link(weak!(view => move |_, uri| {
+            let view = try_upgrade!(view, Inhibit(false));
+            Inhibit(view.presenter.on_link_activated(uri))
+        }));
+
+        view.overview_view.swap_button.connect_clicked(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.presenter.on_swap_clicked();
+        }));
+
+        view.overview_view.base_combo.connect_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            let overview = &view.overview_view;
+
+            if overview.is_syncing_base.get() {
+                return;
+            }
+
+            if let (Some(id), Some(base)) = (overview.commit_id.get(), overview.selected_base()) {
+                view.presenter.on_diff_base_changed(id, base);
+            }
+        }));
+
+        for list in &[&view.staged_view, &view.unstaged_view] {
+            list.tree.get_selection().connect_changed(weak!(view => move |selection| {
+                let view = try_upgrade!(view);
+                let (model, iter) = try_unwrap!(selection.get_selected());
+
+                if let Some(path) = model.get_value(&iter, 4).get::<String>() {
+                    view.presenter.on_file_selected(&path);
+                }
+            }));
+        }
+
         view.staged_view.selection_cell.connect_toggled(weak!(view => move |_, tree_path| {
             if let Some(view) = view.upgrade() {
                 if let Some(index) = tree_path.get_indices().first() {
@@ -201,8 +389,8 @@ impl FileStatusViewable for FileStatusView {
     }
 
     fn set_items(&self, staged: &[TreeItem], unstaged: &[TreeItem]) {
-        self.staged_view().set_items(&staged);
-        self.unstaged_view().set_items(&unstaged);
+        self.staged_view().set_items(staged);
+        self.unstaged_view().set_items(unstaged);
 
         let staged = self.staged_view.root.clone();
         let unstaged = self.unstaged_view.root.clone();
@@ -219,15 +407,26 @@ impl FileStatusViewable for FileStatusView {
         self.root.show_all();
     }
 
-    fn show_commit(&self, statuses: &[TreeItem], commit: &git2::Commit) {
-        self.staged_view().set_items(&statuses);
+    fn show_commit(&self, statuses: &[TreeItem], commit: &CommitDetails) {
+        self.overview_view.set_commit(commit);
+        self.show_overview(statuses);
+    }
+
+    fn show_comparison(&self, statuses: &[TreeItem], comparison: &Comparison) {
+        self.overview_view.set_comparison(comparison);
+        self.show_overview(statuses);
+    }
+}
+
+impl FileStatusView {
+    fn show_overview(&self, statuses: &[TreeItem]) {
+        self.staged_view().set_items(statuses);
         self.unstaged_view().set_items(&[]);
 
         for child in self.root.get_children() {
             self.root.remove(&child);
         }
 
-        self.overview_view.set_commit(commit);
         self.staged_view.columns[0].set_visible(false);
 
         self.root.add1(self.staged_view.widget());
@@ -238,8 +437,9 @@ impl FileStatusViewable for FileStatusView {
 
 pub struct FileListView {
     list_store: gtk::ListStore,
-    columns: [gtk::TreeViewColumn; 3],
+    columns: [gtk::TreeViewColumn; 4],
     selection_cell: gtk::CellRendererToggle,
+    tree: gtk::TreeView,
     root: gtk::ScrolledWindow
 }
 
@@ -248,6 +448,9 @@ impl FileListView {
         let list_store = gtk::ListStore::new(&[
             bool::static_type(),
             String::static_type(),
+            String::static_type(),
+            String::static_type(),
+            // The path itself, which is not shown.
             String::static_type()
         ]);
 
@@ -278,6 +481,11 @@ impl FileListView {
 
         let status_col = append_column(&treeview, 1, "Status");
         let path_col = append_column(&treeview, 2, "Path");
+        let annotations_col = append_column(&treeview, 3, "Details");
+
+        for cell in annotations_col.get_cells() {
+            cell.set_property("foreground", &"#888").unwrap_or(());
+        }
 
         treeview.set_model(&list_store);
 
@@ -286,9 +494,10 @@ impl FileListView {
         scroller.add(&treeview);
 
         FileListView {
-            list_store: list_store,
-            columns: [column, status_col, path_col],
+            list_store,
+            columns: [column, status_col, path_col, annotations_col],
             selection_cell: cell,
+            tree: treeview,
             root: scroller
         }
     }
@@ -297,9 +506,11 @@ impl FileListView {
        self.list_store.clear();
 
         for entry in statuses.iter() {
-            self.list_store.insert_with_values(None, &[0, 1, 2], &[
+            self.list_store.insert_with_values(None, &[0, 1, 2, 3, 4], &[
                 &entry.is_selected,
                 &format!("{:?}", entry.delta),
+                &entry.label,
+                &entry.annotations,
                 &entry.path
             ]);
         }
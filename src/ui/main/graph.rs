@@ -0,0 +1,397 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use cairo;
+use gdk;
+use gdk_pixbuf::Pixbuf;
+use git2::Oid;
+
+const LANE_WIDTH: f64 = 14.0;
+const NODE_RADIUS: f64 = 3.5;
+const MAX_CACHED_ROWS: usize = 512;
+
+const LANE_COLORS: [(f64, f64, f64); 8] = [
+    (0.204, 0.396, 0.643),
+    (0.306, 0.604, 0.024),
+    (0.800, 0.000, 0.000),
+    (0.459, 0.314, 0.482),
+    (0.961, 0.475, 0.000),
+    (0.024, 0.596, 0.604),
+    (0.561, 0.349, 0.008),
+    (0.361, 0.208, 0.400)
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum EdgeHalf {
+    Top,
+    Bottom
+}
+
+/// A line segment drawn within a single row, from one lane to another.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GraphEdge {
+    pub from: usize,
+    pub to: usize,
+    pub color: usize,
+    pub half: EdgeHalf
+}
+
+/// Everything needed to draw the graph cell for one commit.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GraphRow {
+    pub node: usize,
+    pub color: usize,
+    pub width: usize,
+    pub is_merge: bool,
+    pub edges: Vec<GraphEdge>
+}
+
+impl GraphRow {
+    /// Swaps two colors throughout the row, so that rows built separately can be made
+    /// to match the rows they join up with.
+    pub fn swap_colors(&mut self, a: usize, b: usize) {
+        let swap = |color: &mut usize| {
+            if *color == a {
+                *color = b;
+            } else if *color == b {
+                *color = a;
+            }
+        };
+
+        swap(&mut self.color);
+        for edge in self.edges.iter_mut() {
+            swap(&mut edge.color);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Lane {
+    id: Oid,
+    color: usize
+}
+
+/// Assigns commits to lanes one row at a time, in the order the revwalk yields them.
+/// The builder only keeps the set of lanes currently "open", so it can be fed
+/// incrementally as more history is loaded.
+#[derive(Debug, Default)]
+pub struct GraphBuilder {
+    lanes: Vec<Option<Lane>>,
+    next_color: usize
+}
+
+impl GraphBuilder {
+    pub fn new() -> GraphBuilder {
+        GraphBuilder::default()
+    }
+
+    fn new_color(&mut self) -> usize {
+        let color = self.next_color;
+        self.next_color = (self.next_color + 1) % LANE_COLORS.len();
+        color
+    }
+
+    fn free_slot(&mut self, exclude: Option<usize>) -> usize {
+        let slot = self.lanes.iter()
+            .enumerate()
+            .position(|(i, lane)| lane.is_none() && Some(i) != exclude);
+
+        match slot {
+            Some(i) => i,
+            None => {
+                self.lanes.push(None);
+                self.lanes.len() - 1
+            }
+        }
+    }
+
+    /// The commits the lanes left open are waiting for, with their colors.
+    pub fn open_lanes(&self) -> Vec<Option<(Oid, usize)>> {
+        self.lanes.iter().map(|lane| lane.map(|l| (l.id, l.color))).collect()
+    }
+
+    fn lane_of(&self, id: Oid) -> Option<usize> {
+        self.lanes.iter().position(|lane| lane.map(|l| l.id == id).unwrap_or(false))
+    }
+
+    pub fn push(&mut self, id: Oid, parents: &[Oid]) -> GraphRow {
+        let mut edges = vec![];
+
+        let (node, is_tip) = match self.lane_of(id) {
+            Some(i) => (i, false),
+            None => {
+                let slot = self.free_slot(None);
+                let color = self.new_color();
+                self.lanes[slot] = Some(Lane { id, color });
+                (slot, true)
+            }
+        };
+        let color = self.lanes[node].map(|l| l.color).unwrap_or(0);
+        let width_before = self.lanes.len();
+
+        // Every lane that was waiting for this commit converges on the node.
+        for (i, lane) in self.lanes.iter_mut().enumerate() {
+            if let Some(l) = *lane {
+                if l.id == id {
+                    if !(is_tip && i == node) {
+                        edges.push(GraphEdge { from: i, to: node, color: l.color, half: EdgeHalf::Top });
+                    }
+                    *lane = None;
+                } else {
+                    edges.push(GraphEdge { from: i, to: i, color: l.color, half: EdgeHalf::Top });
+                }
+            }
+        }
+
+        // Lanes that simply continue past this row.
+        for (i, lane) in self.lanes.iter().enumerate() {
+            if let Some(l) = lane {
+                edges.push(GraphEdge { from: i, to: i, color: l.color, half: EdgeHalf::Bottom });
+            }
+        }
+
+        for (n, parent) in parents.iter().enumerate() {
+            if let Some(j) = self.lane_of(*parent) {
+                let lane_color = self.lanes[j].map(|l| l.color).unwrap_or(color);
+                edges.push(GraphEdge { from: node, to: j, color: lane_color, half: EdgeHalf::Bottom });
+                continue;
+            }
+
+            let (slot, lane_color) = if n == 0 && self.lanes[node].is_none() {
+                (node, color)
+            } else {
+                (self.free_slot(Some(node)), self.new_color())
+            };
+
+            self.lanes[slot] = Some(Lane { id: *parent, color: lane_color });
+            edges.push(GraphEdge { from: node, to: slot, color: lane_color, half: EdgeHalf::Bottom });
+        }
+
+        while let Some(None) = self.lanes.last() {
+            self.lanes.pop();
+        }
+
+        GraphRow {
+            node,
+            color,
+            width: ::std::cmp::max(width_before, self.lanes.len()).max(node + 1),
+            is_merge: parents.len() > 1,
+            edges
+        }
+    }
+}
+
+fn lane_x(lane: usize) -> f64 {
+    (lane as f64 + 0.5) * LANE_WIDTH
+}
+
+fn set_color(cr: &cairo::Context, color: usize) {
+    let (r, g, b) = LANE_COLORS[color % LANE_COLORS.len()];
+    cr.set_source_rgba(r, g, b, 1.0);
+}
+
+/// Draws a row of the graph into a pixbuf suitable for a `CellRendererPixbuf`.
+pub fn render(row: &GraphRow, height: i32, is_hollow: bool) -> Option<Pixbuf> {
+    let width = (row.width as f64 * LANE_WIDTH).ceil() as i32;
+    let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, width, height).ok()?;
+
+    {
+        let cr = cairo::Context::new(&surface);
+        let h = height as f64;
+        let mid = h / 2.0;
+
+        cr.set_line_width(2.0);
+
+        for edge in row.edges.iter() {
+            let (x1, x2) = (lane_x(edge.from), lane_x(edge.to));
+            let (y1, y2) = match edge.half {
+                EdgeHalf::Top => (0.0, mid),
+                EdgeHalf::Bottom => (mid, h)
+            };
+
+            set_color(&cr, edge.color);
+            cr.move_to(x1, y1);
+            if x1 == x2 {
+                cr.line_to(x2, y2);
+            } else {
+                let ym = (y1 + y2) / 2.0;
+                cr.curve_to(x1, ym, x2, ym, x2, y2);
+            }
+            cr.stroke();
+        }
+
+        let radius = if row.is_merge { NODE_RADIUS - 0.5 } else { NODE_RADIUS };
+        cr.arc(lane_x(row.node), mid, radius, 0.0, 2.0 * PI);
+
+        if is_hollow {
+            cr.set_source_rgba(1.0, 1.0, 1.0, 1.0);
+            cr.fill_preserve();
+            set_color(&cr, row.color);
+            cr.stroke();
+        } else {
+            set_color(&cr, row.color);
+            cr.fill_preserve();
+            if row.is_merge {
+                cr.set_source_rgba(1.0, 1.0, 1.0, 1.0);
+                cr.set_line_width(1.0);
+            }
+            cr.stroke();
+        }
+    }
+
+    gdk::pixbuf_get_from_surface(&surface, 0, 0, width, height)
+}
+
+/// Keeps the pixbufs already drawn, which rows with the same lanes share. Most rows
+/// of a history look alike, so only a handful ever need drawing.
+#[derive(Default)]
+pub struct RenderCache {
+    height: i32,
+    pixbufs: HashMap<GraphRow, Option<Pixbuf>>
+}
+
+impl RenderCache {
+    pub fn render(&mut self, row: &GraphRow, height: i32, is_hollow: bool) -> Option<Pixbuf> {
+        // Only the uncommitted changes are drawn hollow, so there is no point keeping it.
+        if is_hollow {
+            return render(row, height, is_hollow);
+        }
+
+        if height != self.height || self.pixbufs.len() >= MAX_CACHED_ROWS {
+            self.pixbufs.clear();
+            self.height = height;
+        }
+
+        if let Some(pixbuf) = self.pixbufs.get(row) {
+            return pixbuf.clone();
+        }
+
+        let pixbuf = render(row, height, is_hollow);
+        self.pixbufs.insert(row.clone(), pixbuf.clone());
+        pixbuf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> Oid {
+        Oid::from_bytes(&[n; 20]).unwrap()
+    }
+
+    /// Feeds commits given as (id, parents) into a builder, newest first.
+    fn build(commits: &[(u8, &[u8])]) -> Vec<GraphRow> {
+        let mut builder = GraphBuilder::new();
+
+        commits.iter()
+            .map(|&(commit, parents)| {
+                let parents: Vec<Oid> = parents.iter().map(|x| id(*x)).collect();
+                builder.push(id(commit), &parents)
+            })
+            .collect()
+    }
+
+    fn edges(row: &GraphRow, half: EdgeHalf) -> Vec<(usize, usize)> {
+        let mut edges: Vec<(usize, usize)> = row.edges.iter()
+            .filter(|x| x.half == half)
+            .map(|x| (x.from, x.to))
+            .collect();
+        edges.sort();
+        edges
+    }
+
+    #[test]
+    fn linear_history_stays_in_one_lane() {
+        let rows = build(&[(3, &[2]), (2, &[1]), (1, &[])]);
+
+        assert!(rows.iter().all(|x| x.node == 0 && x.width == 1 && !x.is_merge));
+        assert_eq!(edges(&rows[0], EdgeHalf::Top), vec![]);
+        assert_eq!(edges(&rows[0], EdgeHalf::Bottom), vec![(0, 0)]);
+        assert_eq!(edges(&rows[1], EdgeHalf::Top), vec![(0, 0)]);
+        assert_eq!(edges(&rows[2], EdgeHalf::Bottom), vec![]);
+        assert!(rows.iter().all(|x| x.color == rows[0].color));
+    }
+
+    #[test]
+    fn merge_opens_a_lane_for_its_second_parent() {
+        let rows = build(&[(4, &[2, 3]), (3, &[1]), (2, &[1]), (1, &[])]);
+
+        assert!(rows[0].is_merge);
+        assert_eq!(rows[0].width, 2);
+        assert_eq!(edges(&rows[0], EdgeHalf::Bottom), vec![(0, 0), (0, 1)]);
+
+        // The second parent gets its own lane and color.
+        assert_eq!(rows[1].node, 1);
+        assert_ne!(rows[1].color, rows[0].color);
+        assert_eq!(edges(&rows[1], EdgeHalf::Top), vec![(0, 0), (1, 1)]);
+
+        // The first branch to reach the common parent joins the lane already waiting
+        // for it.
+        assert_eq!(rows[2].node, 0);
+        assert_eq!(edges(&rows[2], EdgeHalf::Bottom), vec![(0, 1), (1, 1)]);
+        assert_eq!(rows[3].node, 1);
+        assert_eq!(edges(&rows[3], EdgeHalf::Top), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn fork_joins_the_lane_of_the_common_parent() {
+        let rows = build(&[(3, &[1]), (2, &[1]), (1, &[])]);
+
+        assert_eq!(rows[0].node, 0);
+        assert_eq!(rows[1].node, 1);
+        assert_eq!(rows[1].width, 2);
+        assert_eq!(edges(&rows[1], EdgeHalf::Top), vec![(0, 0)]);
+        assert_eq!(edges(&rows[1], EdgeHalf::Bottom), vec![(0, 0), (1, 0)]);
+
+        assert_eq!(rows[2].node, 0);
+        assert_eq!(rows[2].width, 1);
+        assert_eq!(edges(&rows[2], EdgeHalf::Top), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn tip_reuses_a_freed_lane() {
+        let rows = build(&[(10, &[1]), (11, &[2]), (12, &[3]), (2, &[]), (13, &[1])]);
+
+        assert_eq!(rows[2].node, 2);
+        assert_eq!(rows[3].node, 1);
+        assert_eq!(rows[4].node, 1);
+        assert_eq!(rows[4].width, 3);
+    }
+
+    #[test]
+    fn finished_lanes_on_the_right_are_closed() {
+        let mut builder = GraphBuilder::new();
+        builder.push(id(3), &[id(1), id(2)]);
+        builder.push(id(2), &[]);
+
+        assert_eq!(builder.open_lanes().len(), 1);
+        assert_eq!(builder.open_lanes()[0].map(|x| x.0), Some(id(1)));
+    }
+
+    #[test]
+    fn swap_colors_swaps_both_ways() {
+        let mut row = build(&[(4, &[2, 3])]).remove(0);
+        let (first, second) = (row.edges[0].color, row.edges[1].color);
+
+        row.swap_colors(first, second);
+
+        assert_eq!((row.edges[0].color, row.edges[1].color), (second, first));
+        assert_eq!(row.color, second);
+    }
+}
@@ -0,0 +1,337 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ops::Range;
+
+use glib::markup_escape_text;
+
+/// Lines with more tokens than this on both sides together are not diffed word by word.
+const MAX_TOKENS: usize = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Style {
+    Keyword,
+    Comment,
+    String,
+    Number,
+    Type,
+    Removed,
+    Added,
+    /// Markers standing in for whitespace and line endings.
+    Whitespace,
+    /// Whitespace that should not be there, like at the end of an added line.
+    Warning,
+    /// Text found by a search, and the one of those currently selected.
+    Match,
+    CurrentMatch
+}
+
+impl Style {
+    fn attributes(&self) -> &'static str {
+        match *self {
+            Style::Keyword => "foreground=\"#204a87\" weight=\"bold\"",
+            Style::Comment => "foreground=\"#8f5902\" style=\"italic\"",
+            Style::String => "foreground=\"#4e9a06\"",
+            Style::Number => "foreground=\"#0000cf\"",
+            Style::Type => "foreground=\"#5c3566\"",
+            Style::Removed => "background=\"#e3a9a9\"",
+            Style::Added => "background=\"#a9d99b\"",
+            Style::Whitespace => "foreground=\"#babdb6\"",
+            Style::Warning => "background=\"#f2a1a1\"",
+            Style::Match => "background=\"#fce94f\"",
+            Style::CurrentMatch => "background=\"#f57900\""
+        }
+    }
+}
+
+/// A styled byte range within a line.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub range: Range<usize>,
+    pub style: Style
+}
+
+/// Renders `text` as Pango markup. Where spans overlap, the attributes of all of them
+/// are applied, with later spans winning over earlier ones.
+pub fn to_markup(text: &str, spans: &[Span]) -> String {
+    if spans.is_empty() {
+        return markup_escape_text(text).to_string();
+    }
+
+    let mut bounds: Vec<usize> = spans.iter()
+        .flat_map(|x| vec![x.range.start, x.range.end])
+        .chain(vec![0, text.len()])
+        .filter(|x| *x <= text.len() && text.is_char_boundary(*x))
+        .collect();
+    bounds.sort();
+    bounds.dedup();
+
+    let mut markup = String::new();
+
+    for segment in bounds.windows(2) {
+        let (start, end) = (segment[0], segment[1]);
+        let escaped = markup_escape_text(&text[start..end]);
+
+        let attributes: Vec<&str> = spans.iter()
+            .filter(|x| x.range.start <= start && end <= x.range.end)
+            .map(|x| x.style.attributes())
+            .collect();
+
+        // Pango refuses a tag setting the same attribute twice, so each span gets its
+        // own, with the innermost taking effect.
+        for attribute in attributes.iter() {
+            markup.push_str(&format!("<span {}>", attribute));
+        }
+        markup.push_str(&escaped);
+        markup.push_str(&"</span>".repeat(attributes.len()));
+    }
+
+    markup
+}
+
+/// Replaces spaces and tabs with visible markers, moving the spans along with the text.
+/// Whitespace at the end is marked as a warning if `is_added` is set.
+pub fn show_whitespace(text: &str, spans: &[Span], is_added: bool) -> (String, Vec<Span>) {
+    let mut shown = String::new();
+    let mut markers = vec![];
+    // Where each byte of the text ended up, for moving the spans.
+    let mut offsets = vec![0; text.len() + 1];
+
+    for (i, c) in text.char_indices() {
+        offsets[i] = shown.len();
+        let start = shown.len();
+
+        match c {
+            ' ' => shown.push('·'),
+            '\t' => shown.push_str("→   "),
+            '\r' => shown.push('␍'),
+            _ => {
+                shown.push(c);
+                continue;
+            }
+        }
+
+        markers.push(Span { range: start..shown.len(), style: Style::Whitespace });
+    }
+    offsets[text.len()] = shown.len();
+
+    let mut spans: Vec<Span> = spans.iter()
+        .filter(|x| x.range.end <= text.len())
+        .map(|x| Span { range: offsets[x.range.start]..offsets[x.range.end], style: x.style })
+        .chain(markers)
+        .collect();
+
+    let content_end = text.trim_end().len();
+    if is_added && content_end < text.len() {
+        spans.push(Span { range: offsets[content_end]..shown.len(), style: Style::Warning });
+    }
+
+    (shown, spans)
+}
+
+/// Splits a line into runs of word characters, runs of whitespace and single
+/// punctuation characters.
+fn tokenize(text: &str) -> Vec<Range<usize>> {
+    #[derive(PartialEq)]
+    enum Class { Word, Space, Other }
+
+    let class = |c: char| if c.is_alphanumeric() || c == '_' {
+        Class::Word
+    } else if c.is_whitespace() {
+        Class::Space
+    } else {
+        Class::Other
+    };
+
+    let mut tokens: Vec<Range<usize>> = vec![];
+    let mut last = None;
+
+    for (i, c) in text.char_indices() {
+        let current = class(c);
+        let end = i + c.len_utf8();
+
+        match tokens.last_mut() {
+            Some(ref mut token) if current != Class::Other && last.as_ref() == Some(&current) => token.end = end,
+            _ => tokens.push(i..end)
+        }
+
+        last = Some(current);
+    }
+
+    tokens
+}
+
+/// The byte ranges that changed on each side of a line pair.
+#[derive(Debug, Clone, Default)]
+pub struct WordChanges {
+    pub old: Vec<Range<usize>>,
+    pub new: Vec<Range<usize>>
+}
+
+/// Finds the words that differ between a removed line and the added line replacing it,
+/// returning the changed byte ranges of each. Gives up on lines that are too long or
+/// have nothing but whitespace in common, where highlighting would only add noise.
+pub fn word_changes(old: &str, new: &str) -> Option<WordChanges> {
+    let old_tokens = tokenize(old);
+    let new_tokens = tokenize(new);
+
+    if old_tokens.len() + new_tokens.len() > MAX_TOKENS {
+        return None;
+    }
+
+    let (n, m) = (old_tokens.len(), new_tokens.len());
+    let same = |i: usize, j: usize| old[old_tokens[i].clone()] == new[new_tokens[j].clone()];
+
+    // Longest common subsequence of tokens, filled in from the end.
+    let mut lengths = vec![vec![0u16; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[i][j] = if same(i, j) {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = WordChanges::default();
+    let mut has_common_word = false;
+    let (mut i, mut j) = (0, 0);
+
+    while i < n || j < m {
+        if i < n && j < m && same(i, j) {
+            has_common_word |= !old[old_tokens[i].clone()].trim().is_empty();
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            push_range(&mut changes.old, old_tokens[i].clone());
+            i += 1;
+        } else {
+            push_range(&mut changes.new, new_tokens[j].clone());
+            j += 1;
+        }
+    }
+
+    if !has_common_word {
+        return None;
+    }
+
+    Some(changes)
+}
+
+/// Adds a range, merging it with the previous one when they touch.
+fn push_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    if let Some(last) = ranges.last_mut() {
+        if last.end == range.start {
+            last.end = range.end;
+            return;
+        }
+    }
+
+    ranges.push(range);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words<'a>(text: &'a str, ranges: &[Range<usize>]) -> Vec<&'a str> {
+        ranges.iter().map(|x| &text[x.clone()]).collect()
+    }
+
+    #[test]
+    fn tokenize_groups_words_and_whitespace() {
+        let text = "let  foo_bar = baz(1);";
+        assert_eq!(words(text, &tokenize(text)), vec!["let", "  ", "foo_bar", " ", "=", " ", "baz", "(", "1", ")", ";"]);
+    }
+
+    #[test]
+    fn tokenize_keeps_punctuation_apart() {
+        let text = "a->b";
+        assert_eq!(words(text, &tokenize(text)), vec!["a", "-", ">", "b"]);
+    }
+
+    #[test]
+    fn tokenize_uses_byte_ranges_for_unicode() {
+        let text = "größe – ok";
+        assert_eq!(words(text, &tokenize(text)), vec!["größe", " ", "–", " ", "ok"]);
+        assert!(tokenize("").is_empty());
+    }
+
+    fn styled<'a>(text: &'a str, spans: &[Span], style: Style) -> Vec<&'a str> {
+        spans.iter().filter(|x| x.style == style).map(|x| &text[x.range.clone()]).collect()
+    }
+
+    #[test]
+    fn show_whitespace_replaces_spaces_tabs_and_returns() {
+        let (shown, spans) = show_whitespace("a b\tc\r", &[], false);
+
+        assert_eq!(shown, "a·b→   c␍");
+        assert_eq!(styled(&shown, &spans, Style::Whitespace), vec!["·", "→   ", "␍"]);
+        assert!(styled(&shown, &spans, Style::Warning).is_empty());
+    }
+
+    #[test]
+    fn show_whitespace_moves_spans_along() {
+        let spans = [Span { range: 2..5, style: Style::Keyword }, Span { range: 7..8, style: Style::Number }];
+        let (shown, spans) = show_whitespace("\t fn x 1", &spans, false);
+
+        assert_eq!(styled(&shown, &spans, Style::Keyword), vec!["fn·"]);
+        assert_eq!(styled(&shown, &spans, Style::Number), vec!["1"]);
+    }
+
+    #[test]
+    fn show_whitespace_warns_about_trailing_whitespace_on_added_lines() {
+        let (shown, spans) = show_whitespace("x = 1; \t", &[], true);
+        assert_eq!(styled(&shown, &spans, Style::Warning), vec!["·→   "]);
+
+        let (_, spans) = show_whitespace("x = 1; \t", &[], false);
+        assert!(spans.iter().all(|x| x.style != Style::Warning));
+
+        let (_, spans) = show_whitespace("x = 1;", &[], true);
+        assert!(spans.iter().all(|x| x.style != Style::Warning));
+    }
+
+    #[test]
+    fn word_changes_finds_the_changed_word() {
+        let (old, new) = ("let count = 1;", "let total = 1;");
+        let changes = word_changes(old, new).unwrap();
+
+        assert_eq!(words(old, &changes.old), vec!["count"]);
+        assert_eq!(words(new, &changes.new), vec!["total"]);
+    }
+
+    #[test]
+    fn word_changes_merges_adjacent_tokens() {
+        let (old, new) = ("call(a, b)", "call(a, b, c)");
+        let changes = word_changes(old, new).unwrap();
+
+        assert!(changes.old.is_empty());
+        assert_eq!(words(new, &changes.new), vec![", c"]);
+    }
+
+    #[test]
+    fn word_changes_gives_up_without_a_common_word() {
+        assert!(word_changes("foo bar", "baz qux").is_none());
+        assert!(word_changes("a", "b").is_none());
+    }
+
+    #[test]
+    fn word_changes_gives_up_on_long_lines() {
+        let old = "x ".repeat(MAX_TOKENS);
+        let new = format!("{}y", old);
+        assert!(word_changes(&old, &new).is_none());
+    }
+}
@@ -14,92 +14,122 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::rc::{Rc, Weak};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::min;
+use std::collections::{HashSet, VecDeque};
 use std::sync::mpsc::{channel, TryRecvError};
 use std::time::Duration;
 use std::fmt;
+use std::path::PathBuf;
 
 use notify::{DebouncedEvent, RecommendedWatcher, Watcher, RecursiveMode};
-use chrono::{self, TimeZone};
 use git2;
+use gdk;
 use gtk::prelude::*;
 use gtk;
+use chrono::{self, TimeZone};
 use glib::markup_escape_text;
 use pango;
 
 use super::branch::{BranchPresenter, BranchView, BranchViewable};
 use super::CommitInfo;
+use crate::git::history::{CommitLink, HistoryFilter, HistoryOptions, HistoryScope, HistoryUpdate, SearchField, PAGE_SIZE};
+use crate::git::diff::DiffSide;
+use crate::git::worker::{Client, Request, Response};
+use super::graph::{self, EdgeHalf, GraphBuilder, GraphEdge, GraphRow};
 
 pub trait HistoryViewable {
     fn new(parent: Weak<BranchPresenter<BranchView>>) -> Rc<Self>;
-    fn set_history(&self, commits: &[CommitInfo]);
+    fn set_history(&self, commits: &[CommitInfo], graph: &[GraphRow]);
+    fn append_history(&self, commits: &[CommitInfo], graph: &[GraphRow]);
+    /// Replaces the first `removed` rows with `head`, and the graph down to the first
+    /// row that was kept with `graph`. `relabelled` holds the kept commits whose branch
+    /// labels changed, by their new positions.
+    fn update_history_head(&self, removed: usize, head: &[CommitInfo], graph: &[GraphRow], relabelled: &[(usize, CommitInfo)]);
     fn selected_row(&self) -> Option<usize>;
+    fn select_row(&self, index: Option<usize>);
+    fn select_rows(&self, rows: &[usize]);
+    fn first_visible_row(&self) -> Option<usize>;
+    fn scroll_to_row(&self, index: usize);
+    fn set_options(&self, options: HistoryOptions);
+    fn set_search_status(&self, status: &str);
     fn handle_error(&self, error: impl fmt::Display);
+    fn show_message(&self, message: &str);
     fn refresh_commit_history(&self);
+    fn select_commit(&self, id: git2::Oid);
+    fn children_of(&self, id: git2::Oid) -> Vec<CommitLink>;
 }
 
 struct HistoryPresenter<V> {
     parent: Weak<BranchPresenter<BranchView>>,
     view: RefCell<Weak<V>>,
+    client: Client,
+    page_client: Client,
+    /// Saves patches, apart from the history requests that cancel each other.
+    export_client: Client,
+    is_loading_page: Cell<bool>,
     commits: RefCell<Vec<CommitInfo>>,
+    graph: RefCell<Vec<GraphRow>>,
+    graph_builder: RefCell<GraphBuilder>,
+    pending: RefCell<VecDeque<git2::Oid>>,
+    /// Every commit of the walk, loaded or still pending, so the graph can leave out
+    /// parents that a range hides.
+    walked: RefCell<HashSet<git2::Oid>>,
+    roots: RefCell<Vec<git2::Oid>>,
+    selected: Cell<Option<git2::Oid>>,
+    compared: Cell<Option<(git2::Oid, git2::Oid)>>,
+    options: Cell<HistoryOptions>,
+    filter: RefCell<Option<HistoryFilter>>,
+    range: RefCell<Option<String>>,
     watcher: RefCell<RecommendedWatcher>
 }
 
-pub trait HumanCommitExt<'a> {
-    fn author_str(&self) -> String;
-    fn id_str(&self) -> String;
-    fn short_id_str(&self) -> String;
-    fn full_summary_str(&'a self) -> &'a str;
-    fn summary_str(&'a self) -> &'a str;
-    fn date(&self) -> chrono::DateTime<chrono::FixedOffset>;
-}
-
-impl<'a> HumanCommitExt<'a> for git2::Commit<'a> {
-    fn author_str(&self) -> String {
-        let author = self.author();
-        let author_name = author.name().unwrap_or("Unknown");
-        let author_email = author.email().unwrap_or("unknown");
-        format!("{} <{}>", &author_name, &author_email)
-    }
-
-    fn id_str(&self) -> String {
-        format!("{}", self.id())
-    }
-
-    fn short_id_str(&self) -> String {
-        self.id_str()[0..7].to_string()
-    }
-    
-    fn full_summary_str(&'a self) -> &'a str {
-        self.summary().unwrap_or("<No summary found>")
-    }
-
-    fn summary_str(&'a self) -> &'a str {
-        let full_summary = self.full_summary_str();
-        &full_summary[0..min(80, full_summary.len())]
-    }
-
-    fn date(&self) -> chrono::DateTime<chrono::FixedOffset> {
-        let time = self.time();
-        let naive_dt = chrono::Utc.timestamp(time.seconds(), 0).naive_utc();
-        let offset = chrono::offset::FixedOffset::east(time.offset_minutes() * 60);
-        let date: chrono::DateTime<chrono::FixedOffset> = chrono::DateTime::from_utc(naive_dt, offset);
-        date
-    }
-}
-
 impl<V: HistoryViewable> HistoryPresenter<V> where V: 'static {
     fn new(parent: Weak<BranchPresenter<BranchView>>) -> Rc<HistoryPresenter<V>> {
         let (tx, rx) = channel();
+        let worker = parent.upgrade()
+            .expect("Presenter only created while parent still exists")
+            .worker();
 
         let presenter = Rc::new(HistoryPresenter {
-            parent: parent,
+            parent,
             view: RefCell::new(Weak::new()),
+            client: worker.client(),
+            page_client: worker.client(),
+            export_client: worker.client(),
+            is_loading_page: Cell::new(false),
             commits: RefCell::new(vec![]),
+            graph: RefCell::new(vec![]),
+            graph_builder: RefCell::new(GraphBuilder::new()),
+            pending: RefCell::new(VecDeque::new()),
+            walked: RefCell::new(HashSet::new()),
+            roots: RefCell::new(vec![]),
+            selected: Cell::new(None),
+            compared: Cell::new(None),
+            options: Cell::new(HistoryOptions::default()),
+            filter: RefCell::new(None),
+            range: RefCell::new(None),
             watcher: RefCell::new(Watcher::new(tx, Duration::from_secs(2)).unwrap())
         });
 
+        gtk::timeout_add(50, weak!(presenter => move || {
+            let presenter = try_upgrade!(presenter, gtk::Continue(false));
+
+            while let Some(response) = presenter.client.try_recv() {
+                presenter.on_response(response);
+            }
+
+            while let Some(response) = presenter.page_client.try_recv() {
+                presenter.on_response(response);
+            }
+
+            while let Some(response) = presenter.export_client.try_recv() {
+                presenter.on_response(response);
+            }
+
+            gtk::Continue(true)
+        }));
+
         gtk::timeout_add(50, weak!(presenter => move || {
             match rx.try_recv() {
                 Err(err) => {
@@ -148,9 +178,6 @@ impl<V: HistoryViewable> HistoryPresenter<V> where V: 'static {
         let parent = self.parent();
         if path.ends_with("index") || path.ends_with(&*parent.branch().borrow()) || !path.components().any(|x| x.as_os_str() == ".git") {
             self.update_commit_history();
-            if let Some(idx) = self.view().selected_row() {
-                self.on_item_selected(idx);
-            }
         }
     }
 
@@ -166,97 +193,474 @@ impl<V: HistoryViewable> HistoryPresenter<V> where V: 'static {
             .expect("Presenter only running while parent still exists")
     }
 
-    fn has_uncommitted_changes(&self) -> bool {
-        let parent = self.parent();
-        let repo = parent.repo();
-        let statuses = repo.statuses(None).unwrap();
-        statuses.iter().filter(|x| !x.status().is_ignored()).count() > 0
+    /// The parents to draw lines to, which are only those that are part of the walk.
+    fn graph_parents(&self, info: &CommitInfo) -> Vec<git2::Oid> {
+        let walked = self.walked.borrow();
+        info.parents.iter().cloned().filter(|x| walked.contains(x)).collect()
+    }
+
+    fn rebuild_graph(&self) {
+        let mut builder = GraphBuilder::new();
+        let graph = self.commits.borrow().iter()
+            .map(|x| builder.push(x.id, &self.graph_parents(x)))
+            .collect();
+
+        *self.graph.borrow_mut() = graph;
+        *self.graph_builder.borrow_mut() = builder;
+    }
+
+    pub fn reload_commit_history(&self) {
+        let branch = self.parent().branch().borrow().clone();
+        let options = self.options.get();
+        let range = self.range.borrow().clone();
+
+        if let Some(filter) = self.filter.borrow().clone() {
+            return self.search(branch, options, range, filter);
+        }
+
+        self.client.send_latest(Request::History { branch, options, range, roots: vec![] });
+    }
+
+    /// Walks a revision range such as `main..feature` instead of the scope's branches.
+    fn on_range_changed(&self, range: &str) {
+        let range = Some(range.trim().to_string()).filter(|x| !x.is_empty());
+
+        if *self.range.borrow() == range {
+            return;
+        }
+
+        *self.range.borrow_mut() = range;
+        self.reload_commit_history();
+    }
+
+    /// Replaces the history with the commits matching `filter`, which the worker
+    /// streams back as it finds them.
+    fn search(&self, branch: String, options: HistoryOptions, range: Option<String>, filter: HistoryFilter) {
+        self.page_client.cancel();
+        self.is_loading_page.set(false);
+        self.pending.borrow_mut().clear();
+        self.walked.borrow_mut().clear();
+        self.commits.borrow_mut().clear();
+        self.graph.borrow_mut().clear();
+        self.roots.borrow_mut().clear();
+
+        // The matches are not contiguous, so there is no graph to draw between them.
+        self.view().set_history(&[], &[]);
+        self.view().set_search_status("Searching…");
+        self.client.send_latest(Request::Search { branch, options, range, filter });
+    }
+
+    fn on_search_results(&self, commits: Vec<CommitInfo>, is_done: bool) {
+        self.append_commits(commits, false);
+
+        let count = self.commits.borrow().len();
+        let status = match (is_done, count) {
+            (false, _) => format!("Searching… {} found", count),
+            (true, 0) => "No matching commits".to_string(),
+            (true, 1) => "1 matching commit".to_string(),
+            (true, _) => format!("{} matching commits", count)
+        };
+
+        self.view().set_search_status(&status);
     }
 
+    fn on_filter_changed(&self, filter: Option<HistoryFilter>) {
+        let filter = filter.filter(|x| !x.is_empty());
+
+        if *self.filter.borrow() == filter {
+            return;
+        }
+
+        if filter.is_none() {
+            self.view().set_search_status("");
+        }
+
+        *self.filter.borrow_mut() = filter;
+        self.reload_commit_history();
+    }
+
+    fn repo_key(&self) -> String {
+        self.parent().repo().path().to_string_lossy().to_string()
+    }
+
+    /// Picks up the options last used with the current repository.
+    fn restore_options(&self) {
+        use crate::Config;
+
+        let key = self.repo_key();
+        let defaults = HistoryOptions::default();
+        let flag = |name, default| Config::repo_setting(&key, name).map(|x| x == "true").unwrap_or(default);
+
+        let options = HistoryOptions {
+            scope: Config::repo_setting(&key, "history_scope")
+                .and_then(|x| HistoryScope::from_str(&x))
+                .unwrap_or(defaults.scope),
+            first_parent: flag("history_first_parent", defaults.first_parent),
+            hide_merges: flag("history_hide_merges", defaults.hide_merges)
+        };
+
+        self.options.set(options);
+        self.view().set_options(options);
+    }
+
+    fn on_options_changed(&self, options: HistoryOptions) {
+        use crate::Config;
+
+        if options == self.options.get() {
+            return;
+        }
+
+        let key = self.repo_key();
+        let saved = Config::set_repo_setting(&key, "history_scope", options.scope.as_str())
+            .and_then(|_| Config::set_repo_setting(&key, "history_first_parent", &options.first_parent.to_string()))
+            .and_then(|_| Config::set_repo_setting(&key, "history_hide_merges", &options.hide_merges.to_string()));
+
+        if let Err(err) = saved {
+            self.view().handle_error(format!("The history options could not be saved: {}", err));
+        }
+
+        self.options.set(options);
+        self.reload_commit_history();
+    }
+
+    /// Brings the history up to date after a change on disk, prepending only what is
+    /// new when the worker finds that nothing already loaded has gone away.
     pub fn update_commit_history(&self) {
-        let parent = self.parent();
-        let repo = parent.repo();
-        let branch = repo.find_branch(&parent.branch().borrow(), git2::BranchType::Local).expect("find branch");
-        let refr = branch.get().name().expect("find branch name as ref");
-
-        let revwalk = {
-            let mut revwalk = repo.revwalk().expect("get a revwalk");
-            revwalk.push_head().expect("head can be pushed");
-            let first_commit = revwalk.next().unwrap().unwrap();
-
-            let mut revwalk = repo.revwalk().expect("get a revwalk");
-            let mut sort = git2::Sort::TIME;
-            sort.insert(git2::Sort::TOPOLOGICAL);
-            revwalk.set_sorting(sort);
-            revwalk.push_ref(refr).expect("push ref successfully");
-            revwalk.push(first_commit).unwrap();
-
-            // These two may fail if the directories for them do not exist, so we unwrap and ignore.
-            revwalk.push_glob("heads/*").unwrap_or(());
-            revwalk.push_glob("remotes/*").unwrap_or(());
-            
-            revwalk
+        // Search results are left alone until the search changes.
+        if self.filter.borrow().is_some() {
+            return;
+        }
+
+        let branch = self.parent().branch().borrow().clone();
+        let options = self.options.get();
+        let range = self.range.borrow().clone();
+        let roots = self.roots.borrow().clone();
+        self.client.send_latest(Request::History { branch, options, range, roots });
+    }
+
+    fn on_response(&self, response: Response) {
+        match response {
+            Response::History(update) => self.on_history_update(update),
+            Response::Commits(commits) => self.on_page_loaded(commits),
+            Response::SearchResults { commits, is_done } => self.on_search_results(commits, is_done),
+            Response::PatchesSaved(paths) => self.on_patches_saved(&paths),
+            Response::Error(err) => {
+                self.is_loading_page.set(false);
+                self.view().handle_error(err);
+            }
+            _ => {}
+        }
+    }
+
+    fn index_of(&self, id: git2::Oid) -> Option<usize> {
+        self.commits.borrow().iter().position(|x| x.id == id)
+    }
+
+    /// Rows move around as commits and the sentinel come and go, so the selection and
+    /// the first visible row are put back by commit id rather than by position.
+    fn on_history_update(&self, update: HistoryUpdate) {
+        let view = self.view();
+        let anchor = view.first_visible_row()
+            .filter(|x| *x > 0)
+            .and_then(|x| self.commits.borrow().get(x).map(|x| x.id));
+
+        // The sentinel going away most likely means its changes were just committed.
+        let had_sentinel = self.commits.borrow().first().map(|x| x.is_sentinel()).unwrap_or(false);
+        let is_committed = self.selected.get() == Some(git2::Oid::zero()) && had_sentinel && update.sentinel.is_none();
+        if is_committed {
+            self.selected.set(update.head);
+        }
+
+        self.apply_history_update(update);
+        self.restore_selection(is_committed);
+
+        if let Some(index) = anchor.and_then(|x| self.index_of(x)) {
+            view.scroll_to_row(index);
+        }
+    }
+
+    fn restore_selection(&self, is_changed: bool) {
+        if let Some((from, to)) = self.compared.get() {
+            if let (Some(from), Some(to)) = (self.index_of(from), self.index_of(to)) {
+                return self.view().select_rows(&[from, to]);
+            }
+        }
+
+        let selected = match self.selected.get() {
+            Some(v) => v,
+            None => return
         };
 
+        match self.index_of(selected) {
+            Some(index) => {
+                self.view().select_row(Some(index));
 
-        let mut infos = vec![];
+                // A commit never changes, but the uncommitted changes may have.
+                if is_changed || self.commits.borrow()[index].is_sentinel() {
+                    self.on_item_selected(index);
+                }
+            }
+            None => {
+                self.view().select_row(None);
+                self.load_until(selected);
+            }
+        }
+    }
+
+    /// Loads as far as the given commit if it has not been reached yet. Returns false
+    /// if it is not part of the history at all.
+    fn load_until(&self, id: git2::Oid) -> bool {
+        let position = self.pending.borrow().iter().position(|x| *x == id);
+
+        match position {
+            Some(position) => {
+                self.load_pending(position + 1);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn select_commit(&self, id: git2::Oid) {
+        self.selected.set(Some(id));
+
+        if let Some(index) = self.index_of(id) {
+            self.view().select_row(Some(index));
+            self.view().scroll_to_row(index);
+            self.on_item_selected(index);
+            return;
+        }
+
+        self.view().select_row(None);
 
-        if self.has_uncommitted_changes() {
-            infos.push(CommitInfo::uncommitted_sentinel());
+        // Still show the commit when it is outside of what the history shows.
+        if !self.load_until(id) {
+            self.parent().on_commit_id_selected(id);
         }
+    }
+
+    /// Only the children among the loaded commits are known.
+    fn children_of(&self, id: git2::Oid) -> Vec<CommitLink> {
+        self.commits.borrow().iter()
+            .filter(|x| !x.is_sentinel() && x.parents.contains(&id))
+            .map(CommitLink::from)
+            .collect()
+    }
+
+    fn apply_history_update(&self, update: HistoryUpdate) {
+        *self.roots.borrow_mut() = update.roots;
+
+        if update.is_reload {
+            self.page_client.cancel();
+            self.is_loading_page.set(false);
+
+            *self.walked.borrow_mut() = update.commits.iter().map(|x| x.id).chain(update.pending.iter().cloned()).collect();
+            *self.pending.borrow_mut() = update.pending.into();
+            *self.commits.borrow_mut() = update.sentinel.into_iter().chain(update.commits).collect();
+
+            self.rebuild_graph();
+            self.view().set_history(&self.commits.borrow(), &self.graph.borrow());
+            return;
+        }
+
+        self.walked.borrow_mut().extend(update.commits.iter().map(|x| x.id));
 
-        let repo = parent.repo();
-        let branches: Vec<(String, git2::Commit)> = repo.branches(None).unwrap()
-            .map(|branch| {
-                let branch = branch.unwrap().0;
-                let name = branch.name().unwrap().unwrap().to_string();
-                let commit = branch.get().peel_to_commit().unwrap();
-                (name, commit)
-            })
+        let branch_heads = update.branch_heads;
+        let head: Vec<CommitInfo> = update.sentinel.into_iter().chain(update.commits).collect();
+        let added = head.len();
+        let removed = if self.commits.borrow().first().map(|x| x.is_sentinel()).unwrap_or(false) { 1 } else { 0 };
+
+        let graph = match self.graph_above(&head, removed) {
+            Some(v) => v,
+            None => return self.reload_commit_history()
+        };
+
+        let relabelled: Vec<(usize, CommitInfo)> = {
+            let mut commits = self.commits.borrow_mut();
+            commits.splice(0..removed, head);
+
+            // Branches may have moved onto commits that are already loaded.
+            commits.iter_mut().enumerate().skip(added).filter_map(|(index, info)| {
+                let heads = branch_heads.get(&info.id).cloned().unwrap_or_default();
+                if heads == info.branch_heads {
+                    return None;
+                }
+
+                info.branch_heads = heads;
+                Some((index, info.clone()))
+            }).collect()
+        };
+
+        self.graph.borrow_mut().splice(0..removed + 1, graph.iter().cloned());
+        self.view().update_history_head(removed, &self.commits.borrow()[..added], &graph, &relabelled);
+    }
+
+    /// The graph rows for commits that are new above the loaded history, followed by
+    /// the row of the first loaded commit joined up to them. None unless they all lead
+    /// into that commit; anything else, such as a fetched branch that forked further
+    /// down, has to be reloaded to slot in by date.
+    fn graph_above(&self, head: &[CommitInfo], removed: usize) -> Option<Vec<GraphRow>> {
+        let graph = self.graph.borrow();
+        let first = graph.get(removed).filter(|x| x.node == 0)?;
+        let first_id = self.commits.borrow().get(removed)?.id;
+
+        let mut builder = GraphBuilder::new();
+        let mut rows: Vec<GraphRow> = head.iter()
+            .map(|x| builder.push(x.id, &self.graph_parents(x)))
             .collect();
 
-        for rev in revwalk {
-            let rev = match rev {
-                Ok(v) => v,
-                Err(_) => continue
-            };
+        // Only the lane coming down from above changes for the first loaded commit.
+        let mut joined = GraphRow {
+            edges: first.edges.iter().filter(|x| x.half == EdgeHalf::Bottom).cloned().collect(),
+            ..first.clone()
+        };
 
-            let commit = repo.find_commit(rev).expect("commit to be found");
-            
-            let branch_heads: Vec<String> = branches.iter()
-                .filter(|(_, tip_commit)| tip_commit.id() == commit.id())
-                .map(|(name, _)| name.clone())
-                .collect();
+        match builder.open_lanes().as_slice() {
+            [] if head.is_empty() => {}
+            [Some((id, color))] if *id == first_id => {
+                for row in rows.iter_mut() {
+                    row.swap_colors(*color, first.color);
+                }
+                joined.edges.insert(0, GraphEdge { from: 0, to: 0, color: first.color, half: EdgeHalf::Top });
+            }
+            _ => return None
+        }
 
-            let info = CommitInfo {
-                id: commit.id(),
-                summary: commit.summary_str().to_string(),
-                short_id: commit.short_id_str().to_string(),
-                author: commit.author_str().to_string(),
-                commit_date: commit.date().to_string(),
-                branch_heads: branch_heads
-            };
+        rows.push(joined);
+        Some(rows)
+    }
+
+    fn on_page_loaded(&self, page: Vec<CommitInfo>) {
+        self.is_loading_page.set(false);
+        self.append_commits(page, true);
+    }
+
+    fn append_commits(&self, page: Vec<CommitInfo>, with_graph: bool) {
+        let start = self.commits.borrow().len();
+        let graph_start = self.graph.borrow().len();
+
+        {
+            let mut commits = self.commits.borrow_mut();
+            let mut graph = self.graph.borrow_mut();
+            let mut builder = self.graph_builder.borrow_mut();
+
+            for info in page {
+                if with_graph {
+                    graph.push(builder.push(info.id, &self.graph_parents(&info)));
+                }
+                commits.push(info);
+            }
+        }
+
+        self.view().append_history(&self.commits.borrow()[start..], &self.graph.borrow()[graph_start..]);
+
+        if self.view().selected_row().is_none() {
+            let selected = self.selected.get().and_then(|x| self.index_of(x));
+            if let Some(index) = selected {
+                self.view().select_row(Some(index));
+                self.view().scroll_to_row(index);
+                self.on_item_selected(index);
+            }
+        }
+    }
+
+    fn load_pending(&self, count: usize) {
+        let ids: Vec<git2::Oid> = {
+            let mut pending = self.pending.borrow_mut();
+            let count = min(count, pending.len());
+            pending.drain(..count).collect()
+        };
+
+        self.is_loading_page.set(true);
+        self.page_client.send(Request::LoadCommits(ids, self.options.get()));
+    }
+
+    fn on_scrolled_near_end(&self) {
+        if self.is_loading_page.get() || self.pending.borrow().is_empty() {
+            return;
+        }
 
-            infos.push(info);
+        self.load_pending(PAGE_SIZE);
+    }
+
+    /// Two selected rows are compared, going from the older to the newer commit.
+    fn on_rows_selected(&self, rows: &[usize]) {
+        if rows.len() != 2 {
+            return;
+        }
+
+        let (from, to) = {
+            let commits = self.commits.borrow();
+            (commits[rows[0].max(rows[1])].clone(), commits[rows[0].min(rows[1])].clone())
+        };
+
+        let side = |info: &CommitInfo| if info.is_sentinel() {
+            DiffSide::WorkingTree
+        } else {
+            DiffSide::Rev(info.id.to_string())
+        };
+
+        self.compared.set(Some((from.id, to.id)));
+        self.parent().compare(side(&from), side(&to));
+    }
+
+    fn compare_with_working_tree(&self, index: usize) {
+        let info = self.commits.borrow()[index].clone();
+
+        if !info.is_sentinel() {
+            self.parent().compare(DiffSide::Rev(info.id.to_string()), DiffSide::WorkingTree);
+        }
+    }
+
+    /// Saves the commits of the given rows to `dir` as a series of patches, numbered
+    /// from the oldest.
+    fn save_patches(&self, rows: &[usize], dir: PathBuf) {
+        let mut rows = rows.to_vec();
+        rows.sort_by(|a, b| b.cmp(a));
+
+        let ids: Vec<git2::Oid> = {
+            let commits = self.commits.borrow();
+            rows.iter()
+                .filter_map(|x| commits.get(*x))
+                .filter(|x| !x.is_sentinel())
+                .map(|x| x.id)
+                .collect()
+        };
+
+        if !ids.is_empty() {
+            self.export_client.send(Request::SavePatches { ids, dir });
         }
+    }
 
-        *self.commits.borrow_mut() = infos;
+    fn on_patches_saved(&self, paths: &[PathBuf]) {
+        let dir = paths.first()
+            .and_then(|x| x.parent())
+            .map(|x| x.display().to_string())
+            .unwrap_or_default();
+
+        let message = match paths.len() {
+            1 => format!("Saved 1 patch to {}", dir),
+            n => format!("Saved {} patches to {}", n, dir)
+        };
 
-        self.view().set_history(&self.commits.borrow());
+        self.view().show_message(&message);
     }
 
     fn on_item_selected(&self, index: usize) {
         let info = &self.commits.borrow()[index];
+        self.selected.set(Some(info.id));
+        self.compared.set(None);
 
         if info.is_sentinel() {
             self.parent().on_uncommitted_changes_selected();
         } else {
-            self.parent().on_commit_selected(&info)
+            self.parent().on_commit_selected(info)
         }
     }
 
     fn start(&self) {
-        self.update_commit_history();
+        self.restore_options();
+        self.reload_commit_history();
         let parent = self.parent();
 
         let result = self.watcher.borrow_mut()
@@ -272,13 +676,31 @@ impl<V: HistoryViewable> HistoryPresenter<V> where V: 'static {
 pub struct HistoryView {
     presenter: Rc<HistoryPresenter<HistoryView>>,
     list_store: gtk::ListStore,
+    graph: Rc<RefCell<Vec<GraphRow>>>,
+    has_sentinel: Rc<Cell<bool>>,
     tree: gtk::TreeView,
-    root: gtk::ScrolledWindow
+    is_restoring_selection: Cell<bool>,
+    row_menu: gtk::Menu,
+    compare_workdir_item: gtk::MenuItem,
+    save_patches_item: gtk::MenuItem,
+    menu_row: Cell<Option<usize>>,
+    scope_combo: gtk::ComboBoxText,
+    first_parent_toggle: gtk::CheckButton,
+    hide_merges_toggle: gtk::CheckButton,
+    range_entry: gtk::Entry,
+    is_syncing_options: Cell<bool>,
+    search_field_combo: gtk::ComboBoxText,
+    search_entry: gtk::SearchEntry,
+    since_entry: gtk::Entry,
+    until_entry: gtk::Entry,
+    search_status: gtk::Label,
+    scroller: gtk::ScrolledWindow,
+    root: gtk::Box
 }
 
 impl HistoryView {
-    fn create_tree(model: &gtk::ListStore) -> gtk::TreeView {
-        fn append_column(tree: &gtk::TreeView, id: i32, title: &str, is_expand: bool) {
+    fn create_tree(model: &gtk::ListStore, graph: &Rc<RefCell<Vec<GraphRow>>>, has_sentinel: &Rc<Cell<bool>>) -> gtk::TreeView {
+        fn append_column(tree: &gtk::TreeView, id: i32, title: &str, is_expand: bool) -> gtk::CellRendererText {
             let column = gtk::TreeViewColumn::new();
             let cell = gtk::CellRendererText::new();
 
@@ -293,25 +715,138 @@ impl HistoryView {
             cell.set_property("ellipsize", &pango::EllipsizeMode::End).unwrap();
 
             tree.append_column(&column);
+            cell
         }
 
         let treeview = gtk::TreeView::new();
-
-        append_column(&treeview, 0, "Summary", true);
+        treeview.get_style_context().add_class("history");
+        treeview.get_selection().set_mode(gtk::SelectionMode::Multiple);
+
+        let graph_column = gtk::TreeViewColumn::new();
+        let graph_cell = gtk::CellRendererPixbuf::new();
+        graph_cell.set_padding(0, 0);
+        graph_cell.set_alignment(0.0, 0.5);
+        graph_column.pack_start(&graph_cell, false);
+        graph_column.set_resizable(true);
+        graph_column.set_title("Graph");
+        treeview.append_column(&graph_column);
+
+        let summary_cell = append_column(&treeview, 0, "Summary", true);
         append_column(&treeview, 1, "Commit", false);
         append_column(&treeview, 2, "Author", true);
         append_column(&treeview, 3, "Date", true);
 
+        // Rows are only as tall as their text, so the graph has to be drawn to match
+        // or the lanes will have gaps between them.
+        let row_height = Rc::new(Cell::new(0));
+        let pixbufs = RefCell::new(graph::RenderCache::default());
+
+        TreeViewColumnExt::set_cell_data_func(&graph_column, &graph_cell, Some(Box::new(clone!(graph, has_sentinel => move |column, cell, model, iter| {
+            let index = match model.get_path(iter).and_then(|p| p.get_indices().first().cloned()) {
+                Some(v) if v >= 0 => v as usize,
+                _ => return
+            };
+
+            if row_height.get() <= 0 {
+                if let Some(tree) = column.get_tree_view() {
+                    row_height.set(summary_cell.get_preferred_height(&tree).1);
+                }
+            }
+
+            let pixbuf = match graph.borrow().get(index) {
+                Some(row) => pixbufs.borrow_mut().render(row, row_height.get().max(1), index == 0 && has_sentinel.get()),
+                None => None
+            };
+
+            cell.set_property("pixbuf", &pixbuf).unwrap();
+        }))));
+
         treeview.set_model(model);
         treeview
     }
 
-    pub fn widget(&self) -> &gtk::ScrolledWindow {
+    pub fn widget(&self) -> &gtk::Box {
         &self.root
     }
 
-    fn add_to_tree_escaped(&self, commit: &CommitInfo) {
-        self.list_store.insert_with_values(None, &[0, 1, 2, 3], &[
+    fn options(&self) -> HistoryOptions {
+        let scope = self.scope_combo.get_active_id()
+            .and_then(|x| HistoryScope::from_str(&x))
+            .unwrap_or(HistoryScope::AllRefs);
+
+        HistoryOptions {
+            scope,
+            first_parent: self.first_parent_toggle.get_active(),
+            hide_merges: self.hide_merges_toggle.get_active()
+        }
+    }
+
+    /// Reads a `YYYY-MM-DD` date from the entry, marking it if it cannot be parsed.
+    /// The outer `None` means the entry holds an invalid date.
+    fn parse_date(entry: &gtk::Entry, is_end_of_day: bool) -> Option<Option<i64>> {
+        let text = entry.get_text().map(|x| x.trim().to_string()).unwrap_or_default();
+        let style = entry.get_style_context();
+
+        if text.is_empty() {
+            style.remove_class("error");
+            return Some(None);
+        }
+
+        let date = chrono::NaiveDate::parse_from_str(&text, "%Y-%m-%d").ok()
+            .and_then(|x| chrono::Local.from_local_date(&x).single());
+
+        match date {
+            Some(date) => {
+                style.remove_class("error");
+                let time = if is_end_of_day { date.and_hms(23, 59, 59) } else { date.and_hms(0, 0, 0) };
+                Some(Some(time.timestamp()))
+            }
+            None => {
+                style.add_class("error");
+                None
+            }
+        }
+    }
+
+    fn on_filter_changed(&self) {
+        let (since, until) = match (HistoryView::parse_date(&self.since_entry, false), HistoryView::parse_date(&self.until_entry, true)) {
+            (Some(since), Some(until)) => (since, until),
+            // Wait until the date has been typed out in full.
+            _ => return
+        };
+
+        let field = self.search_field_combo.get_active_id()
+            .and_then(|x| SearchField::from_str(&x))
+            .unwrap_or(SearchField::Message);
+
+        let filter = HistoryFilter {
+            field,
+            text: self.search_entry.get_text().map(|x| x.to_string()).unwrap_or_default(),
+            since,
+            until
+        };
+
+        self.presenter.on_filter_changed(Some(filter));
+    }
+
+    fn on_range_activated(&self) {
+        let range = self.range_entry.get_text().map(|x| x.to_string()).unwrap_or_default();
+
+        // A range decides which commits are walked, so the scope no longer applies.
+        self.scope_combo.set_sensitive(range.trim().is_empty());
+        self.presenter.on_range_changed(&range);
+    }
+
+    fn on_options_changed(&self) {
+        if self.is_syncing_options.get() {
+            return;
+        }
+
+        self.presenter.on_options_changed(self.options());
+    }
+
+    fn add_to_tree_escaped(&self, position: Option<u32>, commit: &CommitInfo) {
+        self.list_store.insert_with_values(position, &[0, 1, 2, 3], &[
             &markup_escape_text(&commit.summary()),
             &markup_escape_text(&commit.short_id),
             &markup_escape_text(&commit.author),
@@ -319,14 +854,84 @@ impl HistoryView {
         ]);
     }
 
-    fn add_to_tree(&self, commit: &CommitInfo) {
-        self.list_store.insert_with_values(None, &[0, 1, 2, 3], &[
+    fn add_to_tree(&self, position: Option<u32>, commit: &CommitInfo) {
+        // The sentinel is the only row that carries its own markup.
+        if !commit.is_sentinel() {
+            return self.add_to_tree_escaped(position, commit);
+        }
+
+        self.list_store.insert_with_values(position, &[0, 1, 2, 3], &[
             &commit.summary(),
             &commit.short_id,
             &commit.author,
             &commit.commit_date
         ]);
     }
+
+    /// Shows the context menu for the row under the pointer on a right click.
+    fn on_button_pressed(&self, event: &gdk::EventButton) -> bool {
+        if event.get_event_type() != gdk::EventType::ButtonPress || event.get_button() != 3 {
+            return false;
+        }
+
+        let (x, y) = event.get_position();
+        let row = self.tree.get_path_at_pos(x as i32, y as i32)
+            .and_then(|(path, _, _, _)| path)
+            .and_then(|x| x.get_indices().first().map(|x| *x as usize));
+
+        let row = match row {
+            Some(v) => v,
+            None => return false
+        };
+
+        let is_sentinel = self.has_sentinel.get() && row == 0;
+        self.compare_workdir_item.set_sensitive(!is_sentinel);
+        let has_commits = self.menu_rows_for(row).iter().any(|x| !self.has_sentinel.get() || *x != 0);
+        self.save_patches_item.set_sensitive(has_commits);
+        self.menu_row.set(Some(row));
+        self.row_menu.popup_at_pointer(Some(&**event));
+
+        true
+    }
+
+    /// The rows a menu opened on `row` acts on: the selection if it includes the row,
+    /// otherwise the row alone.
+    fn menu_rows_for(&self, row: usize) -> Vec<usize> {
+        let rows: Vec<usize> = self.tree.get_selection().get_selected_rows().0.iter()
+            .filter_map(|x| x.get_indices().first().map(|x| *x as usize))
+            .collect();
+
+        if rows.contains(&row) { rows } else { vec![row] }
+    }
+
+    fn on_save_patches(&self) {
+        let row = try_unwrap!(self.menu_row.get());
+        let rows = self.menu_rows_for(row);
+        let window = self.tree.get_toplevel().and_then(|x| x.downcast::<gtk::Window>().ok());
+
+        let dialog = gtk::FileChooserNative::new(
+            Some("Save Patches To"),
+            window.as_ref(),
+            gtk::FileChooserAction::SelectFolder,
+            Some("_Save"),
+            Some("_Cancel"));
+
+        let accept: i32 = gtk::ResponseType::Accept.into();
+        if dialog.run() == accept {
+            if let Some(dir) = dialog.get_filename() {
+                self.presenter.save_patches(&rows, dir);
+            }
+        }
+    }
+
+    fn on_scrolled(&self) {
+        let adjustment = try_unwrap!(self.scroller.get_vadjustment());
+        let remaining = adjustment.get_upper() - adjustment.get_value() - adjustment.get_page_size();
+
+        if remaining < adjustment.get_page_size() {
+            self.presenter.on_scrolled_near_end();
+        }
+    }
 }
 
 impl HistoryViewable for HistoryView {
@@ -338,23 +943,151 @@ impl HistoryViewable for HistoryView {
             String::static_type()
         ]);
 
-        let treeview = HistoryView::create_tree(&list_store);
+        let graph = Rc::new(RefCell::new(vec![]));
+        let has_sentinel = Rc::new(Cell::new(false));
+        let treeview = HistoryView::create_tree(&list_store, &graph, &has_sentinel);
 
         // Make tree view scrollable
-        let root = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
-        root.set_hexpand(true);
-        root.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
-        root.add(&treeview);
+        let scroller = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+        scroller.set_hexpand(true);
+        scroller.set_vexpand(true);
+        scroller.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+        scroller.add(&treeview);
+
+        let scope_combo = gtk::ComboBoxText::new();
+        scope_combo.append(HistoryScope::Branch.as_str(), "Selected branch");
+        scope_combo.append(HistoryScope::LocalBranches.as_str(), "All local branches");
+        scope_combo.append(HistoryScope::AllRefs.as_str(), "All refs");
+        scope_combo.set_active_id(HistoryScope::AllRefs.as_str());
+
+        let first_parent_toggle = gtk::CheckButton::new_with_label("First parent only");
+        let hide_merges_toggle = gtk::CheckButton::new_with_label("Hide merges");
+
+        let toolbar = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        toolbar.set_border_width(4);
+        toolbar.pack_start(&scope_combo, false, false, 0);
+        toolbar.pack_start(&first_parent_toggle, false, false, 0);
+        toolbar.pack_start(&hide_merges_toggle, false, false, 0);
+
+        let range_entry = gtk::Entry::new();
+        range_entry.set_placeholder_text("Revision range, e.g. main..feature");
+        range_entry.set_width_chars(30);
+        toolbar.pack_end(&range_entry, false, false, 0);
+
+        let compare_workdir_item = gtk::MenuItem::new_with_label("Compare working tree with this commit");
+        let row_menu = gtk::Menu::new();
+        let save_patches_item = gtk::MenuItem::new_with_label("Save as patches…");
+        row_menu.append(&compare_workdir_item);
+        row_menu.append(&save_patches_item);
+        row_menu.show_all();
+
+        let search_field_combo = gtk::ComboBoxText::new();
+        search_field_combo.append(SearchField::Message.as_str(), "Message");
+        search_field_combo.append(SearchField::Person.as_str(), "Author or committer");
+        search_field_combo.append(SearchField::Sha.as_str(), "SHA");
+        search_field_combo.append(SearchField::Path.as_str(), "Path");
+        search_field_combo.append(SearchField::Content.as_str(), "Added or removed text");
+        search_field_combo.set_active_id(SearchField::Message.as_str());
+
+        let search_entry = gtk::SearchEntry::new();
+        search_entry.set_hexpand(true);
+
+        let since_entry = gtk::Entry::new();
+        since_entry.set_placeholder_text("Since YYYY-MM-DD");
+        since_entry.set_width_chars(16);
+
+        let until_entry = gtk::Entry::new();
+        until_entry.set_placeholder_text("Until YYYY-MM-DD");
+        until_entry.set_width_chars(16);
+
+        let search_status = gtk::Label::new(None);
+        search_status.get_style_context().add_class("dim-label");
+
+        let search_bar = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        search_bar.set_border_width(4);
+        search_bar.pack_start(&search_field_combo, false, false, 0);
+        search_bar.pack_start(&search_entry, true, true, 0);
+        search_bar.pack_start(&since_entry, false, false, 0);
+        search_bar.pack_start(&until_entry, false, false, 0);
+        search_bar.pack_start(&search_status, false, false, 0);
+
+        let root = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        root.pack_start(&search_bar, false, false, 0);
+        root.pack_start(&toolbar, false, false, 0);
+        root.pack_start(&scroller, true, true, 0);
 
         let view = view!(HistoryView {
             presenter: HistoryPresenter::new(parent),
-            list_store: list_store,
+            list_store,
+            graph,
+            has_sentinel,
             tree: treeview,
-            root: root
+            is_restoring_selection: Cell::new(false),
+            row_menu,
+            compare_workdir_item,
+            save_patches_item,
+            menu_row: Cell::new(None),
+            scope_combo,
+            first_parent_toggle,
+            hide_merges_toggle,
+            range_entry,
+            is_syncing_options: Cell::new(false),
+            search_field_combo,
+            search_entry,
+            since_entry,
+            until_entry,
+            search_status,
+            scroller,
+            root
         });
 
+        view.scope_combo.connect_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_options_changed();
+        }));
+
+        view.first_parent_toggle.connect_toggled(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_options_changed();
+        }));
+
+        view.hide_merges_toggle.connect_toggled(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_options_changed();
+        }));
+
+        view.range_entry.connect_activate(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_range_activated();
+        }));
+
+        view.search_field_combo.connect_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_filter_changed();
+        }));
+
+        view.search_entry.connect_search_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_filter_changed();
+        }));
+
+        view.since_entry.connect_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_filter_changed();
+        }));
+
+        view.until_entry.connect_changed(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_filter_changed();
+        }));
+
         view.tree.connect_cursor_changed(weak!(view => move |_| {
             if let Some(view) = view.upgrade() {
+                // Selecting a second row compares the two instead.
+                if view.is_restoring_selection.get() || view.tree.get_selection().count_selected_rows() > 1 {
+                    return;
+                }
+
                 if let Some(idx) = view.selected_row() {
                     view.presenter.on_item_selected(idx);
                 }
@@ -363,13 +1096,93 @@ impl HistoryViewable for HistoryView {
             }
         }));
 
+        view.tree.connect_button_press_event(weak!(view => move |_, event| {
+            let view = try_upgrade!(view, Inhibit(false));
+            Inhibit(view.on_button_pressed(event))
+        }));
+
+        view.compare_workdir_item.connect_activate(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+
+            if let Some(row) = view.menu_row.get() {
+                view.presenter.compare_with_working_tree(row);
+            }
+        }));
+
+        view.save_patches_item.connect_activate(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.on_save_patches();
+        }));
+
+        view.tree.get_selection().connect_changed(weak!(view => move |selection| {
+            let view = try_upgrade!(view);
+
+            if view.is_restoring_selection.get() {
+                return;
+            }
+
+            let rows: Vec<usize> = selection.get_selected_rows().0.iter()
+                .filter_map(|x| x.get_indices().first().map(|x| *x as usize))
+                .collect();
+
+            view.presenter.on_rows_selected(&rows);
+        }));
+
+        if let Some(adjustment) = view.scroller.get_vadjustment() {
+            adjustment.connect_value_changed(weak!(view => move |_| {
+                let view = try_upgrade!(view);
+                view.on_scrolled();
+            }));
+
+            // Also catches the first page being too short to scroll at all.
+            adjustment.connect_changed(weak!(view => move |_| {
+                let view = try_upgrade!(view);
+                view.on_scrolled();
+            }));
+        }
+
         view.presenter.start();
         
         view
     }
 
     fn refresh_commit_history(&self) {
-        self.presenter.update_commit_history();
+        self.presenter.restore_options();
+        self.presenter.reload_commit_history();
+    }
+
+    fn select_commit(&self, id: git2::Oid) {
+        self.presenter.select_commit(id);
+    }
+
+    fn children_of(&self, id: git2::Oid) -> Vec<CommitLink> {
+        self.presenter.children_of(id)
+    }
+
+    fn set_search_status(&self, status: &str) {
+        self.search_status.set_text(status);
+    }
+
+    fn show_message(&self, message: &str) {
+        let window = self.tree.get_toplevel().and_then(|x| x.downcast::<gtk::Window>().ok());
+        let dialog = gtk::MessageDialog::new(
+            window.as_ref(),
+            gtk::DialogFlags::MODAL,
+            gtk::MessageType::Info,
+            gtk::ButtonsType::Close,
+            message
+        );
+
+        dialog.run();
+        dialog.destroy();
+    }
+
+    fn set_options(&self, options: HistoryOptions) {
+        self.is_syncing_options.set(true);
+        self.scope_combo.set_active_id(options.scope.as_str());
+        self.first_parent_toggle.set_active(options.first_parent);
+        self.hide_merges_toggle.set_active(options.hide_merges);
+        self.is_syncing_options.set(false);
     }
 
     fn handle_error(&self, error: impl fmt::Display) {
@@ -386,33 +1199,90 @@ impl HistoryViewable for HistoryView {
         None
     }
 
-    fn set_history(&self, commits: &[CommitInfo]) {
-        let cursor = self.tree.get_cursor();
+    fn select_row(&self, index: Option<usize>) {
+        self.is_restoring_selection.set(true);
+
+        match index {
+            Some(index) => {
+                let path = gtk::TreePath::new_from_indicesv(&[index as i32]);
+                self.tree.set_cursor(&path, None::<&gtk::TreeViewColumn>, false);
+            }
+            None => self.tree.get_selection().unselect_all()
+        }
+
+        self.is_restoring_selection.set(false);
+    }
+
+    fn select_rows(&self, rows: &[usize]) {
+        self.is_restoring_selection.set(true);
+
+        let selection = self.tree.get_selection();
+        selection.unselect_all();
+
+        for row in rows {
+            selection.select_path(&gtk::TreePath::new_from_indicesv(&[*row as i32]));
+        }
+
+        self.is_restoring_selection.set(false);
+    }
+
+    fn first_visible_row(&self) -> Option<usize> {
+        let (start, _) = self.tree.get_visible_range()?;
+        start.get_indices().first().map(|x| *x as usize)
+    }
+
+    fn scroll_to_row(&self, index: usize) {
+        let path = gtk::TreePath::new_from_indicesv(&[index as i32]);
+        self.tree.scroll_to_cell(Some(&path), None::<&gtk::TreeViewColumn>, true, 0.0, 0.0);
+    }
+
+    fn set_history(&self, commits: &[CommitInfo], graph: &[GraphRow]) {
+        self.is_restoring_selection.set(true);
         self.list_store.clear();
+        self.is_restoring_selection.set(false);
 
-        let mut iter = commits.iter();
+        *self.graph.borrow_mut() = graph.to_vec();
+        self.has_sentinel.set(commits.first().map(|x| x.is_sentinel()).unwrap_or(false));
 
-        if let Some(first_commit) = iter.next() {
-            if first_commit.is_sentinel() {
-                self.add_to_tree(first_commit);
-            } else {
-                self.add_to_tree_escaped(first_commit);
+        for commit in commits {
+            self.add_to_tree(None, commit);
+        }
+
+        self.tree.show_all();
+    }
+    fn append_history(&self, commits: &[CommitInfo], graph: &[GraphRow]) {
+        self.graph.borrow_mut().extend_from_slice(graph);
+
+        for commit in commits {
+            self.add_to_tree(None, commit);
+        }
+    }
+
+    fn update_history_head(&self, removed: usize, head: &[CommitInfo], graph: &[GraphRow], relabelled: &[(usize, CommitInfo)]) {
+        // Removing the row under the cursor moves it; the presenter restores it after.
+        self.is_restoring_selection.set(true);
+
+        for _ in 0..removed {
+            if let Some(iter) = self.list_store.get_iter_first() {
+                self.list_store.remove(&iter);
             }
         }
 
-        for commit in iter {
-            self.add_to_tree_escaped(commit);
+        for (n, commit) in head.iter().enumerate() {
+            self.add_to_tree(Some(n as u32), commit);
         }
 
-        let col = match cursor.1 {
-            Some(ref v) => Some(v),
-            None => None
-        };
+        self.is_restoring_selection.set(false);
+
+        self.graph.borrow_mut().splice(0..removed + 1, graph.iter().cloned());
+        self.has_sentinel.set(head.first().map(|x| x.is_sentinel()).unwrap_or(false));
 
-        if let Some(path) = cursor.0 {
-            self.tree.set_cursor(&path, col, false);
+        for (index, commit) in relabelled {
+            if let Some(iter) = self.list_store.iter_nth_child(None, *index as i32) {
+                self.list_store.set_value(&iter, 0, &markup_escape_text(&commit.summary()).to_value());
+            }
         }
 
-        self.tree.show_all();
+        self.tree.queue_draw();
     }
 }
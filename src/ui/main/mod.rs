@@ -17,64 +17,29 @@ mod branch;
 mod history;
 mod filestatus;
 mod diff;
+mod graph;
+mod binary;
+mod highlight;
+mod syntax;
+mod pattern;
 
 use std::rc::{Rc, Weak};
 use std::cell::RefCell;
+use std::fs;
 use std::path::Path;
 use std::fmt;
 
 use git2;
 use gtk::prelude::*;
 use gtk;
+use gdk;
 
+use crate::git::worker::Worker;
 use crate::ui::Window;
 use crate::ui::main::branch::{BranchViewable, BranchView};
 use crate::ui::AsMessageDialog;
 
-#[derive(Debug)]
-pub struct CommitInfo {
-    pub id: git2::Oid,
-    summary: String,
-    pub short_id: String,
-    pub author: String,
-    pub commit_date: String,
-    pub branch_heads: Vec<String>
-}
-
-impl CommitInfo {
-    pub fn summary(&self) -> String {
-        if self.branch_heads.len() == 0 {
-            return self.summary.to_string();
-        }
-
-        let mut out = String::new();
-        
-        for name in &self.branch_heads {
-            out.push_str(&format!("[{}] ", &name));
-        }
-        out.push_str(&self.summary);
-        out
-    } 
-}
-
-const UNCOMMITTED_STR: &'static str = "<b>Uncommitted changes</b>";
-
-impl CommitInfo {
-    pub fn uncommitted_sentinel() -> CommitInfo {
-        CommitInfo {
-            id: git2::Oid::zero(),
-            summary: UNCOMMITTED_STR.into(),
-            short_id: "*".into(),
-            author: "*".into(),
-            commit_date: "*".into(),
-            branch_heads: vec![]
-        }
-    }
-
-    pub fn is_sentinel(&self) -> bool {
-        self.id == git2::Oid::zero() && self.summary == UNCOMMITTED_STR
-    }
-}
+pub use crate::git::history::CommitInfo;
 
 struct MainPresenter<V> {
     view: RefCell<Weak<V>>,
@@ -87,6 +52,9 @@ pub trait MainViewable {
     fn show(&self);
     fn set_title(&self, path: &str);
     fn open_repo_selector(&self);
+    fn open_patch_selector(&self);
+    fn clipboard_text(&self) -> Option<String>;
+    fn preview_patch(&self, text: &str, source: &str);
     fn handle_error(&self, error: impl fmt::Display);
 }
 
@@ -108,10 +76,31 @@ impl<V: MainViewable> MainPresenter<V> {
         self.view().open_repo_selector();
     }
 
+    fn apply_file_clicked(&self) {
+        self.view().open_patch_selector();
+    }
+
+    fn apply_clipboard_clicked(&self) {
+        match self.view().clipboard_text() {
+            Some(text) => self.view().preview_patch(&text, "Clipboard"),
+            None => self.view().handle_error("The clipboard holds no text.")
+        }
+    }
+
+    fn select_patch(&self, path: &Path) {
+        match fs::read(path) {
+            Ok(data) => {
+                let name = path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+                self.view().preview_patch(&String::from_utf8_lossy(&data), &name);
+            }
+            Err(err) => self.view().handle_error(err)
+        }
+    }
+
     fn select_repo(&self, repo_dir: &Path) {
         use crate::Config;
 
-        let repo = match git2::Repository::open(&repo_dir) {
+        let repo = match git2::Repository::open(repo_dir) {
             Ok(repo) => repo,
             Err(err) => {
                 self.view().handle_error(err);
@@ -130,7 +119,7 @@ impl<V: MainViewable> MainPresenter<V> {
     fn update_branches(&self) {
         let repo = self.repo.borrow();
         let mut names = vec![];
-        for entry in repo.branches(Some(git2::BranchType::Local)).unwrap().into_iter() {
+        for entry in repo.branches(Some(git2::BranchType::Local)).unwrap() {
             let (branch, _) = entry.unwrap();
             let name = branch.name().unwrap().unwrap();
             names.push(name.to_string());
@@ -152,6 +141,7 @@ pub struct MainWindow {
     presenter: MainPresenter<MainWindow>,
     header: MainWindowHeader,
     window: gtk::Window,
+    worker: Rc<Worker>,
     sidebar_view: Rc<SidebarView>,
     branch_view: Rc<BranchView>,
     branches: RefCell<Vec<String>>
@@ -209,6 +199,41 @@ impl MainViewable for MainWindow {
         }
     }
 
+    fn open_patch_selector(&self) {
+        let dialog = gtk::FileChooserNative::new(
+            Some("Select Patch"),
+            Some(&self.window),
+            gtk::FileChooserAction::Open,
+            Some("_Open"),
+            Some("_Cancel"));
+
+        let filter = gtk::FileFilter::new();
+        filter.set_name("Patches and mailboxes");
+        for pattern in &["*.patch", "*.diff", "*.mbox", "*.eml"] {
+            filter.add_pattern(pattern);
+        }
+        dialog.add_filter(&filter);
+
+        let all = gtk::FileFilter::new();
+        all.set_name("All files");
+        all.add_pattern("*");
+        dialog.add_filter(&all);
+
+        if dialog.run() == -3 {
+            if let Some(filename) = dialog.get_filename() {
+                self.presenter.select_patch(&filename);
+            }
+        }
+    }
+
+    fn clipboard_text(&self) -> Option<String> {
+        gtk::Clipboard::get(&gdk::SELECTION_CLIPBOARD).wait_for_text().map(|x| x.to_string())
+    }
+
+    fn preview_patch(&self, text: &str, source: &str) {
+        self.branch_view.preview_patch(text, source);
+    }
+
     fn show(&self) {
         self.window.show_all();
     }
@@ -221,7 +246,10 @@ impl MainViewable for MainWindow {
 
 struct MainWindowHeader {
     root: gtk::HeaderBar,
-    open_button: gtk::Button
+    open_button: gtk::Button,
+    apply_file_item: gtk::MenuItem,
+    apply_clipboard_item: gtk::MenuItem,
+    spinner: gtk::Spinner
 }
 
 impl MainWindowHeader {
@@ -247,7 +275,7 @@ impl SidebarView {
         
         {
             let iter = self.branch_iter.borrow();
-            self.tree_store.swap(&branch_iter, &*iter);
+            self.tree_store.swap(&branch_iter, &iter);
             self.tree_store.remove(&iter);
         }
         
@@ -274,6 +302,7 @@ impl MainWindow {
             Inhibit(false)
         });
 
+        let worker = Rc::new(Worker::new(repo.path().to_path_buf()));
         let repo = Rc::new(repo);
         let initial_branch = {
             let repo = repo.clone();
@@ -291,13 +320,14 @@ impl MainWindow {
                 .to_string()
         };
         
-        let branch_view = BranchView::new(&window, Rc::clone(&repo), initial_branch);
+        let branch_view = BranchView::new(&window, Rc::clone(&repo), Rc::clone(&worker), initial_branch);
         sidebar_view.root.add2(branch_view.widget());
 
         let view = view!(MainWindow {
             presenter: MainPresenter::new(Rc::clone(&repo)),
             header,
             window,
+            worker,
             sidebar_view: Rc::new(sidebar_view),
             branch_view,
             branches: RefCell::new(vec![])
@@ -311,10 +341,25 @@ impl MainWindow {
             }
         }));
 
+        view.header.apply_file_item.connect_activate(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.presenter.apply_file_clicked();
+        }));
+
+        view.header.apply_clipboard_item.connect_activate(weak!(view => move |_| {
+            let view = try_upgrade!(view);
+            view.presenter.apply_clipboard_clicked();
+        }));
+
         {
             // let sidebar_view = &view.sidebar_view;
             view.sidebar_view.tree_view.connect_cursor_changed(weak!(view => move |_| {
                 if let Some(view) = view.upgrade() {
+                    // Selecting a second branch compares the two instead.
+                    if view.sidebar_view.tree_view.get_selection().count_selected_rows() > 1 {
+                        return;
+                    }
+
                     let idxs = view.sidebar_view.tree_view.get_cursor().0.unwrap().get_indices();
                     if idxs.len() < 2 {
                         return;
@@ -331,6 +376,30 @@ impl MainWindow {
             }));
         }
 
+        view.sidebar_view.tree_view.get_selection().connect_changed(weak!(view => move |selection| {
+            let view = try_upgrade!(view);
+
+            let branches: Vec<usize> = selection.get_selected_rows().0.iter()
+                .map(|x| x.get_indices())
+                .filter(|x| x.len() == 2 && x[1] >= 0)
+                .map(|x| x[1] as usize)
+                .collect();
+
+            view.compare_branches(&branches);
+        }));
+
+        gtk::timeout_add(100, weak!(view => move || {
+            let view = try_upgrade!(view, gtk::Continue(false));
+
+            if view.worker.is_busy() {
+                view.header.spinner.start();
+            } else {
+                view.header.spinner.stop();
+            }
+
+            gtk::Continue(true)
+        }));
+
         view.presenter.start();
 
         // Hack to make the pane be at 50% on first load
@@ -339,6 +408,15 @@ impl MainWindow {
         Ok(view)
     }
 
+    fn compare_branches(&self, indices: &[usize]) {
+        if indices.len() != 2 {
+            return;
+        }
+
+        let branches = self.branches.borrow();
+        self.branch_view.compare(&branches[indices[0]], &branches[indices[1]]);
+    }
+
     fn create_sidebar() -> SidebarView {
         let tree_store = gtk::TreeStore::new(&[
             String::static_type()
@@ -350,6 +428,7 @@ impl MainWindow {
         let tree_view = gtk::TreeView::new();
         tree_view.set_model(&tree_store);
         tree_view.set_headers_visible(false);
+        tree_view.get_selection().set_mode(gtk::SelectionMode::Multiple);
 
         let renderer_name = gtk::CellRendererText::new();
         let column_name = gtk::TreeViewColumn::new();
@@ -418,9 +497,27 @@ impl MainWindow {
         let open_button = gtk::Button::new_with_label("Open");
         header_bar.pack_end(&open_button);
 
+        let apply_file_item = gtk::MenuItem::new_with_label("From file…");
+        let apply_clipboard_item = gtk::MenuItem::new_with_label("From clipboard");
+        let apply_menu = gtk::Menu::new();
+        apply_menu.append(&apply_file_item);
+        apply_menu.append(&apply_clipboard_item);
+        apply_menu.show_all();
+
+        let apply_button = gtk::MenuButton::new();
+        apply_button.set_label("Apply patch…");
+        apply_button.set_popup(&apply_menu);
+        header_bar.pack_end(&apply_button);
+
+        let spinner = gtk::Spinner::new();
+        header_bar.pack_end(&spinner);
+
         MainWindowHeader {
             root: header_bar,
-            open_button: open_button
+            open_button,
+            apply_file_item,
+            apply_clipboard_item,
+            spinner
         }
     }
 }
@@ -429,6 +526,9 @@ impl MainWindow {
 pub struct TreeItem {
     id: git2::Oid,
     path: String,
+    /// The path as shown, with both paths for renames and copies.
+    label: String,
+    annotations: String,
     delta: git2::Delta,
     is_selected: bool
 }
@@ -0,0 +1,163 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use regex::{self, Regex, RegexBuilder};
+
+/// A search pattern, either plain text or a regular expression in the syntax of the
+/// `regex` crate. Searches take time in proportion to the length of the text, whatever
+/// the pattern.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    regex: Regex
+}
+
+/// Gives the last line of what the `regex` crate reports, which says what is wrong
+/// without the copy of the pattern above it.
+fn describe(error: &regex::Error) -> String {
+    match *error {
+        regex::Error::CompiledTooBig(_) => "The pattern is too complex".to_string(),
+        _ => {
+            let text = error.to_string();
+            let reason = text.lines().last().unwrap_or("").trim_start_matches("error: ");
+            let mut chars = reason.chars();
+
+            match chars.next() {
+                Some(c) => c.to_uppercase().chain(chars).collect(),
+                None => "Invalid pattern".to_string()
+            }
+        }
+    }
+}
+
+impl Pattern {
+    pub fn new(text: &str, is_regex: bool, is_case_sensitive: bool) -> Result<Pattern, String> {
+        let source = if is_regex { Cow::Borrowed(text) } else { Cow::Owned(regex::escape(text)) };
+
+        RegexBuilder::new(&source)
+            .case_insensitive(!is_case_sensitive)
+            .build()
+            .map(|regex| Pattern { regex })
+            .map_err(|e| describe(&e))
+    }
+
+    /// Finds the byte ranges of every match that does not overlap an earlier one.
+    /// Matches of nothing are skipped.
+    pub fn find_all(&self, text: &str) -> Vec<Range<usize>> {
+        self.regex.find_iter(text)
+            .map(|x| x.start()..x.end())
+            .filter(|x| !x.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find<'a>(pattern: &str, text: &'a str) -> Vec<&'a str> {
+        let pattern = Pattern::new(pattern, true, true).unwrap();
+        pattern.find_all(text).into_iter().map(|x| &text[x]).collect()
+    }
+
+    #[test]
+    fn plain_text_is_matched_literally() {
+        let pattern = Pattern::new("a.b", false, true).unwrap();
+        assert_eq!(pattern.find_all("axb a.b"), vec![4..7]);
+    }
+
+    #[test]
+    fn case_is_ignored_unless_asked_for() {
+        let pattern = Pattern::new("Foo", false, false).unwrap();
+        assert_eq!(pattern.find_all("foo FOO"), vec![0..3, 4..7]);
+        assert_eq!(find("[a-c]+", "ABC abc"), vec!["abc"]);
+
+        let pattern = Pattern::new("[a-c]+", true, false).unwrap();
+        assert_eq!(pattern.find_all("ABC"), vec![0..3]);
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert_eq!(find("\\d+", "a12b345"), vec!["12", "345"]);
+        assert_eq!(find("[^a-z ]+", "ab CD ef"), vec!["CD"]);
+        assert_eq!(find("\\w+\\s\\w", "foo bar"), vec!["foo b"]);
+        assert_eq!(find("[\\d_]+", "a1_2b"), vec!["1_2"]);
+    }
+
+    #[test]
+    fn anchors_and_word_boundaries() {
+        assert_eq!(find("^a", "aaa"), vec!["a"]);
+        assert_eq!(find("a$", "aaa"), vec!["a"]);
+        assert_eq!(find("\\bcat\\b", "cat concat cat"), vec!["cat", "cat"]);
+    }
+
+    #[test]
+    fn quantifiers_are_greedy() {
+        assert_eq!(find("a*", "baab"), vec!["aa"]);
+        assert_eq!(find("<.*>", "<a><b>"), vec!["<a><b>"]);
+        assert_eq!(find("colou?r", "color colour"), vec!["color", "colour"]);
+        assert_eq!(find("a{2,3}", "aaaaaaa"), vec!["aaa", "aaa"]);
+        assert_eq!(find("a{2}", "aaaaa"), vec!["aa", "aa"]);
+        assert_eq!(find("a{2,}", "a aaaa"), vec!["aaaa"]);
+    }
+
+    #[test]
+    fn alternatives_prefer_the_first_that_matches() {
+        assert_eq!(find("a|ab", "ab"), vec!["a"]);
+        assert_eq!(find("(ab|a)c", "ac abc"), vec!["ac", "abc"]);
+        assert_eq!(find("x(|y)", "xy"), vec!["x"]);
+        assert_eq!(find("x(y|)", "xy"), vec!["xy"]);
+    }
+
+    #[test]
+    fn empty_matches_are_skipped() {
+        assert_eq!(find("x*", "abc"), Vec::<&str>::new());
+        assert_eq!(find("(a*)*b", "aab"), vec!["aab"]);
+    }
+
+    #[test]
+    fn matches_use_byte_offsets() {
+        let pattern = Pattern::new("ü+", true, true).unwrap();
+        assert_eq!(pattern.find_all("aüüb"), vec![1..5]);
+    }
+
+    #[test]
+    fn pathological_patterns_finish_quickly() {
+        let text = "a".repeat(5000);
+        assert_eq!(find("(a|a)*b", &text), Vec::<&str>::new());
+        assert_eq!(find("(a*)*c", &text), Vec::<&str>::new());
+
+        let long = "x".repeat(200_000);
+        assert_eq!(find(".*", &long).len(), 1);
+    }
+
+    #[test]
+    fn invalid_patterns_are_refused() {
+        assert!(Pattern::new("(a", true, true).is_err());
+        assert!(Pattern::new("a)", true, true).is_err());
+        assert!(Pattern::new("*a", true, true).is_err());
+        assert!(Pattern::new("[a-", true, true).is_err());
+        assert!(Pattern::new("a{1,x}", true, true).is_err());
+        assert!(Pattern::new("(a{1000}){1000}", true, true).is_err());
+    }
+
+    #[test]
+    fn errors_say_what_is_wrong() {
+        assert_eq!(Pattern::new("(a", true, true).unwrap_err(), "Unclosed group");
+        assert_eq!(Pattern::new("(a{1000}){1000}", true, true).unwrap_err(), "The pattern is too complex");
+    }
+}
@@ -0,0 +1,373 @@
+// Sourcepan - a Gtk+ Git client written in Rust
+// Copyright (C) 2018  Brendan Molloy <brendan@bbqsrc.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 as 
+// published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::path::Path;
+
+use super::highlight::{Span, Style};
+
+/// Just enough about a language's lexical structure to color keywords, comments,
+/// strings, numbers and type names.
+pub struct Language {
+    extensions: &'static [&'static str],
+    keywords: &'static [&'static str],
+    line_comment: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    /// String delimiters, longest first so that `"""` wins over `"`.
+    strings: &'static [&'static str],
+    /// The delimiters whose strings may continue on the following lines.
+    multiline_strings: &'static [&'static str],
+    /// Whether capitalized identifiers are type names.
+    has_capitalized_types: bool
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "char", "class", "const", "constexpr", "continue", "default",
+    "delete", "do", "double", "else", "enum", "explicit", "extern", "false", "float", "for",
+    "friend", "goto", "if", "inline", "int", "long", "namespace", "new", "nullptr", "operator",
+    "private", "protected", "public", "register", "return", "short", "signed", "sizeof",
+    "static", "struct", "switch", "template", "this", "throw", "true", "try", "catch",
+    "typedef", "typename", "union", "unsigned", "using", "virtual", "void", "volatile", "while"
+];
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        extensions: &["rs"],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+            "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+            "trait", "true", "type", "unsafe", "use", "where", "while"
+        ],
+        line_comment: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\""],
+        multiline_strings: &["\""],
+        has_capitalized_types: true
+    },
+    Language {
+        extensions: &["c", "h", "cc", "cpp", "cxx", "hpp", "hxx", "m", "mm"],
+        keywords: C_KEYWORDS,
+        line_comment: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\"", "'"],
+        multiline_strings: &[],
+        has_capitalized_types: true
+    },
+    Language {
+        extensions: &["java", "kt", "kts", "scala", "cs", "swift"],
+        keywords: &[
+            "abstract", "break", "case", "catch", "class", "const", "continue", "default", "do",
+            "else", "enum", "extends", "false", "final", "finally", "for", "fun", "func", "if",
+            "implements", "import", "in", "interface", "is", "let", "new", "null", "object",
+            "override", "package", "private", "protected", "public", "return", "static", "super",
+            "switch", "this", "throw", "throws", "true", "try", "val", "var", "void", "when", "while"
+        ],
+        line_comment: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\"\"\"", "\"", "'"],
+        multiline_strings: &["\"\"\""],
+        has_capitalized_types: true
+    },
+    Language {
+        extensions: &["js", "jsx", "mjs", "cjs", "ts", "tsx"],
+        keywords: &[
+            "async", "await", "break", "case", "catch", "class", "const", "continue", "default",
+            "delete", "do", "else", "export", "extends", "false", "finally", "for", "from",
+            "function", "if", "import", "in", "instanceof", "interface", "let", "new", "null",
+            "of", "return", "static", "super", "switch", "this", "throw", "true", "try", "type",
+            "typeof", "undefined", "var", "void", "while", "yield"
+        ],
+        line_comment: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\"", "'", "`"],
+        multiline_strings: &["`"],
+        has_capitalized_types: true
+    },
+    Language {
+        extensions: &["go"],
+        keywords: &[
+            "break", "case", "chan", "const", "continue", "default", "defer", "else",
+            "fallthrough", "false", "for", "func", "go", "goto", "if", "import", "interface",
+            "map", "nil", "package", "range", "return", "select", "struct", "switch", "true",
+            "type", "var"
+        ],
+        line_comment: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\"", "'", "`"],
+        multiline_strings: &["`"],
+        has_capitalized_types: false
+    },
+    Language {
+        extensions: &["py", "pyw", "pyi"],
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+            "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return",
+            "self", "True", "try", "while", "with", "yield"
+        ],
+        line_comment: &["#"],
+        block_comment: None,
+        strings: &["\"\"\"", "'''", "\"", "'"],
+        multiline_strings: &["\"\"\"", "'''"],
+        has_capitalized_types: true
+    },
+    Language {
+        extensions: &["rb", "rake", "gemspec"],
+        keywords: &[
+            "alias", "and", "begin", "break", "case", "class", "def", "defined?", "do", "else",
+            "elsif", "end", "ensure", "false", "for", "if", "in", "module", "next", "nil", "not",
+            "or", "redo", "rescue", "retry", "return", "self", "super", "then", "true", "undef",
+            "unless", "until", "when", "while", "yield"
+        ],
+        line_comment: &["#"],
+        block_comment: None,
+        strings: &["\"", "'"],
+        multiline_strings: &[],
+        has_capitalized_types: true
+    },
+    Language {
+        extensions: &["sh", "bash", "zsh"],
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+            "in", "local", "return", "then", "until", "while"
+        ],
+        line_comment: &["#"],
+        block_comment: None,
+        strings: &["\"", "'"],
+        multiline_strings: &["\"", "'"],
+        has_capitalized_types: false
+    },
+    Language {
+        extensions: &["toml", "yml", "yaml", "ini", "cfg", "conf"],
+        keywords: &["true", "false", "yes", "no", "null", "on", "off"],
+        line_comment: &["#", ";"],
+        block_comment: None,
+        strings: &["\"\"\"", "\"", "'"],
+        multiline_strings: &["\"\"\""],
+        has_capitalized_types: false
+    },
+    Language {
+        extensions: &["css", "scss", "less"],
+        keywords: &["important", "inherit", "initial", "none", "auto"],
+        line_comment: &[],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\"", "'"],
+        multiline_strings: &[],
+        has_capitalized_types: false
+    },
+    Language {
+        extensions: &["json"],
+        keywords: &["true", "false", "null"],
+        line_comment: &[],
+        block_comment: None,
+        strings: &["\""],
+        multiline_strings: &[],
+        has_capitalized_types: false
+    }
+];
+
+/// What a line starts inside of, carried over from the line before it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum State {
+    Normal,
+    Comment,
+    String(&'static str)
+}
+
+// Deriving needs `#[default]`, which is newer than the compilers this crate supports.
+#[allow(clippy::derivable_impls)]
+impl Default for State {
+    fn default() -> State {
+        State::Normal
+    }
+}
+
+impl Language {
+    pub fn for_path(path: &str) -> Option<&'static Language> {
+        let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+        LANGUAGES.iter().find(|x| x.extensions.contains(&extension.as_str()))
+    }
+
+    /// Colors one line, returning the spans and the state the next line starts in.
+    pub fn highlight(&self, text: &str, state: State) -> (Vec<Span>, State) {
+        let mut spans = vec![];
+        let mut state = state;
+        let mut i = 0;
+
+        while i < text.len() {
+            let rest = &text[i..];
+
+            match state {
+                State::Comment => {
+                    let end = self.block_comment.map(|x| x.1).unwrap_or("");
+                    let (len, is_closed) = match rest.find(end) {
+                        Some(n) => (n + end.len(), true),
+                        None => (rest.len(), false)
+                    };
+                    spans.push(Span { range: i..i + len, style: Style::Comment });
+                    i += len;
+                    if is_closed {
+                        state = State::Normal;
+                    }
+                    continue;
+                }
+                State::String(delimiter) => {
+                    let (len, is_closed) = string_end(rest, delimiter);
+                    spans.push(Span { range: i..i + len, style: Style::String });
+                    i += len;
+                    if is_closed {
+                        state = State::Normal;
+                    }
+                    continue;
+                }
+                State::Normal => {}
+            }
+
+            if self.line_comment.iter().any(|x| rest.starts_with(x)) {
+                spans.push(Span { range: i..text.len(), style: Style::Comment });
+                break;
+            }
+
+            if let Some((start, _)) = self.block_comment {
+                if rest.starts_with(start) {
+                    state = State::Comment;
+                    spans.push(Span { range: i..i + start.len(), style: Style::Comment });
+                    i += start.len();
+                    continue;
+                }
+            }
+
+            if let Some(delimiter) = self.strings.iter().find(|x| rest.starts_with(*x)) {
+                let (len, is_closed) = string_end(&rest[delimiter.len()..], delimiter);
+                spans.push(Span { range: i..i + delimiter.len() + len, style: Style::String });
+                i += delimiter.len() + len;
+                if !is_closed && self.multiline_strings.contains(delimiter) {
+                    state = State::String(delimiter);
+                }
+                continue;
+            }
+
+            let c = rest.chars().next().unwrap_or(' ');
+
+            if c.is_alphanumeric() || c == '_' {
+                let len = rest.find(|x: char| !(x.is_alphanumeric() || x == '_' || x == '?'))
+                    .unwrap_or(rest.len());
+                let word = &rest[..len];
+
+                let style = if c.is_ascii_digit() {
+                    Some(Style::Number)
+                } else if self.keywords.contains(&word) {
+                    Some(Style::Keyword)
+                } else if self.has_capitalized_types && c.is_uppercase() {
+                    Some(Style::Type)
+                } else {
+                    None
+                };
+
+                if let Some(style) = style {
+                    spans.push(Span { range: i..i + len, style });
+                }
+
+                i += len;
+                continue;
+            }
+
+            i += c.len_utf8();
+        }
+
+        (spans, state)
+    }
+}
+
+/// Finds where a string closed by `delimiter` ends, skipping escaped characters.
+fn string_end(text: &str, delimiter: &str) -> (usize, bool) {
+    let mut chars = text.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if text[i..].starts_with(delimiter) {
+            return (i + delimiter.len(), true);
+        }
+    }
+
+    (text.len(), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn highlight(language: &Language, text: &str, state: State) -> (Vec<(String, Style)>, State) {
+        let (spans, state) = language.highlight(text, state);
+        let styles = spans.into_iter().map(|x| (text[x.range].to_string(), x.style)).collect();
+        (styles, state)
+    }
+
+    fn rust() -> &'static Language {
+        Language::for_path("src/main.rs").unwrap()
+    }
+
+    #[test]
+    fn for_path_uses_the_extension() {
+        assert!(Language::for_path("lib/Foo.JAVA").is_some());
+        assert!(Language::for_path("Makefile").is_none());
+        assert!(Language::for_path("notes.txt").is_none());
+    }
+
+    #[test]
+    fn highlight_colors_keywords_types_numbers_and_strings() {
+        let (styles, state) = highlight(rust(), "let x: Vec<u8> = f(\"a\\\"b\", 42);", State::Normal);
+
+        assert_eq!(styles, vec![
+            ("let".to_string(), Style::Keyword),
+            ("Vec".to_string(), Style::Type),
+            ("\"a\\\"b\"".to_string(), Style::String),
+            ("42".to_string(), Style::Number)
+        ]);
+        assert_eq!(state, State::Normal);
+    }
+
+    #[test]
+    fn highlight_stops_at_a_line_comment() {
+        let (styles, _) = highlight(rust(), "x // if \"y\"", State::Normal);
+        assert_eq!(styles, vec![("// if \"y\"".to_string(), Style::Comment)]);
+    }
+
+    #[test]
+    fn highlight_carries_block_comments_over() {
+        let (styles, state) = highlight(rust(), "a /* b", State::Normal);
+        assert_eq!(styles, vec![("/*".to_string(), Style::Comment), (" b".to_string(), Style::Comment)]);
+        assert_eq!(state, State::Comment);
+
+        let (styles, state) = highlight(rust(), "c */ fn", state);
+        assert_eq!(styles, vec![("c */".to_string(), Style::Comment), ("fn".to_string(), Style::Keyword)]);
+        assert_eq!(state, State::Normal);
+    }
+
+    #[test]
+    fn highlight_carries_only_multiline_strings_over() {
+        let python = Language::for_path("a.py").unwrap();
+        let (_, state) = highlight(python, "x = \"\"\"doc", State::Normal);
+        assert_eq!(state, State::String("\"\"\""));
+
+        let (styles, state) = highlight(python, "end\"\"\" + 1", state);
+        assert_eq!(styles, vec![("end\"\"\"".to_string(), Style::String), ("1".to_string(), Style::Number)]);
+        assert_eq!(state, State::Normal);
+
+        let (_, state) = highlight(python, "x = 'open", State::Normal);
+        assert_eq!(state, State::Normal);
+    }
+}
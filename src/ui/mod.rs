@@ -15,10 +15,6 @@
 
 #![macro_use]
 
-use std::rc::Rc;
-
-use gtk;
-
 macro_rules! view {
     ($view:expr) => {
         {
@@ -119,11 +115,5 @@ impl<T: fmt::Display> AsMessageDialog for T {
 
 pub trait Window {}
 
-pub trait Parent {
-    type View;
-
-    fn parent(&self) -> Option<Rc<Self::View>>;
-}
-
 pub mod init;
 pub mod main;